@@ -0,0 +1,144 @@
+use crate::repository::Repo;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+
+/// The state an attribute is given for a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeValue {
+    /// `attr`
+    Set,
+    /// `-attr`
+    Unset,
+    /// `attr=value`
+    Value(String),
+}
+
+/// One line of an attributes file: a pattern and what it does to each attribute it names.
+/// `None` is `!attr`, which returns the attribute to unspecified.
+struct AttributeRule {
+    pattern: Regex,
+    attributes: Vec<(String, Option<AttributeValue>)>,
+}
+
+/// Translates a glob into a regex, unanchored: `*` and `?` don't match `/`, while `**/` matches
+/// any number of leading directories and `**` anything at all.
+pub fn glob_regex(pattern: &str) -> String {
+    let mut re = String::new();
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        if let Some(r) = rest.strip_prefix("**/") {
+            re += "(?:.*/)?";
+            rest = r;
+            continue;
+        } else if let Some(r) = rest.strip_prefix("**") {
+            re += ".*";
+            rest = r;
+            continue;
+        }
+        match c {
+            '*' => re += "[^/]*",
+            '?' => re += "[^/]",
+            c => re += &regex::escape(&c.to_string()),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    re
+}
+
+/// Parses the attributes file of the directory `dir`, repo-relative and `/` separated (empty
+/// for the top level). Patterns without a `/` match a file name at any depth below `dir`;
+/// others match paths relative to it. Macro definitions are skipped.
+fn attributes_parse(data: &str, dir: &str) -> Vec<AttributeRule> {
+    let prefix = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", regex::escape(dir))
+    };
+    let mut rules = vec![];
+    for line in data.lines() {
+        let mut words = line.split_whitespace();
+        let pattern = match words.next() {
+            Some(p) if !p.starts_with('#') && !p.starts_with("[attr]") => p,
+            _ => continue,
+        };
+        let re = match pattern.trim_start_matches('/') {
+            p if pattern.contains('/') => format!("^{}{}$", prefix, glob_regex(p)),
+            p => format!("^{}(?:.*/)?{}$", prefix, glob_regex(p)),
+        };
+        let pattern = match Regex::new(&re) {
+            Ok(re) => re,
+            Err(_) => continue,
+        };
+
+        let mut attributes = vec![];
+        for word in words {
+            if word == "binary" {
+                // the one built in macro
+                for name in ["diff", "merge", "text"] {
+                    attributes.push((name.to_owned(), Some(AttributeValue::Unset)));
+                }
+            } else if let Some(name) = word.strip_prefix('-') {
+                attributes.push((name.to_owned(), Some(AttributeValue::Unset)));
+            } else if let Some(name) = word.strip_prefix('!') {
+                attributes.push((name.to_owned(), None));
+            } else if let Some((name, value)) = word.split_once('=') {
+                let value = AttributeValue::Value(value.to_owned());
+                attributes.push((name.to_owned(), Some(value)));
+            } else {
+                attributes.push((word.to_owned(), Some(AttributeValue::Set)));
+            }
+        }
+        rules.push(AttributeRule {
+            pattern,
+            attributes,
+        });
+    }
+    rules
+}
+
+/// The attributes that apply to the repo-relative `path`, from the `.gitattributes` files of
+/// the work tree directories containing it and from `.git/info/attributes`. As in git, deeper
+/// files override shallower ones, `info/attributes` overrides them all, and within a file
+/// later lines override earlier ones.
+pub fn attributes_for(repo: &Repo, path: &str) -> Result<HashMap<String, AttributeValue>, String> {
+    let mut files = vec![];
+    // a bare repository has no work tree to hold `.gitattributes` files
+    if !repo.is_bare() {
+        files.push((repo.worktree().join(".gitattributes"), String::new()));
+        let mut dir = String::new();
+        let components: Vec<&str> = path.split('/').collect();
+        for component in components[..components.len() - 1].iter() {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir += component;
+            files.push((
+                repo.worktree().join(&dir).join(".gitattributes"),
+                dir.clone(),
+            ));
+        }
+    }
+    files.push((repo.gitdir().join("info").join("attributes"), String::new()));
+
+    let mut out = HashMap::new();
+    for (file, dir) in files {
+        if !file.is_file() {
+            continue;
+        }
+        let data =
+            fs::read_to_string(&file).map_err(|e| format!("Could not read {:?}: {:?}", file, e))?;
+        for rule in attributes_parse(&data, &dir) {
+            if !rule.pattern.is_match(path) {
+                continue;
+            }
+            for (name, value) in rule.attributes {
+                match value {
+                    Some(value) => out.insert(name, value),
+                    None => out.remove(&name),
+                };
+            }
+        }
+    }
+    Ok(out)
+}
@@ -1,42 +1,131 @@
 use structopt::StructOpt;
 use wyag::commands::*;
 
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// Write all output straight to stdout instead of through a pager
+    #[structopt(long)]
+    no_pager: bool,
+    #[structopt(subcommand)]
+    app: App,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab")]
 enum App {
-    Add,
+    /// Add file contents to the index
+    Add(Add),
+    /// Show what commit last changed each line of a file
+    Blame(Blame),
+    /// Use binary search to find the commit that introduced a change
+    Bisect(Bisect),
+    /// List or create branches, or set the branch they track
+    Branch(Branch),
     /// Provide content of repository objects
     CatFile(CatFile),
+    /// Find commits not yet applied upstream
+    Cherry(Cherry),
     /// Checkout a commit inside a directory
     Checkout(Checkout),
-    Commit,
+    /// Clone a repository into a new directory
+    Clone(Clone),
+    /// Record the staged changes as a new commit
+    Commit(Commit),
+    /// Create a commit object from a tree
+    CommitTree(CommitTree),
+    /// Get and set repository or global options
+    Config(Config),
+    /// List the paths in conflict and the blob each side has for them
+    Conflicts(Conflicts),
+    /// Show changes between two commits or trees
+    Diff(Diff),
+    /// Compare two trees, printing each changed path in the raw diff format
+    DiffTree(DiffTree),
+    /// Print the parsed index in full, for debugging
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    DumpIndex(DumpIndex),
+    /// Verify the connectivity and validity of the objects in the repository
+    Fsck(Fsck),
     /// Compute object id and optionally create a blob from a file
     HashObject(HashObject),
     /// Initialize an empty repository
     Init(Init),
     /// Display history of a given commit
     Log(Log),
+    /// Show the paths in the index
+    LsFiles(LsFiles),
     /// Pretty print a tree object
     LsTree(LsTree),
-    Merge,
-    Rebase,
-    RevParse,
+    /// Abort a merge in progress
+    Merge(Merge),
+    /// Compute the patch id of a diff read from stdin, or of a commit
+    PatchId(PatchId),
+    /// Replay the current branch's commits on top of another commit
+    Rebase(Rebase),
+    /// Pack every object into a single new pack, optionally removing the old ones
+    Repack(Repack),
+    /// Move the current branch to another commit, keeping local changes
+    Reset(Reset),
+    /// Print the object ids revisions name
+    RevParse(RevParse),
     Rm,
+    /// Show an object: the contents of a blob, the entries of a tree, or a commit or tag
+    Show(Show),
+    /// Print the entries of a pack index, sorted by their offset in the pack
+    ShowIndex(ShowIndex),
     ShowRef,
+    /// Show the paths that differ between HEAD, the index and the work tree
+    Status(Status),
     Tag,
+    /// Create a tree object from the index
+    WriteTree(WriteTree),
 }
 
 fn main() -> Result<(), String> {
     env_logger::init();
-    let args = App::from_args();
+    let opt = Opt::from_args();
+    if opt.no_pager {
+        wyag::pager::pager_set_enabled(false);
+    }
 
-    match args {
+    match opt.app {
         App::Init(init) => cmd_init(&init),
+        App::Add(add) => cmd_add(&add),
+        App::Bisect(bisect) => cmd_bisect(&bisect),
+        App::Blame(blame) => cmd_blame(&blame),
+        App::Branch(branch) => cmd_branch(&branch),
         App::CatFile(cf) => cmd_cat_file(&cf),
+        App::Cherry(cherry) => cmd_cherry(&cherry),
+        App::Clone(clone) => cmd_clone(&clone),
+        App::Commit(commit) => cmd_commit(&commit),
+        App::CommitTree(ct) => cmd_commit_tree(&ct),
+        App::Config(cfg) => match cmd_config(&cfg) {
+            Err(e) if e.starts_with(CONFIG_NOTHING_SET) => {
+                eprintln!("error: {}", e);
+                std::process::exit(CONFIG_EXIT_NOTHING_SET);
+            }
+            result => result,
+        },
+        App::Conflicts(conflicts) => cmd_conflicts(&conflicts),
+        App::Diff(diff) => cmd_diff(&diff),
+        App::DiffTree(dt) => cmd_diff_tree(&dt),
+        App::DumpIndex(dump) => cmd_dump_index(&dump),
+        App::Fsck(fsck) => cmd_fsck(&fsck),
         App::HashObject(ho) => cmd_hash_object(&ho),
         App::Log(log) => cmd_log(&log),
+        App::LsFiles(ls_files) => cmd_ls_files(&ls_files),
         App::LsTree(ls_tree) => cmd_ls_tree(&ls_tree),
         App::Checkout(checkout) => cmd_checkout(&checkout),
+        App::Merge(merge) => cmd_merge(&merge),
+        App::PatchId(pi) => cmd_patch_id(&pi),
+        App::Rebase(rebase) => cmd_rebase(&rebase),
+        App::Repack(repack) => cmd_repack(&repack),
+        App::Reset(reset) => cmd_reset(&reset),
+        App::RevParse(rp) => cmd_rev_parse(&rp),
+        App::Show(show) => cmd_show(&show),
+        App::ShowIndex(si) => cmd_show_index(&si),
+        App::Status(status) => cmd_status(&status),
+        App::WriteTree(wt) => cmd_write_tree(&wt),
         _ => unimplemented!("This command has not been implemented yet!"),
     }
 }
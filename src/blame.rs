@@ -0,0 +1,114 @@
+use crate::diff::{self, Edit};
+use crate::object::{self, Oid};
+use crate::repository::{self, Repo};
+
+/// A line of the blamed file and the commit it was attributed to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameLine {
+    /// The commit that introduced the line
+    pub commit: Oid,
+    /// 1-based line number in the version of the file at `commit`
+    pub orig_line: usize,
+    /// 1-based line number in the blamed version of the file
+    pub final_line: usize,
+    pub content: Vec<u8>,
+    /// Whether `commit` is a root or shallow commit, whose lines may really be older
+    pub boundary: bool,
+    /// The parent `commit` was compared against, when it had the file too
+    pub previous: Option<Oid>,
+}
+
+/// Splits `data` into lines without their `\n`.
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines: Vec<&[u8]> = data.split(|&b| b == b'\n').collect();
+    if data.ends_with(b"\n") || data.is_empty() {
+        lines.pop();
+    }
+    lines
+}
+
+/// Attributes every line of `path` as of `commit` to the commit that introduced it.
+///
+/// Only first parents are followed, so lines brought in by a merge are blamed on the merge,
+/// and renames are not tracked: history stops at the commit that added `path`.
+pub fn blame(repo: &Repo, commit: &Oid, path: &str) -> Result<Vec<BlameLine>, String> {
+    let shallow = repository::shallow_read(repo)?;
+    let blob_at = |sha: &Oid| -> Result<Option<Oid>, String> {
+        let commit = object::commit_read(repo, sha)?;
+        let tree = commit
+            .tree()
+            .ok_or_else(|| format!("Commit {} does not have a tree", sha))?;
+        object::tree_lookup_path(repo, &tree, path)
+    };
+
+    let mut sha = *commit;
+    let mut blob =
+        blob_at(&sha)?.ok_or_else(|| format!("No such path \"{}\" in {}", path, commit))?;
+    let final_data = object::blob_read(repo, &blob)?;
+    let final_lines = split_lines(&final_data);
+    let mut data = final_data.clone();
+    // (line index in the version at `sha`, line index in the final version) of lines not
+    // yet attributed to a commit
+    let mut pending: Vec<(usize, usize)> = (0..final_lines.len()).map(|i| (i, i)).collect();
+    let mut out = vec![];
+
+    while !pending.is_empty() {
+        let current = object::commit_read(repo, &sha)?;
+        let parent = object::commit_parents(&current, &sha, &shallow)
+            .first()
+            .copied();
+        let parent_blob = match parent {
+            Some(parent) => blob_at(&parent)?,
+            None => None,
+        };
+        let blame_here = |cur: usize, fin: usize, previous: Option<Oid>| BlameLine {
+            commit: sha,
+            orig_line: cur + 1,
+            final_line: fin + 1,
+            content: final_lines[fin].to_vec(),
+            boundary: parent.is_none(),
+            previous,
+        };
+
+        let (parent, parent_blob) = match (parent, parent_blob) {
+            (Some(parent), Some(parent_blob)) => (parent, parent_blob),
+            // the file was added here, or history ends here
+            _ => {
+                out.extend(
+                    pending
+                        .drain(..)
+                        .map(|(cur, fin)| blame_here(cur, fin, None)),
+                );
+                break;
+            }
+        };
+        if parent_blob == blob {
+            sha = parent;
+            continue;
+        }
+
+        let parent_data = object::blob_read(repo, &parent_blob)?;
+        let (old, new) = (split_lines(&parent_data), split_lines(&data));
+        let mut to_parent = vec![None; new.len()];
+        for edit in diff::diff_lines(&old, &new) {
+            if let Edit::Equal(i, j) = edit {
+                to_parent[j] = Some(i);
+            }
+        }
+
+        let mut carried = vec![];
+        for (cur, fin) in pending.drain(..) {
+            match to_parent[cur] {
+                Some(i) => carried.push((i, fin)),
+                None => out.push(blame_here(cur, fin, Some(parent))),
+            }
+        }
+        pending = carried;
+        sha = parent;
+        blob = parent_blob;
+        data = parent_data;
+    }
+
+    out.sort_by_key(|line| line.final_line);
+    Ok(out)
+}
@@ -1,11 +1,150 @@
 use std::collections::*;
-use std::io::Write;
+use std::io::{BufRead, Read, Write};
 use std::path::PathBuf;
 use std::{fs, str};
 use structopt::StructOpt;
 
-use crate::object::{self, ObjectSelect, ObjectType, Tree};
+use crate::blame;
+use crate::config;
+use crate::diff;
+use crate::fsck;
+use crate::graph;
+use crate::hooks;
+use crate::index;
+use crate::lockfile::LockFile;
+use crate::merge::{self, TreeMerge};
+use crate::object::{
+    self, ObjectSelect, ObjectType, Oid, Tree, MODE_EXECUTABLE, MODE_GITLINK, MODE_SYMLINK,
+    MODE_TREE,
+};
+use crate::pack;
+use crate::pager::Pager;
+use crate::refs;
 use crate::repository::{self, Repo};
+use crate::rerere;
+use crate::status;
+use crate::transport;
+
+#[derive(Debug, StructOpt)]
+pub struct Add {
+    /// Files to stage; directories are staged recursively
+    #[structopt(parse(from_os_str), required = true)]
+    paths: Vec<PathBuf>,
+}
+
+/// Stages the current contents of each path. Staging a path in conflict marks it resolved, and
+/// staging a tracked path that has been deleted removes it from the index. With
+/// `rerere.enabled`, the contents a conflict was resolved with are recorded for reuse.
+pub fn cmd_add(add: &Add) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    let lock = index::index_lock(&repo)?;
+    let mut index = index::index_read(&repo)?.unwrap_or_default();
+    let unmerged: Vec<(String, [Option<Oid>; 3])> = index::index_unmerged(&index)
+        .into_iter()
+        .map(|(path, stages)| (path.to_owned(), stages.map(|e| e.map(|e| e.sha))))
+        .collect();
+    for path in add.paths.iter() {
+        let name = worktree_relative(&repo, path)?;
+        let prefix = format!("{}/", name);
+        let tracked: Vec<String> = index
+            .entries
+            .iter()
+            .filter(|e| e.path == name || name.is_empty() || e.path.starts_with(&prefix))
+            .map(|e| e.path.clone())
+            .collect();
+        let exists =
+            fs::symlink_metadata(repo.worktree().join(object::from_git_path(&name))).is_ok();
+        if !exists && tracked.is_empty() {
+            return Err(format!(
+                "pathspec '{}' did not match any files",
+                path.display()
+            ));
+        }
+        // tracked files that are gone, or have become directories, are unstaged
+        for tracked in tracked {
+            match fs::symlink_metadata(repo.worktree().join(object::from_git_path(&tracked))) {
+                Ok(meta) if !meta.is_dir() => (),
+                _ => {
+                    index.remove(&tracked);
+                }
+            }
+        }
+        if exists {
+            add_path(&repo, &mut index, &name)?;
+        }
+    }
+    if rerere::rerere_enabled(&repo) {
+        for (path, stages) in unmerged.iter() {
+            let entry = index
+                .entries
+                .iter()
+                .find(|e| e.path == *path && e.stage() == 0);
+            if let Some(entry) = entry {
+                let sides = [stages[0].as_ref(), stages[1].as_ref(), stages[2].as_ref()];
+                rerere::rerere_record(&repo, sides, &object::blob_read(&repo, &entry.sha)?)?;
+                println!("Recorded resolution for '{}'.", path);
+            }
+        }
+    }
+    index::index_write(lock, &index)
+}
+
+/// Stages the file or directory at the repo-relative path `name`.
+fn add_path(repo: &Repo, index: &mut index::Index, name: &str) -> Result<(), String> {
+    let full = repo.worktree().join(object::from_git_path(name));
+    let meta =
+        fs::symlink_metadata(&full).map_err(|e| format!("Could not stat {:?}: {:?}", full, e))?;
+    if meta.is_dir() {
+        let mut children = vec![];
+        for entry in
+            fs::read_dir(&full).map_err(|e| format!("Could not read dir {:?}: {:?}", full, e))?
+        {
+            let entry = entry.map_err(|e| format!("Could not read dir {:?}: {:?}", full, e))?;
+            let child = entry.file_name().to_string_lossy().into_owned();
+            if child != ".git" {
+                children.push(child);
+            }
+        }
+        children.sort();
+        for child in children {
+            let child = if name.is_empty() {
+                child
+            } else {
+                format!("{}/{}", name, child)
+            };
+            add_path(repo, index, &child)?;
+        }
+        return Ok(());
+    }
+
+    let sha = status::worktree_hash(repo, name, &meta, true)?;
+    index.add(index::IndexEntry::from_metadata(name, &sha, &meta));
+    Ok(())
+}
+
+/// Converts `path`, relative to the current directory, to a `/` separated path relative to the
+/// work tree, which is empty for the work tree itself. The path needn't exist.
+fn worktree_relative(repo: &Repo, path: &std::path::Path) -> Result<String, String> {
+    use std::path::Component;
+
+    let cwd = std::env::current_dir().map_err(|e| format!("Could not get current dir: {:?}", e))?;
+    let cwd = cwd.canonicalize().unwrap_or(cwd);
+    let mut full = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                full.pop();
+            }
+            Component::CurDir => (),
+            c => full.push(c),
+        }
+    }
+    let relative = full
+        .strip_prefix(repo.worktree())
+        .map_err(|_| format!("{:?} is outside repository", path))?;
+    object::to_git_path(relative)
+}
 
 #[derive(Debug, StructOpt)]
 pub struct Init {
@@ -21,210 +160,3457 @@ pub fn cmd_init(init: &Init) -> Result<(), String> {
 }
 
 #[derive(Debug, StructOpt)]
-pub struct CatFile {
-    /// The type of the object
-    #[structopt(possible_values = ObjectType::variants(), name = "type")]
-    _type: ObjectType,
-    /// the hash string of the object to display
-    object: String,
+#[structopt(rename_all = "kebab")]
+pub enum Bisect {
+    /// Start bisecting, optionally marking the bad commit and some good commits
+    Start {
+        bad: Option<String>,
+        good: Vec<String>,
+    },
+    /// Mark a commit, `HEAD` by default, as having the change being looked for
+    Bad { rev: Option<String> },
+    /// Mark commits, `HEAD` by default, as predating the change being looked for
+    Good { revs: Vec<String> },
+    /// Stop bisecting and check out the commit bisecting started from
+    Reset,
 }
 
-pub fn cmd_cat_file(cf: &CatFile) -> Result<(), String> {
+const BISECT_START: &str = "BISECT_START";
+const BISECT_BAD: &str = "BISECT_BAD";
+const BISECT_GOOD: &str = "BISECT_GOOD";
+
+/// Finds the commit that introduced a change by binary search over history. The bisection
+/// state lives in `.git/BISECT_START` (where `HEAD` pointed before), `.git/BISECT_BAD` and
+/// `.git/BISECT_GOOD` (one sha per line), and each step checks out the next commit to test.
+pub fn cmd_bisect(bisect: &Bisect) -> Result<(), String> {
     let repo = repository::repo_find::<&str>(None, false)?;
-    cat_file(&repo, &cf.object, cf._type)
+    let resolve = |rev: &Option<String>| {
+        object::object_find(
+            &repo,
+            rev.as_deref().unwrap_or("HEAD"),
+            Some(ObjectType::Commit),
+            true,
+        )
+    };
+
+    match bisect {
+        Bisect::Start { bad, good } => {
+            if state_read(&repo, BISECT_START)?.is_none() {
+                let start = match refs::ref_symbolic_target(&repo, "HEAD")? {
+                    Some(branch) => branch,
+                    None => refs::ref_resolve(&repo, "HEAD")?
+                        .ok_or("Cannot bisect without any commits")?
+                        .to_hex(),
+                };
+                state_write(&repo, BISECT_START, &start)?;
+            }
+            state_remove(&repo, &[BISECT_BAD, BISECT_GOOD])?;
+            if let Some(bad) = bad {
+                state_write_shas(&repo, BISECT_BAD, &[resolve(&Some(bad.clone()))?])?;
+            }
+            let mut shas = vec![];
+            for rev in good {
+                shas.push(resolve(&Some(rev.clone()))?);
+            }
+            state_write_shas(&repo, BISECT_GOOD, &shas)?;
+        }
+        Bisect::Bad { rev } => {
+            bisect_started(&repo)?;
+            state_write_shas(&repo, BISECT_BAD, &[resolve(rev)?])?;
+        }
+        Bisect::Good { revs } => {
+            bisect_started(&repo)?;
+            let mut shas = state_read_shas(&repo, BISECT_GOOD)?;
+            if revs.is_empty() {
+                shas.push(resolve(&None)?);
+            }
+            for rev in revs {
+                shas.push(resolve(&Some(rev.clone()))?);
+            }
+            state_write_shas(&repo, BISECT_GOOD, &shas)?;
+        }
+        Bisect::Reset => {
+            let start = match state_read(&repo, BISECT_START)? {
+                Some(start) => start,
+                None => {
+                    println!("We are not bisecting.");
+                    return Ok(());
+                }
+            };
+            if start.starts_with("refs/") {
+                let sha = refs::ref_resolve(&repo, &start)?
+                    .ok_or_else(|| format!("Branch \"{}\" no longer exists", start))?;
+                bisect_checkout(&repo, &sha)?;
+                refs::ref_set_symbolic(&repo, "HEAD", &start)?;
+            } else {
+                let sha = Oid::from_hex(&start)?;
+                bisect_checkout(&repo, &sha)?;
+                refs::ref_update(&repo, "HEAD", &sha)?;
+            }
+            return state_remove(&repo, &[BISECT_START, BISECT_BAD, BISECT_GOOD]);
+        }
+    }
+
+    let bad = state_read_shas(&repo, BISECT_BAD)?.first().copied();
+    let good = state_read_shas(&repo, BISECT_GOOD)?;
+    let bad = match (bad, good.is_empty()) {
+        (Some(bad), false) => bad,
+        (Some(_), true) => {
+            println!("status: waiting for good commit(s), bad commit known");
+            return Ok(());
+        }
+        (None, _) => {
+            println!(
+                "status: waiting for a bad commit, {} good commit(s) known",
+                good.len()
+            );
+            return Ok(());
+        }
+    };
+
+    match graph::bisect_next(&repo, &bad, &good)? {
+        graph::BisectStep::Found(sha) => {
+            println!("{} is the first bad commit", sha);
+            for line in object::commit_read(&repo, &sha)?.message().lines() {
+                println!("    {}", line);
+            }
+        }
+        graph::BisectStep::Test { sha, left } => {
+            bisect_checkout(&repo, &sha)?;
+            refs::ref_update(&repo, "HEAD", &sha)?;
+            let steps = (usize::BITS - left.leading_zeros()) as usize;
+            println!(
+                "Bisecting: {} revisions left to test after this (roughly {} steps)",
+                left, steps
+            );
+            let commit = object::commit_read(&repo, &sha)?;
+            println!(
+                "[{}] {}",
+                sha,
+                commit.message().lines().next().unwrap_or("")
+            );
+        }
+    }
+    Ok(())
 }
 
-fn cat_file(repo: &Repo, object: &str, _type: ObjectType) -> Result<(), String> {
-    let obj_inner = object::object_find(repo, object, Some(_type), true);
-    let obj = object::object_read(repo, obj_inner)?;
+/// Checks out the commit `sha` for the next bisection step, refusing if that could lose local
+/// changes. `HEAD` is left for the caller to move.
+fn bisect_checkout(repo: &Repo, sha: &Oid) -> Result<(), String> {
+    repo.require_worktree()?;
+    if refs::ref_resolve(repo, "HEAD")? == Some(*sha) {
+        return Ok(());
+    }
+    let lock = index::index_lock(repo)?;
+    require_clean(repo, "bisect")?;
+    worktree_checkout(repo, lock, sha)
+}
 
-    let obj_bytes = obj.serialize();
-    if let Ok(as_str) = str::from_utf8(&obj_bytes) {
-        print!("{}", as_str);
-    } else {
-        println!("{:X?}", &obj_bytes);
+fn bisect_started(repo: &Repo) -> Result<(), String> {
+    match state_read(repo, BISECT_START)? {
+        Some(_) => Ok(()),
+        None => Err("You need to start by \"wyag bisect start\"".to_owned()),
+    }
+}
+
+/// Reads a state file such as `.git/BISECT_START` or `.git/MERGE_HEAD`, if it exists.
+fn state_read(repo: &Repo, name: &str) -> Result<Option<String>, String> {
+    let path = repo.repo_file(name, false)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data =
+        fs::read_to_string(&path).map_err(|e| format!("Could not read {:?}: {:?}", path, e))?;
+    Ok(Some(data.trim_end().to_owned()))
+}
+
+fn state_write(repo: &Repo, name: &str, contents: &str) -> Result<(), String> {
+    let path = repo.repo_file(name, false)?;
+    fs::write(&path, format!("{}\n", contents))
+        .map_err(|e| format!("Could not write {:?}: {:?}", path, e))
+}
+
+/// Reads a state file holding one sha per line, such as `.git/BISECT_GOOD`. A missing file
+/// holds none.
+fn state_read_shas(repo: &Repo, name: &str) -> Result<Vec<Oid>, String> {
+    match state_read(repo, name)? {
+        Some(data) => data
+            .lines()
+            .map(|l| Oid::from_hex(l).map_err(|e| format!("Bad {} file: {}", name, e)))
+            .collect(),
+        None => Ok(vec![]),
     }
+}
+
+fn state_write_shas(repo: &Repo, name: &str, shas: &[Oid]) -> Result<(), String> {
+    let lines: Vec<String> = shas.iter().map(Oid::to_hex).collect();
+    state_write(repo, name, &lines.join("\n"))
+}
 
+fn state_remove(repo: &Repo, names: &[&str]) -> Result<(), String> {
+    for name in names {
+        let path = repo.repo_file(name, false)?;
+        if path.exists() {
+            fs::remove_file(&path).map_err(|e| format!("Could not remove {:?}: {:?}", path, e))?;
+        }
+    }
     Ok(())
 }
 
 #[derive(Debug, StructOpt)]
-pub struct HashObject {
-    /// The type of the object
-    #[structopt(possible_values = ObjectType::variants(), short = "t", long = "type", default_value = "blob")]
-    _type: ObjectType,
-    /// whether to write it or not
-    #[structopt(short = "w")]
-    write: bool,
-    #[structopt(parse(from_os_str))]
-    file: PathBuf,
+pub struct Blame {
+    /// Output machine readable attribution in git's porcelain format
+    #[structopt(long)]
+    porcelain: bool,
+    /// Output machine readable attribution as a JSON array with one object per line
+    #[structopt(long, conflicts_with = "porcelain")]
+    json: bool,
+    /// `[<rev>] <file>`: the file to blame, as of `HEAD` unless a revision is given first
+    #[structopt(min_values = 1, max_values = 2, required = true)]
+    args: Vec<String>,
 }
 
-pub fn cmd_hash_object(ho: &HashObject) -> Result<(), String> {
-    let mut reader = fs::OpenOptions::new()
-        .read(true)
-        .open(&ho.file)
-        .map_err(|e| format!("Could not open file in cmd_hash_object: {:?}", e))?;
-    let hash = if ho.write {
-        let repo = repository::repo_find::<&str>(Some("."), false)?;
-        object::object_hash(&mut reader, ho._type, Some(&repo))?
-    } else {
-        object::object_hash(&mut reader, ho._type, None)?
+pub fn cmd_blame(blame: &Blame) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let (rev, path) = match &blame.args[..] {
+        [path] => ("HEAD", path),
+        [rev, path] => (rev.as_str(), path),
+        _ => return Err("Expected [<rev>] <file>".to_owned()),
     };
-    println!("{}", hash);
+    let sha = object::object_find(&repo, rev, Some(ObjectType::Commit), true)?;
+    let lines = blame::blame(&repo, &sha, path)?;
+
+    let mut commits = HashMap::new();
+    for line in lines.iter() {
+        if let hash_map::Entry::Vacant(entry) = commits.entry(line.commit) {
+            entry.insert(object::commit_read(&repo, &line.commit)?);
+        }
+    }
+
+    if blame.porcelain {
+        blame_porcelain(&lines, &commits, path, &mut std::io::stdout().lock())?;
+    } else if blame.json {
+        blame_json(&lines, &commits, &mut std::io::stdout().lock())?;
+    } else {
+        let author = |line: &blame::BlameLine| commits[&line.commit].author();
+        let name_width = lines
+            .iter()
+            .map(|l| author(l).map_or(0, |a| a.name.chars().count()))
+            .max()
+            .unwrap_or(0);
+        let line_width = lines.len().to_string().len();
+        for line in lines.iter() {
+            let sha = if line.boundary {
+                format!("^{}", line.commit.short())
+            } else {
+                line.commit.to_hex()[..8].to_owned()
+            };
+            let (name, date) =
+                author(line).map_or_else(Default::default, |a| (a.name.clone(), a.date()));
+            println!(
+                "{} ({:<nw$} {} {:>lw$}) {}",
+                sha,
+                name,
+                date,
+                line.final_line,
+                String::from_utf8_lossy(&line.content),
+                nw = name_width,
+                lw = line_width
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Writes blame output in git's porcelain format: a header for each run of lines from the
+/// same commit, the commit's details the first time it appears, then each line after a tab.
+fn blame_porcelain(
+    lines: &[blame::BlameLine],
+    commits: &HashMap<Oid, object::Commit>,
+    path: &str,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    let mut shown = HashSet::new();
+    for (idx, line) in lines.iter().enumerate() {
+        let continues = idx > 0 && {
+            let prev = &lines[idx - 1];
+            prev.commit == line.commit && prev.orig_line + 1 == line.orig_line
+        };
+        if continues {
+            writeln!(
+                out,
+                "{} {} {}",
+                line.commit, line.orig_line, line.final_line
+            )
+            .map_err(output_err)?;
+        } else {
+            let group = lines[idx..]
+                .iter()
+                .zip(0..)
+                .take_while(|(l, i)| l.commit == line.commit && l.orig_line == line.orig_line + i)
+                .count();
+            writeln!(
+                out,
+                "{} {} {} {}",
+                line.commit, line.orig_line, line.final_line, group
+            )
+            .map_err(output_err)?;
+        }
+
+        if shown.insert(&line.commit) {
+            let commit = &commits[&line.commit];
+            for (role, sig) in [
+                ("author", commit.author()),
+                ("committer", commit.committer()),
+            ] {
+                if let Some(sig) = sig {
+                    writeln!(out, "{} {}", role, sig.name).map_err(output_err)?;
+                    writeln!(out, "{}-mail <{}>", role, sig.email).map_err(output_err)?;
+                    writeln!(out, "{}-time {}", role, sig.time).map_err(output_err)?;
+                    writeln!(out, "{}-tz {}", role, sig.tz).map_err(output_err)?;
+                }
+            }
+            writeln!(
+                out,
+                "summary {}",
+                commit.message().lines().next().unwrap_or("")
+            )
+            .map_err(output_err)?;
+            if line.boundary {
+                writeln!(out, "boundary").map_err(output_err)?;
+            }
+            if let Some(previous) = &line.previous {
+                writeln!(out, "previous {} {}", previous, path).map_err(output_err)?;
+            }
+            writeln!(out, "filename {}", path).map_err(output_err)?;
+        }
+        writeln!(out, "\t{}", String::from_utf8_lossy(&line.content)).map_err(output_err)?;
+    }
     Ok(())
 }
 
+/// Prints blame output as a JSON array with an object per line, for editor integrations.
+fn blame_json(
+    lines: &[blame::BlameLine],
+    commits: &HashMap<Oid, object::Commit>,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    writeln!(out, "[").map_err(output_err)?;
+    for (idx, line) in lines.iter().enumerate() {
+        let author = commits[&line.commit].author();
+        let (name, email, time, tz) = match &author {
+            Some(a) => (a.name.as_str(), a.email.as_str(), a.time, a.tz.as_str()),
+            None => ("", "", 0, ""),
+        };
+        let sep = if idx + 1 < lines.len() { "," } else { "" };
+        writeln!(
+            out,
+            "  {{\"line\": {}, \"commit\": \"{}\", \"orig_line\": {}, \"author\": {}, \"author_mail\": {}, \"author_time\": {}, \"author_tz\": {}, \"boundary\": {}, \"content\": {}}}{}",
+            line.final_line,
+            line.commit,
+            line.orig_line,
+            json_string(name),
+            json_string(email),
+            time,
+            json_string(tz),
+            line.boundary,
+            json_string(&String::from_utf8_lossy(&line.content)),
+            sep
+        )
+        .map_err(output_err)?;
+    }
+    writeln!(out, "]").map_err(output_err)
+}
+
+/// Quotes and escapes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
 #[derive(Debug, StructOpt)]
-pub struct Log {
-    /// The commit to inspect
-    #[structopt(default_value = "HEAD")]
-    commit: String,
+pub struct Branch {
+    /// Make the branch track this upstream, e.g. `origin/main`, instead of creating it
+    #[structopt(short = "u", long = "set-upstream-to", name = "upstream")]
+    set_upstream_to: Option<String>,
+    /// The branch to create, or with --set-upstream-to the one to change (by default the
+    /// current branch); without either the branches are listed
+    name: Option<String>,
+    /// The commit the new branch starts at, `HEAD` by default
+    start: Option<String>,
 }
 
-pub fn cmd_log(log: &Log) -> Result<(), String> {
+/// Lists the branches, creates one, or sets the upstream of one with `--set-upstream-to`.
+pub fn cmd_branch(branch: &Branch) -> Result<(), String> {
     let repo = repository::repo_find::<&str>(None, false)?;
+    if let Some(upstream) = &branch.set_upstream_to {
+        let name = match &branch.name {
+            Some(name) => name.clone(),
+            None => refs::ref_symbolic_target(&repo, "HEAD")?
+                .and_then(|b| b.strip_prefix("refs/heads/").map(str::to_owned))
+                .ok_or("HEAD is detached, name the branch to set the upstream of")?,
+        };
+        if refs::ref_resolve(&repo, &format!("refs/heads/{}", name))?.is_none() {
+            return Err(format!("Branch '{}' does not exist", name));
+        }
+        let upstream_ref = refs::ref_dwim(&repo, upstream)?.ok_or_else(|| {
+            format!(
+                "The requested upstream branch '{}' does not exist",
+                upstream
+            )
+        })?;
+        transport::branch_set_upstream(&repo, &name, &upstream_ref)?;
+        println!("branch '{}' set up to track '{}'.", name, upstream);
+        return Ok(());
+    }
 
-    println!("digraph wyaglog{{");
-    let mut set = HashSet::new();
-    log_graphviz(
-        &repo,
-        object::object_find(&repo, &log.commit, None, false),
-        &mut set,
-    );
-    println!("}}");
-
-    Ok(())
+    match &branch.name {
+        Some(name) => branch_create(&repo, name, branch.start.as_deref()).map(|_| ()),
+        None => {
+            let current = refs::ref_symbolic_target(&repo, "HEAD")?;
+            for (name, _) in refs::ref_list(&repo)? {
+                if let Some(short) = name.strip_prefix("refs/heads/") {
+                    let mark = if current.as_deref() == Some(&name) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    println!("{} {}", mark, short);
+                }
+            }
+            Ok(())
+        }
+    }
 }
 
-fn log_graphviz(repo: &Repo, sha: &str, seen: &mut HashSet<String>) {
-    if seen.contains(sha) {
-        return;
+/// Creates the branch `name` at `start`, or at `HEAD` without one, and returns the commit
+/// it points at. As `branch.autoSetupMerge` (`true` by default) asks, a branch started from
+/// a remote-tracking ref, or with `always` from a local branch too, is set up to track it.
+fn branch_create(repo: &Repo, name: &str, start: Option<&str>) -> Result<Oid, String> {
+    let full = format!("refs/heads/{}", name);
+    if refs::ref_resolve(repo, &full)?.is_some() {
+        return Err(format!("A branch named '{}' already exists", name));
     }
-    seen.insert(sha.to_owned());
+    let start = start.unwrap_or("HEAD");
+    let sha = object::object_find(repo, start, Some(ObjectType::Commit), true)?;
+    refs::ref_update(repo, &full, &sha)?;
 
-    let obj = object::object_read(repo, sha).unwrap();
-    let commit = if let ObjectSelect::Commit(commit) = obj.get_specific() {
-        commit
-    } else {
-        error!("Found non-commit at {}", sha);
-        return;
+    let auto_setup = repo
+        .config_value("branch.autosetupmerge")
+        .unwrap_or_else(|| "true".to_owned());
+    let start_ref = refs::ref_dwim(repo, start)?.filter(|r| r.starts_with("refs/"));
+    let track = match (auto_setup.as_str(), start_ref.as_deref()) {
+        ("false", _) => false,
+        ("always", Some(r)) => r.starts_with("refs/remotes/") || r.starts_with("refs/heads/"),
+        (_, Some(r)) => r.starts_with("refs/remotes/"),
+        (_, None) => false,
     };
+    if let (true, Some(start_ref)) = (track, start_ref) {
+        transport::branch_set_upstream(repo, name, &start_ref)?;
+        println!("branch '{}' set up to track '{}'.", name, start);
+    }
+    Ok(sha)
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CatFile {
+    /// The type of the object
+    #[structopt(possible_values = ObjectType::variants(), name = "type", required_unless_one = &["batch", "size", "pretty"])]
+    _type: Option<ObjectType>,
+    /// the hash string of the object to display
+    #[structopt(required_unless_one = &["batch", "size", "pretty"])]
+    object: Option<String>,
+    /// Read object names from stdin, printing each as `<sha> <type> <size>`, a newline, its
+    /// contents and another newline
+    #[structopt(long, conflicts_with_all = &["type", "object", "size", "pretty"])]
+    batch: bool,
+    /// Print the size of an object's contents instead of the contents
+    #[structopt(short = "s", name = "size", value_name = "object", conflicts_with_all = &["type", "object", "pretty"])]
+    size_of: Option<String>,
+    /// Pretty print an object whatever its type: a tree as its entries, and anything else,
+    /// including an annotated tag, as it is stored
+    #[structopt(short = "p", name = "pretty", value_name = "object", conflicts_with_all = &["type", "object"])]
+    pretty: Option<String>,
+}
+
+pub fn cmd_cat_file(cf: &CatFile) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    if cf.batch {
+        let stdout = std::io::stdout();
+        return cat_file_batch(&repo, &mut std::io::stdin().lock(), &mut stdout.lock());
+    }
+    if let Some(object) = &cf.size_of {
+        let sha = object::object_find(&repo, object, None, false)?;
+        println!("{}", object::object_size(&repo, &sha)?);
+        return Ok(());
+    }
+    if let Some(object) = &cf.pretty {
+        let stdout = std::io::stdout();
+        return cat_file_pretty(&repo, object, &mut stdout.lock());
+    }
+    match (cf._type, &cf.object) {
+        (Some(_type), Some(object)) => cat_file(&repo, object, _type),
+        _ => Err("Both a type and an object are required".to_owned()),
+    }
+}
+
+fn output_err(e: std::io::Error) -> String {
+    format!("Could not write output: {:?}", e)
+}
 
-    if let Some(parents) = commit.get("parent") {
-        for p in parents {
-            println!("C_{} -> C_{};", sha, p);
-            log_graphviz(repo, p, seen);
+/// Answers `cat-file --batch`: each object's contents are copied to stdout as they are read,
+/// so large blobs are never held in memory whole.
+fn cat_file_batch(repo: &Repo, input: &mut dyn BufRead, out: &mut dyn Write) -> Result<(), String> {
+    for line in input.lines() {
+        let name = line.map_err(|e| format!("Could not read object name: {:?}", e))?;
+        let found = object::object_find(repo, &name, None, false)
+            .and_then(|sha| Ok((object::object_read_stream(repo, &sha)?, sha)));
+        let ((kind, size, mut reader), sha) = match found {
+            Ok(found) => found,
+            Err(_) => {
+                writeln!(out, "{} missing", name).map_err(output_err)?;
+                continue;
+            }
+        };
+        writeln!(out, "{} {} {}", sha, kind, size).map_err(output_err)?;
+        let written = std::io::copy(&mut reader, out)
+            .map_err(|e| format!("Could not copy object {}: {:?}", sha, e))?;
+        if written != size as u64 {
+            return Err(format!("Malformed object {}: bad length", sha));
         }
+        writeln!(out).map_err(output_err)?;
+        out.flush().map_err(output_err)?;
+    }
+    Ok(())
+}
+
+fn cat_file(repo: &Repo, object: &str, _type: ObjectType) -> Result<(), String> {
+    let obj_inner = object::object_find(repo, object, Some(_type), true)?;
+    let obj = object::object_read(repo, &obj_inner)?;
+
+    let obj_bytes = obj.serialize();
+    if let Ok(as_str) = str::from_utf8(&obj_bytes) {
+        print!("{}", as_str);
     } else {
-        return;
+        println!("{:X?}", &obj_bytes);
+    }
+
+    Ok(())
+}
+
+/// Answers `cat-file -p`. The object isn't followed, so a tag prints its own headers and
+/// message rather than the object it points to.
+fn cat_file_pretty(repo: &Repo, object: &str, out: &mut dyn Write) -> Result<(), String> {
+    let sha = object::object_find(repo, object, None, false)?;
+    let obj = object::object_read(repo, &sha)?;
+    match obj.get_specific() {
+        ObjectSelect::Tree(tree) => {
+            for leaf in tree.iterate_leaves() {
+                writeln!(
+                    out,
+                    "{:06} {} {}\t{}",
+                    leaf.mode,
+                    leaf.object_type(),
+                    leaf.sha,
+                    leaf.name()
+                )
+                .map_err(output_err)?;
+            }
+            Ok(())
+        }
+        _ => out.write_all(&obj.serialize()).map_err(output_err),
     }
 }
 
 #[derive(Debug, StructOpt)]
-pub struct LsTree {
-    /// The tree to show
-    object: String,
+pub struct Cherry {
+    /// The branch the commits may have been applied to
+    upstream: String,
+    /// The branch whose commits to look for
+    #[structopt(default_value = "HEAD")]
+    head: String,
 }
 
-pub fn cmd_ls_tree(tree: &LsTree) -> Result<(), String> {
+/// Lists the commits in `upstream..head`, oldest first, marking with `-` those whose change
+/// already exists in upstream (by patch id) and with `+` those that don't. A commit with no
+/// change to compare, such as an empty one, is always `+`.
+pub fn cmd_cherry(cherry: &Cherry) -> Result<(), String> {
     let repo = repository::repo_find::<&str>(None, false)?;
-    let obj_inner = object::object_find(&repo, &tree.object, Some(ObjectType::Tree), true);
-    let obj = object::object_read(&repo, obj_inner)?;
-    if let ObjectSelect::Tree(t) = obj.get_specific() {
-        for leaf in t.iterate_leaves() {
-            println!(
-                "{:6} {:?} {} {}",
-                leaf.mode,
-                object::object_read(&repo, &leaf.sha),
-                leaf.sha,
-                leaf.path.to_string_lossy()
-            );
-        }
-    } else {
-        return Err(format!("Object \"{}\" is not a tree", tree.object));
+    let upstream = object::object_find(&repo, &cherry.upstream, Some(ObjectType::Commit), true)?;
+    let head = object::object_find(&repo, &cherry.head, Some(ObjectType::Commit), true)?;
+    for (applied, sha) in cherry_marks(&repo, &upstream, &head)? {
+        let mark = if applied { '-' } else { '+' };
+        println!("{} {}", mark, sha);
     }
     Ok(())
 }
 
+/// The commits in `upstream..head`, oldest first, each with whether it is already upstream.
+fn cherry_marks(repo: &Repo, upstream: &Oid, head: &Oid) -> Result<Vec<(bool, Oid)>, String> {
+    let shallow = repository::shallow_read(repo)?;
+    // merges are skipped, as they have no single patch; an empty commit has no patch id
+    let patch_ids = |include: &Oid, exclude: &Oid| -> Result<Vec<(Oid, Option<String>)>, String> {
+        let mut out = vec![];
+        for sha in graph::rev_list(repo, &[*include], &[*exclude])? {
+            if object::commit_read(repo, &sha)?.parents().len() > 1 {
+                continue;
+            }
+            let id = diff::patch_id(&diff::commit_patch(repo, &sha, &shallow)?);
+            out.push((sha, id));
+        }
+        Ok(out)
+    };
+    let upstream_ids: HashSet<String> = patch_ids(upstream, head)?
+        .into_iter()
+        .filter_map(|(_, id)| id)
+        .collect();
+
+    Ok(patch_ids(head, upstream)?
+        .into_iter()
+        .rev()
+        .map(|(sha, id)| (id.is_some_and(|id| upstream_ids.contains(&id)), sha))
+        .collect())
+}
+
 #[derive(Debug, StructOpt)]
-pub struct Checkout {
-    /// The commit or tree to checkout
-    commit: String,
-    /// The path at which to checkout
+pub struct Clone {
+    /// Only fetch this many commits of history from each ref
+    #[structopt(long)]
+    depth: Option<usize>,
+    /// Make a bare repository with a copy of every ref under the same name, including
+    /// branches, tags and the source's own remote branches
+    #[structopt(long)]
+    mirror: bool,
+    /// The repository to clone, a `file://` url or path
+    repository: String,
+    /// Where to clone to, defaults to the name of the repository
     #[structopt(parse(from_os_str))]
-    path: PathBuf,
+    directory: Option<PathBuf>,
 }
 
-pub fn cmd_checkout(checkout: &Checkout) -> Result<(), String> {
-    let repo = repository::repo_find::<&str>(None, false)?;
-    let obj_inner = object::object_find(&repo, &checkout.commit, None, true);
-    let obj = object::object_read(&repo, obj_inner)?;
-    let tree = match obj.get_specific() {
-        ObjectSelect::Tree(tree) => tree,
-        ObjectSelect::Commit(commit) => {
-            let t_obj = &commit
-                .get("tree")
-                .ok_or_else(|| format!("Commit \"{}\" does not have a tree!", checkout.commit))?[0];
-            let t_dyn = object::object_read(&repo, &t_obj)?;
-            if let ObjectSelect::Tree(tree) = t_dyn.get_specific() {
-                tree
-            } else {
-                return Err(format!(
-                    "Commit \"{}\"'s `tree` field points to object \"{}\" which is not a tree!",
-                    checkout.commit, t_obj
-                ));
+pub fn cmd_clone(clone: &Clone) -> Result<(), String> {
+    let directory = match &clone.directory {
+        Some(d) => d.clone(),
+        None => {
+            let url = clone.repository.trim_end_matches('/');
+            let name = url.rsplit('/').next().unwrap_or(url);
+            match name.strip_suffix(".git") {
+                // a bare repository is named like one, `<name>.git`
+                Some(_) if clone.mirror => PathBuf::from(name),
+                Some(stem) => PathBuf::from(stem),
+                None if clone.mirror => PathBuf::from(format!("{}.git", name)),
+                None => PathBuf::from(name),
             }
         }
-        _ => {
-            return Err(format!(
-                "Object \"{}\" is not a commit or tree",
-                checkout.commit
-            ))
+    };
+    let (repo, head) =
+        transport::clone_local(&clone.repository, &directory, clone.depth, clone.mirror)?;
+
+    if let Some(head) = head.filter(|_| !repo.is_bare()) {
+        let commit = object::commit_read(&repo, &head)?;
+        let tree_sha = commit
+            .tree()
+            .ok_or_else(|| format!("Commit \"{}\" does not have a tree!", head))?;
+        let tree = object::tree_read(&repo, &tree_sha)?;
+        let plan = checkout_plan(&repo, &tree, repo.worktree().to_owned())?;
+        checkout_apply(&repo, &plan)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Commit {
+    /// A paragraph of the commit message; may be given more than once
+    #[structopt(short = "m", long = "message", number_of_values = 1)]
+    message: Vec<String>,
+    /// Skip the pre-commit and commit-msg hooks
+    #[structopt(short = "n", long)]
+    no_verify: bool,
+}
+
+/// Set while a merge is in progress: the commits being merged into `HEAD`, one per line
+const MERGE_HEAD: &str = "MERGE_HEAD";
+/// The message prepared for an in-progress merge's commit
+const MERGE_MSG: &str = "MERGE_MSG";
+/// Options of an in-progress merge, such as `no-ff`
+const MERGE_MODE: &str = "MERGE_MODE";
+/// The tree an in-progress merge produced automatically, conflict markers and all
+const AUTO_MERGE: &str = "AUTO_MERGE";
+/// The message of the commit being made, for the commit-msg hook to check or edit
+const COMMIT_EDITMSG: &str = "COMMIT_EDITMSG";
+
+/// Records the index as a new commit on the current branch, or on a detached `HEAD`. While a
+/// merge is in progress the commits in `MERGE_HEAD` become extra parents, and without `-m` the
+/// message comes from `MERGE_MSG`.
+///
+/// Unless `--no-verify` is given, the pre-commit hook runs first and the commit-msg hook is
+/// then given the message in `.git/COMMIT_EDITMSG`; either failing aborts the commit. The
+/// message is read back afterwards, as the commit-msg hook may rewrite it.
+pub fn cmd_commit(commit: &Commit) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    if !commit.no_verify {
+        hooks::hook_run(&repo, "pre-commit", &[])?;
+    }
+    // read after the pre-commit hook, which may have staged more changes, and held until the
+    // branch is moved so nothing can be staged in between
+    let _lock = index::index_lock(&repo)?;
+    let index = index::index_read(&repo)?.unwrap_or_default();
+    let unmerged = index::index_unmerged(&index);
+    if !unmerged.is_empty() {
+        return Err(format!(
+            "Committing is not possible because you have unmerged files: {}",
+            unmerged.keys().copied().collect::<Vec<_>>().join(", ")
+        ));
+    }
+    let merge_heads = state_read_shas(&repo, MERGE_HEAD)?;
+    let message = if !commit.message.is_empty() {
+        message_paragraphs(&commit.message)
+    } else if let Some(message) = state_read(&repo, MERGE_MSG)?.filter(|_| !merge_heads.is_empty())
+    {
+        message_cleanup(&message)
+    } else {
+        return Err("A commit message is required, use -m".to_owned());
+    };
+    let message = if commit.no_verify {
+        message
+    } else {
+        state_write(&repo, COMMIT_EDITMSG, message.trim_end())?;
+        hooks::hook_run(&repo, "commit-msg", &[&format!(".git/{}", COMMIT_EDITMSG)])?;
+        message_cleanup(&state_read(&repo, COMMIT_EDITMSG)?.unwrap_or_default())
+    };
+    if message.is_empty() {
+        return Err("Aborting commit due to empty commit message".to_owned());
+    }
+
+    let tree = index::index_write_tree(&repo, &index)?;
+    let parent = refs::ref_resolve(&repo, "HEAD")?;
+    let mut builder = object::CommitBuilder::new().tree(&tree);
+    if let Some(parent) = &parent {
+        // a merge may keep HEAD's tree, but an ordinary commit must change something
+        if merge_heads.is_empty() && object::commit_read(&repo, parent)?.tree() == Some(tree) {
+            return Err("Nothing to commit, the index matches HEAD".to_owned());
         }
+        builder = builder.parent(parent);
+    }
+    for head in merge_heads.iter() {
+        builder = builder.parent(head);
+    }
+    let new = builder
+        .author(object::Identity::from_env(&repo, "AUTHOR")?)
+        .committer(object::Identity::from_env(&repo, "COMMITTER")?)
+        .message(message.clone())
+        .build();
+    let sha = object::object_write(Some(&repo), &new)?;
+
+    let branch = refs::ref_symbolic_target(&repo, "HEAD")?;
+    refs::ref_update(&repo, branch.as_deref().unwrap_or("HEAD"), &sha)?;
+    state_remove(&repo, &[MERGE_HEAD, MERGE_MSG, MERGE_MODE, AUTO_MERGE])?;
+    let branch = branch
+        .as_deref()
+        .map(|b| b.trim_start_matches("refs/heads/"))
+        .unwrap_or("detached HEAD");
+    let root = if parent.is_none() {
+        " (root-commit)"
+    } else {
+        ""
     };
-    if checkout.path.exists() {
-        if !checkout.path.is_dir() {
-            return Err(format!(
-                "\"{}\" is not a directory.",
-                checkout.path.to_string_lossy()
-            ));
+    println!(
+        "[{}{} {}] {}",
+        branch,
+        root,
+        sha.short(),
+        message.lines().next().unwrap_or("")
+    );
+    Ok(())
+}
+
+/// Tidies a prepared message such as `MERGE_MSG` as git does when committing it unedited:
+/// trailing whitespace is stripped, runs of blank lines are collapsed and leading and trailing
+/// blank lines removed. Comment lines are kept, as git only drops them from edited messages.
+fn message_cleanup(message: &str) -> String {
+    let mut out = String::new();
+    let mut blank = false;
+    for line in message.lines().map(str::trim_end) {
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
         }
-        if checkout.path.read_dir().unwrap().count() != 0 {
-            return Err(format!(
-                "\"{}\" is not empty!",
-                checkout.path.to_string_lossy()
-            ));
+        if blank {
+            out.push('\n');
+            blank = false;
         }
-    } else {
-        fs::create_dir(&checkout.path).map_err(|e| format!("Could not create dir: {:?}", e))?;
+        out.push_str(line);
+        out.push('\n');
     }
+    out
+}
+
+/// Joins `-m` paragraphs into a message, separated by blank lines as git does.
+fn message_paragraphs(paragraphs: &[String]) -> String {
+    paragraphs
+        .iter()
+        .map(|paragraph| format!("{}\n", paragraph.trim_end_matches('\n')))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-    tree_checkout(&repo, &tree, checkout.path.clone())?;
+#[derive(Debug, StructOpt)]
+pub struct CommitTree {
+    /// The tree the new commit records
+    tree: String,
+    /// A parent commit, repeatable for merges
+    #[structopt(short = "p", number_of_values = 1)]
+    parents: Vec<String>,
+    /// A paragraph of the commit message; without any the message is read from stdin
+    #[structopt(short = "m", number_of_values = 1)]
+    message: Vec<String>,
+}
+
+/// Creates a commit object for a tree and prints its sha, without moving any refs.
+pub fn cmd_commit_tree(ct: &CommitTree) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    println!("{}", commit_tree(&repo, ct)?);
     Ok(())
 }
 
-fn tree_checkout(repo: &Repo, tree: &Tree, path: PathBuf) -> Result<(), String> {
-    for leaf in tree.iterate_leaves() {
-        let obj = object::object_read(&repo, &leaf.sha)?;
+fn commit_tree(repo: &Repo, ct: &CommitTree) -> Result<Oid, String> {
+    let tree = object::object_find(repo, &ct.tree, Some(ObjectType::Tree), true)?;
+    let mut builder = object::CommitBuilder::new().tree(&tree);
+    for parent in ct.parents.iter() {
+        builder = builder.parent(&object::object_find(
+            repo,
+            parent,
+            Some(ObjectType::Commit),
+            true,
+        )?);
+    }
 
-        let dest = path.join(&leaf.path);
+    let message = if ct.message.is_empty() {
+        let mut message = String::new();
+        std::io::stdin()
+            .read_to_string(&mut message)
+            .map_err(|e| format!("Could not read commit message from stdin: {:?}", e))?;
+        message
+    } else {
+        message_paragraphs(&ct.message)
+    };
 
-        match obj.get_specific() {
-            ObjectSelect::Tree(t) => {
-                fs::create_dir(&dest).expect("create dir in checkout");
-                return tree_checkout(repo, &t, dest);
-            }
-            ObjectSelect::Blob(b) => {
+    let commit = builder
+        .author(object::Identity::from_env(repo, "AUTHOR")?)
+        .committer(object::Identity::from_env(repo, "COMMITTER")?)
+        .message(message)
+        .build();
+    object::object_write(Some(repo), &commit)
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Config {
+    /// Use the global config file instead of the repository's
+    #[structopt(long)]
+    global: bool,
+    /// List all variables set in the config file(s)
+    #[structopt(short, long)]
+    list: bool,
+    /// List all variables whose name matches the regex
+    #[structopt(long = "get-regexp", name = "pattern")]
+    get_regexp: Option<String>,
+    /// Remove the variable `section.key`
+    #[structopt(long, name = "name")]
+    unset: Option<String>,
+    /// Remove the given section, e.g. `remote.origin`
+    #[structopt(long = "remove-section", name = "section")]
+    remove_section: Option<String>,
+}
+
+/// `git config` exits with this code when asked to unset a key that is not set
+pub const CONFIG_EXIT_NOTHING_SET: i32 = 5;
+/// Starts the error `config --unset` gives for a key that is not set, so that it can be told
+/// apart and exit with [`CONFIG_EXIT_NOTHING_SET`]
+pub const CONFIG_NOTHING_SET: &str = "key is not set";
+
+pub fn cmd_config(cfg: &Config) -> Result<(), String> {
+    if cfg.unset.is_some() || cfg.remove_section.is_some() {
+        return config_modify(cfg);
+    }
+
+    let repo = repository::repo_find::<&str>(None, false);
+    let gitdir = repo.as_ref().ok().map(|r| r.gitdir());
+    let mut entries = config::config_flatten(&config::config_global(gitdir)?);
+    if !cfg.global {
+        let repo = repo.as_ref().map_err(|e| e.clone())?;
+        entries.extend(config::config_flatten(repo.config()));
+    }
+
+    if let Some(pattern) = &cfg.get_regexp {
+        let re = regex::Regex::new(pattern)
+            .map_err(|e| format!("Invalid pattern \"{}\": {}", pattern, e))?;
+        for (name, value) in entries.iter().filter(|(name, _)| re.is_match(name)) {
+            println!("{} {}", name, value);
+        }
+    } else if cfg.list {
+        for (name, value) in entries.iter() {
+            println!("{}={}", name, value);
+        }
+    } else {
+        return Err("No action given, expected one of --list or --get-regexp".to_owned());
+    }
+
+    Ok(())
+}
+
+fn config_modify(cfg: &Config) -> Result<(), String> {
+    let path = if cfg.global {
+        config::config_global_path()
+            .ok_or_else(|| "Could not find the global config file: $HOME is not set".to_owned())?
+    } else {
+        let repo = repository::repo_find::<&str>(None, false)?;
+        repo.repo_file("config", false)?
+    };
+
+    // only the lines being removed change, the rest of the file is kept as written
+    config::config_file_edit(&path, |text| {
+        if let Some(name) = &cfg.unset {
+            if !config::config_edit_unset(text, name)? {
+                return Err(format!("{}: {}", CONFIG_NOTHING_SET, name));
+            }
+        }
+        if let Some(section) = &cfg.remove_section {
+            if !config::config_edit_remove_section(text, section) {
+                return Err(format!("No such section: {}", section));
+            }
+        }
+        Ok(())
+    })
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Conflicts {}
+
+/// Lists each path left in conflict by a merge, with the blob each side of it has.
+pub fn cmd_conflicts(_conflicts: &Conflicts) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    let index = match index::index_read(&repo)? {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    let stdout = std::io::stdout();
+    conflicts_list(&index, &mut stdout.lock())
+}
+
+fn conflicts_list(index: &index::Index, out: &mut dyn Write) -> Result<(), String> {
+    for (path, stages) in index::index_unmerged(index) {
+        writeln!(out, "{}", path).map_err(output_err)?;
+        for (name, entry) in ["base", "ours", "theirs"].iter().zip(stages.iter()) {
+            match entry {
+                Some(entry) => writeln!(out, "  {:<7}{:06o} {}", name, entry.mode, entry.sha),
+                None => writeln!(out, "  {:<7}(absent)", name),
+            }
+            .map_err(output_err)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Diff {
+    /// The commit or tree to compare from
+    old: String,
+    /// The commit or tree to compare to
+    new: String,
+    /// Compare files as stored even if their diff driver has a textconv command
+    #[structopt(long)]
+    no_textconv: bool,
+}
+
+/// Prints the changes from one tree to another as a patch.
+pub fn cmd_diff(diff: &Diff) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let old = object::object_find(&repo, &diff.old, Some(ObjectType::Tree), true)?;
+    let new = object::object_find(&repo, &diff.new, Some(ObjectType::Tree), true)?;
+    let changes = diff::diff_trees(&repo, Some(&old), Some(&new))?;
+    let patch = diff::format_patch(&repo, &changes, !diff.no_textconv)?;
+    Pager::start(&repo)
+        .write_all(patch.as_bytes())
+        .map_err(output_err)
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DiffTree {
+    /// Read commits from stdin, one per line, each optionally followed by the commit to
+    /// compare it against instead of its parent
+    #[structopt(long, conflicts_with_all = &["old", "new"])]
+    stdin: bool,
+    /// The commit or tree to compare from
+    #[structopt(required_unless = "stdin")]
+    old: Option<String>,
+    /// The commit or tree to compare to
+    #[structopt(required_unless = "stdin")]
+    new: Option<String>,
+}
+
+/// Prints the changes from one tree to another in the raw diff format, recursing into
+/// subtrees as `git diff-tree -r` does.
+///
+/// With `--stdin`, each line names a commit, which is printed and then compared against the
+/// commit given after it on the line, or else against its parent. As in git, a root commit or
+/// a merge with no commit to compare against prints nothing.
+pub fn cmd_diff_tree(dt: &DiffTree) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let tree = |name: &str| object::object_find(&repo, name, Some(ObjectType::Tree), true);
+
+    if !dt.stdin {
+        let (old, new) = match (&dt.old, &dt.new) {
+            (Some(old), Some(new)) => (tree(old)?, tree(new)?),
+            _ => return Err("Expected <old> <new>".to_owned()),
+        };
+        let changes = diff::diff_trees(&repo, Some(&old), Some(&new))?;
+        return out
+            .write_all(diff::format_raw(&changes).as_bytes())
+            .map_err(output_err);
+    }
+
+    let shallow = repository::shallow_read(&repo)?;
+    for line in std::io::stdin().lock().lines() {
+        let line = line.map_err(|e| format!("Could not read commit from stdin: {:?}", e))?;
+        let mut names = line.split_whitespace();
+        let name = match names.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        let sha = object::object_find(&repo, name, Some(ObjectType::Commit), true)?;
+        let commit = object::commit_read(&repo, &sha)?;
+        let parent = match names.next() {
+            Some(parent) => object::object_find(&repo, parent, Some(ObjectType::Commit), true)?,
+            None => match object::commit_parents(&commit, &sha, &shallow)[..] {
+                [parent] => parent,
+                _ => continue,
+            },
+        };
+        let old = object::commit_read(&repo, &parent)?.tree();
+        let changes = diff::diff_trees(&repo, old.as_ref(), commit.tree().as_ref())?;
+        write!(out, "{}\n{}", sha, diff::format_raw(&changes)).map_err(output_err)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DumpIndex {}
+
+/// Prints everything in the index, for debugging commands that change it.
+pub fn cmd_dump_index(_dump: &DumpIndex) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    match index::index_read(&repo)? {
+        Some(index) => dump_index(&index, &mut out),
+        None => writeln!(out, "no index").map_err(output_err),
+    }
+}
+
+fn dump_index(index: &index::Index, out: &mut dyn Write) -> Result<(), String> {
+    writeln!(out, "version {}", index.version).map_err(output_err)?;
+    writeln!(out, "entries {}", index.entries.len()).map_err(output_err)?;
+    for entry in index.entries.iter() {
+        writeln!(out).map_err(output_err)?;
+        writeln!(out, "path {}", entry.path).map_err(output_err)?;
+        writeln!(
+            out,
+            "  ctime {}:{}",
+            entry.ctime.seconds, entry.ctime.nanoseconds
+        )
+        .map_err(output_err)?;
+        writeln!(
+            out,
+            "  mtime {}:{}",
+            entry.mtime.seconds, entry.mtime.nanoseconds
+        )
+        .map_err(output_err)?;
+        writeln!(out, "  dev {}  ino {}", entry.dev, entry.ino).map_err(output_err)?;
+        writeln!(out, "  mode {:06o}", entry.mode).map_err(output_err)?;
+        writeln!(out, "  uid {}  gid {}", entry.uid, entry.gid).map_err(output_err)?;
+        writeln!(out, "  size {}", entry.size).map_err(output_err)?;
+        writeln!(out, "  sha {}", entry.sha).map_err(output_err)?;
+        writeln!(
+            out,
+            "  flags {:#06x}  extended {:#06x}",
+            entry.flags, entry.extended_flags
+        )
+        .map_err(output_err)?;
+        writeln!(out, "  stage {}", entry.stage()).map_err(output_err)?;
+        let mut bits = vec![];
+        if entry.assume_valid() {
+            bits.push("assume-valid");
+        }
+        if entry.skip_worktree() {
+            bits.push("skip-worktree");
+        }
+        if entry.intent_to_add() {
+            bits.push("intent-to-add");
+        }
+        if !bits.is_empty() {
+            writeln!(out, "  {}", bits.join(" ")).map_err(output_err)?;
+        }
+    }
+    for ext in index.extensions.iter() {
+        writeln!(out).map_err(output_err)?;
+        writeln!(
+            out,
+            "extension {} ({} bytes)",
+            ext.signature,
+            ext.data.len()
+        )
+        .map_err(output_err)?;
+    }
+    writeln!(out).map_err(output_err)?;
+    writeln!(out, "checksum {}", index.checksum).map_err(output_err)?;
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Fsck {}
+
+/// Checks the object store for corruption, printing each problem found. Missing and corrupt
+/// objects make this fail; unreachable ones are only reported.
+pub fn cmd_fsck(_fsck: &Fsck) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let problems = fsck::fsck(&repo)?;
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    match problems.iter().filter(|p| p.is_error()).count() {
+        0 => Ok(()),
+        errors => Err(format!("Found {} problems in the object store", errors)),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct HashObject {
+    /// The type of the object
+    #[structopt(possible_values = ObjectType::variants(), short = "t", long = "type", default_value = "blob")]
+    _type: ObjectType,
+    /// whether to write it or not
+    #[structopt(short = "w")]
+    write: bool,
+    /// Read the paths of the files to hash from stdin, one per line, instead of the command
+    /// line
+    #[structopt(long, conflicts_with = "file")]
+    stdin_paths: bool,
+    #[structopt(parse(from_os_str), required_unless = "stdin-paths")]
+    file: Option<PathBuf>,
+}
+
+pub fn cmd_hash_object(ho: &HashObject) -> Result<(), String> {
+    let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+    hash_object(ho, &mut stdin.lock(), &mut stdout.lock())
+}
+
+/// Hashes the files `ho` names, reading their paths from `input` with `--stdin-paths`, and
+/// prints each sha to `out`.
+fn hash_object(
+    ho: &HashObject,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    let repo = if ho.write {
+        Some(repository::repo_find::<&str>(Some("."), false)?)
+    } else {
+        None
+    };
+    let hash_file = |path: &std::path::Path| -> Result<Oid, String> {
+        let mut reader = fs::OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|e| format!("Could not open file in cmd_hash_object: {:?}", e))?;
+        let store = repo.as_ref().map(|repo| repo as &dyn object::ObjectStore);
+        object::object_hash(&mut reader, ho._type, store)
+    };
+
+    if ho.stdin_paths {
+        for line in input.lines() {
+            let path = line.map_err(|e| format!("Could not read path from stdin: {:?}", e))?;
+            writeln!(out, "{}", hash_file(std::path::Path::new(&path))?).map_err(output_err)?;
+        }
+    } else if let Some(file) = &ho.file {
+        writeln!(out, "{}", hash_file(file)?).map_err(output_err)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Log {
+    /// The commit to inspect
+    #[structopt(default_value = "HEAD")]
+    commit: String,
+    /// Print each commit with the paths it changed and whether they were added (A),
+    /// modified (M) or deleted (D)
+    #[structopt(long = "name-status")]
+    name_status: bool,
+    /// Print each commit with the paths it changed
+    #[structopt(long = "name-only")]
+    name_only: bool,
+}
+
+pub fn cmd_log(log: &Log) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let shallow = repository::shallow_read(&repo)?;
+    let sha = object::object_find(&repo, &log.commit, Some(ObjectType::Commit), true)?;
+
+    let mut out = Pager::start(&repo);
+    if log.name_status || log.name_only {
+        return log_names(&repo, &sha, log.name_status, &shallow, &mut out);
+    }
+
+    writeln!(out, "digraph wyaglog{{").map_err(output_err)?;
+    let mut set = HashSet::new();
+    log_graphviz(&repo, &sha, &mut set, &shallow, &mut out)?;
+    writeln!(out, "}}").map_err(output_err)?;
+
+    Ok(())
+}
+
+/// Prints the history from `sha`, newest first, with the paths each commit changed relative
+/// to its first parent.
+fn log_names(
+    repo: &Repo,
+    sha: &Oid,
+    with_status: bool,
+    shallow: &HashSet<Oid>,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    for sha in graph::rev_list(repo, &[*sha], &[])? {
+        let commit = object::commit_read(repo, &sha)?;
+
+        writeln!(out, "commit {}", sha).map_err(output_err)?;
+        if let Some(author) = commit.get("author") {
+            // drop the timestamp and timezone
+            let name = author[0].rsplitn(3, ' ').nth(2).unwrap_or(&author[0]);
+            writeln!(out, "Author: {}", name).map_err(output_err)?;
+        }
+        writeln!(out).map_err(output_err)?;
+        for line in commit.message().lines() {
+            writeln!(out, "    {}", line).map_err(output_err)?;
+        }
+        writeln!(out).map_err(output_err)?;
+        for change in diff::commit_changes(repo, &sha, shallow)? {
+            if with_status {
+                writeln!(out, "{}\t{}", change.status.letter(), change.path).map_err(output_err)?;
+            } else {
+                writeln!(out, "{}", change.path).map_err(output_err)?;
+            }
+        }
+        writeln!(out).map_err(output_err)?;
+    }
+    Ok(())
+}
+
+fn log_graphviz(
+    repo: &Repo,
+    sha: &Oid,
+    seen: &mut HashSet<Oid>,
+    shallow: &HashSet<Oid>,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    if !seen.insert(*sha) {
+        return Ok(());
+    }
+
+    let commit = object::commit_read(repo, sha)?;
+    for p in object::commit_parents(&commit, sha, shallow) {
+        writeln!(out, "C_{} -> C_{};", sha, p).map_err(output_err)?;
+        log_graphviz(repo, &p, seen, shallow, out)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct LsFiles {
+    /// Show each entry's mode, sha and stage before its path
+    #[structopt(short, long)]
+    stage: bool,
+    /// Show only the entries of paths in conflict; implies --stage
+    #[structopt(short, long)]
+    unmerged: bool,
+}
+
+pub fn cmd_ls_files(ls_files: &LsFiles) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    let index = match index::index_read(&repo)? {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    for entry in index.entries.iter() {
+        if ls_files.unmerged && entry.stage() == 0 {
+            continue;
+        }
+        if ls_files.stage || ls_files.unmerged {
+            println!(
+                "{:06o} {} {}\t{}",
+                entry.mode,
+                entry.sha,
+                entry.stage(),
+                entry.path
+            );
+        } else {
+            println!("{}", entry.path);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct LsTree {
+    /// Recurse into subtrees, showing the blobs in them with their full paths
+    #[structopt(short = "r")]
+    recursive: bool,
+    /// The tree to show
+    object: String,
+}
+
+pub fn cmd_ls_tree(tree: &LsTree) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let stdout = std::io::stdout();
+    ls_tree(&repo, &tree.object, tree.recursive, &mut stdout.lock())
+}
+
+fn ls_tree(repo: &Repo, object: &str, recursive: bool, out: &mut dyn Write) -> Result<(), String> {
+    let obj_inner = object::object_find(repo, object, Some(ObjectType::Tree), true)?;
+    let t = object::tree_read(repo, &obj_inner)?;
+    let mut print = |path: &str, leaf: &object::TreeLeaf| {
+        writeln!(
+            out,
+            "{:06} {} {}\t{}",
+            leaf.mode,
+            leaf.object_type(),
+            leaf.sha,
+            path
+        )
+        .map_err(output_err)
+    };
+    if recursive {
+        t.walk(repo, "", &mut |path, leaf| print(path, leaf))?;
+    } else {
+        for leaf in t.iterate_leaves() {
+            print(&leaf.name(), leaf)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Merge {
+    /// Abandon the merge in progress, putting the index and work tree back as they were
+    #[structopt(long)]
+    abort: bool,
+}
+
+/// Only `--abort` is implemented: the paths the merge changed in the index are reset to
+/// `HEAD`, along with their work tree files, and the merge state files are removed. Changes
+/// that were never staged are kept.
+pub fn cmd_merge(merge: &Merge) -> Result<(), String> {
+    if !merge.abort {
+        return Err("Merging is not implemented yet, only --abort is".to_owned());
+    }
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    if state_read(&repo, MERGE_HEAD)?.is_none() {
+        return Err("There is no merge to abort (MERGE_HEAD missing)".to_owned());
+    }
+    let head = refs::ref_resolve(&repo, "HEAD")?
+        .ok_or_else(|| "Cannot abort the merge, HEAD does not point at a commit".to_owned())?;
+    reset_keep(&repo, &head, true)
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PatchId {
+    /// Compute the patch id of this commit instead of reading a diff from stdin
+    commit: Option<String>,
+}
+
+pub fn cmd_patch_id(pi: &PatchId) -> Result<(), String> {
+    let ids = if let Some(commit) = &pi.commit {
+        let repo = repository::repo_find::<&str>(None, false)?;
+        let shallow = repository::shallow_read(&repo)?;
+        let sha = object::object_find(&repo, commit, Some(ObjectType::Commit), true)?;
+        let patch = diff::commit_patch(&repo, &sha, &shallow)?;
+        diff::patch_id(&patch)
+            .map(|id| (id, sha.to_hex()))
+            .into_iter()
+            .collect()
+    } else {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .map_err(|e| format!("Could not read diff from stdin: {:?}", e))?;
+        diff::patch_ids(&input)
+    };
+
+    for (id, commit) in ids {
+        println!("{} {}", id, commit);
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Rebase {
+    /// Recreate every commit, even those that could be reused because they already follow
+    /// the commit they would be replayed onto
+    #[structopt(long)]
+    no_ff: bool,
+    /// Keep commits whose changes are already upstream, as empty commits, instead of dropping
+    /// them
+    #[structopt(long)]
+    keep_empty: bool,
+    /// The commit to replay the current branch onto
+    upstream: String,
+}
+
+/// Replays the commits in `upstream..HEAD` onto `upstream`, oldest first, then moves the
+/// current branch (or a detached `HEAD`) to the result and updates the work tree and index to
+/// match. A commit whose parent is already the commit it would be replayed onto is reused
+/// unless `--no-ff` is given. Merge commits are left out.
+///
+/// Nothing is done if the work tree or index has changes to tracked files. Each commit's
+/// change is merged path by path, without merging file contents, and a conflict stops the
+/// rebase before anything is changed. With `rerere.enabled`, a conflict that was
+/// resolved before is resolved the same way instead. A commit that becomes empty, because its
+/// change is already upstream, is dropped unless `--keep-empty` is given.
+pub fn cmd_rebase(rebase: &Rebase) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    let head = refs::ref_resolve(&repo, "HEAD")?
+        .ok_or_else(|| "Cannot rebase, HEAD does not point at a commit".to_owned())?;
+    let upstream = object::object_find(&repo, &rebase.upstream, Some(ObjectType::Commit), true)?;
+    let tree_of = |sha: &Oid| -> Result<Oid, String> {
+        object::commit_read(&repo, sha)?
+            .tree()
+            .ok_or_else(|| format!("Commit {} does not have a tree", sha))
+    };
+    let lock = index::index_lock(&repo)?;
+    let index = index::index_read(&repo)?;
+    if let Some(index) = &index {
+        // replaying rewrites every path the commits touch, so work tree edits would be lost
+        let unstaged = status::status(&repo, Some(&tree_of(&head)?), index)?
+            .iter()
+            .any(|e| e.index != '?' && e.worktree != ' ');
+        if unstaged {
+            return Err("cannot rebase: You have unstaged changes".to_owned());
+        }
+        if index::index_write_tree(&repo, index)? != tree_of(&head)? {
+            return Err("Cannot rebase, the index contains uncommitted changes".to_owned());
+        }
+    }
+
+    let rerere = rerere::rerere_enabled(&repo);
+    let mut onto = upstream;
+    for sha in graph::rev_list(&repo, std::slice::from_ref(&head), &[upstream])?
+        .into_iter()
+        .rev()
+    {
+        let commit = object::commit_read(&repo, &sha)?;
+        if commit.parents().len() > 1 {
+            continue;
+        }
+        let parent = commit.parents().first().copied();
+        if !rebase.no_ff && parent == Some(onto) {
+            onto = sha;
+            continue;
+        }
+
+        let subject = commit.message().lines().next().unwrap_or("");
+        let base = parent.map(|p| tree_of(&p)).transpose()?;
+        let onto_tree = tree_of(&onto)?;
+        let reuse = |path: &str, [b, o, t]: [Option<&merge::Leaf>; 3]| {
+            if !rerere {
+                return Ok(None);
+            }
+            let sides = [b.map(|l| &l.1), o.map(|l| &l.1), t.map(|l| &l.1)];
+            let resolution = match rerere::rerere_lookup(&repo, sides)? {
+                Some(resolution) => resolution,
+                None => return Ok(None),
+            };
+            let blob = object::object_write_raw(Some(&repo), ObjectType::Blob, &resolution)?;
+            println!("Resolved '{}' using previous resolution.", path);
+            let mode = o.or(t).map_or(object::MODE_FILE, |l| l.0);
+            Ok(Some((mode, blob)))
+        };
+        let tree = match merge::merge_trees_resolving(
+            &repo,
+            base.as_ref(),
+            Some(&onto_tree),
+            commit.tree().as_ref(),
+            reuse,
+        )? {
+            TreeMerge::Clean(tree) => tree,
+            TreeMerge::Conflicts(paths) => {
+                return Err(format!(
+                    "Could not apply {}... {}: both sides changed {}",
+                    sha.short(),
+                    subject,
+                    paths.join(", ")
+                ))
+            }
+        };
+        // a commit that was empty to begin with is kept, like git does
+        let became_empty = tree == onto_tree && base != commit.tree();
+        if became_empty && !rebase.keep_empty {
+            println!(
+                "dropping {} {} -- patch contents already upstream",
+                sha, subject
+            );
+            continue;
+        }
+
+        let author = commit
+            .author()
+            .ok_or_else(|| format!("Commit {} does not have an author", sha))?;
+        let new = object::CommitBuilder::new()
+            .tree(&tree)
+            .parent(&onto)
+            .author(author)
+            .committer(object::Identity::from_env(&repo, "COMMITTER")?)
+            .message(commit.message().to_owned())
+            .build();
+        onto = object::object_write(Some(&repo), &new)?;
+    }
+
+    let branch = refs::ref_symbolic_target(&repo, "HEAD")?;
+    let name = branch
+        .as_deref()
+        .map_or("detached HEAD", |b| b.trim_start_matches("refs/heads/"));
+    if onto == head {
+        println!("Current branch {} is up to date.", name);
+        return Ok(());
+    }
+    if index.is_some() {
+        worktree_checkout(&repo, lock, &onto)?;
+    } else {
+        worktree_switch(&repo, &onto)?;
+    }
+    refs::ref_update(&repo, branch.as_deref().unwrap_or("HEAD"), &onto)?;
+    println!("Successfully rebased and updated {}.", name);
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Repack {
+    /// Remove the old packs and the loose objects now in the new pack, once it is verified
+    /// to hold every one of their objects
+    #[structopt(short = "d")]
+    delete: bool,
+}
+
+/// Packs every object of the repository into one new pack and prints its path.
+pub fn cmd_repack(repack: &Repack) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let done = pack::repack(&repo, repack.delete)?;
+    println!("{}", done.pack.path.display());
+    if repack.delete {
+        println!(
+            "Removed {} packs and {} loose objects",
+            done.packs_removed, done.loose_removed
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Reset {
+    /// Move to the commit, keeping local changes to the paths it doesn't change
+    #[structopt(long)]
+    keep: bool,
+    /// The commit to reset the current branch to
+    #[structopt(default_value = "HEAD")]
+    commit: String,
+}
+
+/// The previous value of `HEAD`, saved by commands that move it a long way
+const ORIG_HEAD: &str = "ORIG_HEAD";
+
+/// Only `--keep` is implemented.
+pub fn cmd_reset(reset: &Reset) -> Result<(), String> {
+    if !reset.keep {
+        return Err("Only reset --keep is implemented".to_owned());
+    }
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    let target = object::object_find(&repo, &reset.commit, Some(ObjectType::Commit), true)?;
+    reset_keep(&repo, &target, false)
+}
+
+/// Moves the current branch, or a detached `HEAD`, to `target`, saving the old `HEAD` in
+/// `ORIG_HEAD`. The paths that differ between the two commits are reset in the index and work
+/// tree; local changes to any other path are kept. A path with local changes that would have
+/// to be reset is an error, and nothing is changed.
+///
+/// Paths in conflict are reset too, and any merge in progress is forgotten by removing its
+/// state files. With `merge_staged` the staged changes are taken to be the merge's and are
+/// reset as well, for `merge --abort`; only the changes in the work tree that were never
+/// staged are then local changes.
+fn reset_keep(repo: &Repo, target: &Oid, merge_staged: bool) -> Result<(), String> {
+    let tree_of = |sha: &Oid| -> Result<Oid, String> {
+        object::commit_read(repo, sha)?
+            .tree()
+            .ok_or_else(|| format!("Commit {} does not have a tree", sha))
+    };
+    let head = refs::ref_resolve(repo, "HEAD")?
+        .ok_or_else(|| "Cannot reset, HEAD does not point at a commit".to_owned())?;
+    let (head_tree, target_tree) = (tree_of(&head)?, tree_of(target)?);
+    let lock = index::index_lock(repo)?;
+    let mut index = index::index_read(repo)?.unwrap_or_default();
+    let local = status::status(repo, Some(&head_tree), &index)?;
+
+    let leaves = |tree: &Oid| -> Result<BTreeMap<String, (u32, Oid)>, String> {
+        let mut leaves = BTreeMap::new();
+        object::tree_read(repo, tree)?.walk(repo, "", &mut |path, leaf| {
+            leaves.insert(path.to_owned(), (leaf.mode, leaf.sha));
+            Ok(())
+        })?;
+        Ok(leaves)
+    };
+    let (head_leaves, target_leaves) = (leaves(&head_tree)?, leaves(&target_tree)?);
+    let mut changes: BTreeMap<String, diff::TreeChange> =
+        diff::diff_trees(repo, Some(&head_tree), Some(&target_tree))?
+            .into_iter()
+            .map(|change| (change.path.clone(), change))
+            .collect();
+    // paths in conflict, and with `merge_staged` staged ones, also go back to the target
+    let unmerged: BTreeSet<String> = index::index_unmerged(&index)
+        .keys()
+        .map(|p| (*p).to_owned())
+        .collect();
+    let staged: Vec<String> = local
+        .iter()
+        .filter(|e| merge_staged && e.index != ' ' && e.index != '?')
+        .map(|e| e.path.clone())
+        .collect();
+    for path in unmerged.iter().chain(staged.iter()) {
+        if changes.contains_key(path) {
+            continue;
+        }
+        let side = |&(mode, sha): &(u32, Oid)| diff::DiffSide { mode, sha };
+        let new = target_leaves.get(path).map(side);
+        // the work tree holds what the index has for the path, ours in a conflict, or for a
+        // staged deletion what `HEAD` has, if anything
+        let staged = index
+            .entries
+            .iter()
+            .filter(|e| e.path == *path)
+            .min_by_key(|e| [0, 3, 1, 2][e.stage() as usize])
+            .map(|e| (index::tree_mode(e.mode), e.sha));
+        let old = staged.as_ref().or_else(|| head_leaves.get(path)).map(side);
+        let status = match (&old, &new) {
+            (None, _) => diff::ChangeStatus::Added,
+            (_, None) => diff::ChangeStatus::Deleted,
+            _ => diff::ChangeStatus::Modified,
+        };
+        let path = path.clone();
+        changes.insert(
+            path.clone(),
+            diff::TreeChange {
+                status,
+                path,
+                old,
+                new,
+            },
+        );
+    }
+
+    for path in changes.keys().filter(|p| !unmerged.contains(*p)) {
+        let overwritten = local
+            .iter()
+            .find(|e| e.path == *path || (e.path.ends_with('/') && path.starts_with(&e.path)));
+        let only_staged = |e: &status::StatusEntry| e.index != '?' && e.worktree == ' ';
+        if overwritten.is_some_and(|e| !(merge_staged && only_staged(e))) {
+            return Err(format!(
+                "Could not reset to {}: local changes to '{}' would be overwritten",
+                target.short(),
+                path
+            ));
+        }
+    }
+
+    // without `merge_staged` the rest of the index goes back to the target too, leaving
+    // what was staged as a change in the work tree
+    if !merge_staged {
+        let restaged: Vec<(String, Option<(u32, Oid)>)> = target_leaves
+            .iter()
+            .map(|(path, &leaf)| (path.clone(), Some(leaf)))
+            .chain(
+                index
+                    .entries
+                    .iter()
+                    .filter(|e| e.stage() == 0 && !target_leaves.contains_key(&e.path))
+                    .map(|e| (e.path.clone(), None)),
+            )
+            .filter(|(path, _)| !changes.contains_key(path))
+            .collect();
+        for (path, leaf) in restaged {
+            let entry = index
+                .entries
+                .iter()
+                .find(|e| e.path == path && e.stage() == 0);
+            let current = entry.map(|e| (index::tree_mode(e.mode), e.sha));
+            if current == leaf {
+                continue;
+            }
+            let old_entry = entry.cloned();
+            index.remove(&path);
+            if let Some((mode, sha)) = leaf {
+                let full = repo.worktree().join(object::from_git_path(&path));
+                let mut entry = match (old_entry, fs::symlink_metadata(&full)) {
+                    (Some(entry), _) => entry,
+                    (None, Ok(meta)) => index::IndexEntry::from_metadata(&path, &sha, &meta),
+                    // staged as deleted, so there's no file to take stat data from
+                    (None, Err(_)) => index::IndexEntry {
+                        ctime: Default::default(),
+                        mtime: Default::default(),
+                        dev: 0,
+                        ino: 0,
+                        mode: 0,
+                        uid: 0,
+                        gid: 0,
+                        size: 0,
+                        sha,
+                        flags: 0,
+                        extended_flags: 0,
+                        path: path.clone(),
+                    },
+                };
+                entry.sha = sha;
+                entry.mode = index::index_mode(mode);
+                // stat data that can't match, so the file is hashed again
+                entry.mtime = index::IndexTime::default();
+                index.add(entry);
+            }
+        }
+    }
+
+    let changes: Vec<diff::TreeChange> = changes.into_values().collect();
+    worktree_apply(repo, &changes)?;
+    for change in changes.iter() {
+        index.remove(&change.path);
+        if let Some(new) = &change.new {
+            let full = repo.worktree().join(object::from_git_path(&change.path));
+            let meta = fs::symlink_metadata(&full)
+                .map_err(|e| format!("Could not stat {:?}: {:?}", full, e))?;
+            let mut entry = index::IndexEntry::from_metadata(&change.path, &new.sha, &meta);
+            entry.mode = index::index_mode(new.mode);
+            index.add(entry);
+        }
+    }
+    index::index_write(lock, &index)?;
+
+    let branch = refs::ref_symbolic_target(repo, "HEAD")?;
+    refs::ref_update(repo, ORIG_HEAD, &head)?;
+    refs::ref_update(repo, branch.as_deref().unwrap_or("HEAD"), target)?;
+    state_remove(repo, &[MERGE_HEAD, MERGE_MSG, MERGE_MODE, AUTO_MERGE])
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RevParse {
+    /// The revisions to resolve, e.g. `HEAD`, `v1.0^{}` or `v1.0^{commit}`
+    #[structopt(required = true)]
+    revs: Vec<String>,
+}
+
+/// Prints the sha each revision names, one per line.
+pub fn cmd_rev_parse(rp: &RevParse) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    for rev in rp.revs.iter() {
+        println!("{}", object::object_find(&repo, rev, None, false)?);
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Status {
+    /// Give the output in the short format, one `XY <path>` line per path
+    #[structopt(short = "s", long)]
+    short: bool,
+    /// With --short, start with a `## ` line naming the branch, its upstream and how far
+    /// ahead and behind of it the branch is
+    #[structopt(short = "b", long)]
+    branch: bool,
+}
+
+/// Where the current branch stands against its upstream
+enum Tracking {
+    /// No upstream is configured
+    None,
+    /// The upstream is configured but its ref doesn't exist
+    Gone(String),
+    /// The upstream's short name, and the commits only the branch has and only it has
+    Counts(String, usize, usize),
+}
+
+fn status_tracking(repo: &Repo, branch: &str, head: &Oid) -> Result<Tracking, String> {
+    let upstream = match transport::branch_upstream(repo, branch)? {
+        Some(upstream) => upstream,
+        None => return Ok(Tracking::None),
+    };
+    let short = upstream
+        .strip_prefix("refs/remotes/")
+        .or_else(|| upstream.strip_prefix("refs/heads/"))
+        .unwrap_or(&upstream)
+        .to_owned();
+    let upstream = match refs::ref_resolve(repo, &upstream)? {
+        Some(sha) => sha,
+        None => return Ok(Tracking::Gone(short)),
+    };
+    let ahead = graph::rev_list(repo, &[*head], &[upstream])?.len();
+    let behind = graph::rev_list(repo, &[upstream], &[*head])?.len();
+    Ok(Tracking::Counts(short, ahead, behind))
+}
+
+/// The `## ` line of `status --short --branch`, e.g. `## main...origin/main [ahead 2, behind 1]`.
+fn status_branch_header(repo: &Repo, head: Option<&Oid>) -> Result<String, String> {
+    let branch = match refs::ref_symbolic_target(repo, "HEAD")? {
+        Some(branch) => branch,
+        None => return Ok("## HEAD (no branch)".to_owned()),
+    };
+    let name = branch.trim_start_matches("refs/heads/");
+    let head = match head {
+        Some(head) => head,
+        None => return Ok(format!("## No commits yet on {}", name)),
+    };
+    Ok(match status_tracking(repo, name, head)? {
+        Tracking::None => format!("## {}", name),
+        Tracking::Gone(upstream) => format!("## {}...{} [gone]", name, upstream),
+        Tracking::Counts(upstream, ahead, behind) => {
+            let counts = match (ahead, behind) {
+                (0, 0) => String::new(),
+                (ahead, 0) => format!(" [ahead {}]", ahead),
+                (0, behind) => format!(" [behind {}]", behind),
+                (ahead, behind) => format!(" [ahead {}, behind {}]", ahead, behind),
+            };
+            format!("## {}...{}{}", name, upstream, counts)
+        }
+    })
+}
+
+/// The lines of the long status format naming the branch and how it compares to its upstream.
+fn status_branch_long(repo: &Repo, head: Option<&Oid>) -> Result<String, String> {
+    let branch = match (refs::ref_symbolic_target(repo, "HEAD")?, head) {
+        (Some(branch), _) => branch,
+        (None, Some(head)) => return Ok(format!("HEAD detached at {}\n", head.short())),
+        (None, None) => return Ok("HEAD detached\n".to_owned()),
+    };
+    let name = branch.trim_start_matches("refs/heads/");
+    let out = format!("On branch {}\n", name);
+    let head = match head {
+        Some(head) => head,
+        None => return Ok(out + "\nNo commits yet\n\n"),
+    };
+    let commits = |n: usize| if n == 1 { "commit" } else { "commits" };
+    let tracking = match status_tracking(repo, name, head)? {
+        Tracking::None => return Ok(out),
+        Tracking::Gone(upstream) => format!(
+            "Your branch is based on '{}', but the upstream is gone.",
+            upstream
+        ),
+        Tracking::Counts(upstream, 0, 0) => {
+            format!("Your branch is up to date with '{}'.", upstream)
+        }
+        Tracking::Counts(upstream, ahead, 0) => format!(
+            "Your branch is ahead of '{}' by {} {}.",
+            upstream,
+            ahead,
+            commits(ahead)
+        ),
+        Tracking::Counts(upstream, 0, behind) => format!(
+            "Your branch is behind '{}' by {} {}, and can be fast-forwarded.",
+            upstream,
+            behind,
+            commits(behind)
+        ),
+        Tracking::Counts(upstream, ahead, behind) => format!(
+            "Your branch and '{}' have diverged,\nand have {} and {} different commits each, \
+             respectively.",
+            upstream, ahead, behind
+        ),
+    };
+    Ok(format!("{}{}\n\n", out, tracking))
+}
+
+/// The sections of the long status format, grouping `entries` by how they changed, and what
+/// they add up to. An `unborn` branch, with no commits yet, can't have a clean work tree.
+fn status_long(entries: &[status::StatusEntry], unborn: bool) -> String {
+    let describe = |code: char| match code {
+        'A' => "new file:   ",
+        'D' => "deleted:    ",
+        _ => "modified:   ",
+    };
+    let unmerged = |e: &status::StatusEntry| match [e.index, e.worktree] {
+        ['D', 'D'] => Some("both deleted:    "),
+        ['A', 'U'] => Some("added by us:     "),
+        ['U', 'D'] => Some("deleted by them: "),
+        ['U', 'A'] => Some("added by them:   "),
+        ['D', 'U'] => Some("deleted by us:   "),
+        ['A', 'A'] => Some("both added:      "),
+        ['U', 'U'] => Some("both modified:   "),
+        _ => None,
+    };
+    let (mut staged, mut conflicts, mut changed, mut new) =
+        (String::new(), String::new(), String::new(), String::new());
+    for entry in entries {
+        if let Some(what) = unmerged(entry) {
+            conflicts += &format!("\t{}{}\n", what, entry.path);
+        } else if entry.index == '?' {
+            new += &format!("\t{}\n", entry.path);
+        } else {
+            if entry.index != ' ' {
+                staged += &format!("\t{}{}\n", describe(entry.index), entry.path);
+            }
+            if entry.worktree != ' ' {
+                changed += &format!("\t{}{}\n", describe(entry.worktree), entry.path);
+            }
+        }
+    }
+
+    let summary = if !staged.is_empty() || !conflicts.is_empty() {
+        ""
+    } else if !changed.is_empty() {
+        "no changes added to commit\n"
+    } else if !new.is_empty() {
+        "nothing added to commit but untracked files present\n"
+    } else if unborn {
+        "nothing to commit\n"
+    } else {
+        "nothing to commit, working tree clean\n"
+    };
+    let mut out = String::new();
+    for (title, section) in [
+        ("Changes to be committed:", staged),
+        ("Unmerged paths:", conflicts),
+        ("Changes not staged for commit:", changed),
+        ("Untracked files:", new),
+    ] {
+        if !section.is_empty() {
+            out += &format!("{}\n{}\n", title, section);
+        }
+    }
+    out + summary
+}
+
+/// Prints how the index differs from `HEAD` and the work tree from the index, with paths
+/// relative to the top of the work tree. The long format starts with the branch and how far
+/// ahead of and behind its upstream it is; `--short` gives one `XY <path>` line per path, as
+/// `git status --short` does, with that in a `## ` line first if `--branch` is given.
+pub fn cmd_status(status: &Status) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    let head = refs::ref_resolve(&repo, "HEAD")?;
+    let tree = match &head {
+        Some(head) => object::commit_read(&repo, head)?.tree(),
+        None => None,
+    };
+    let index = index::index_read(&repo)?.unwrap_or_default();
+    let entries = status::status(&repo, tree.as_ref(), &index)?;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if !status.short {
+        let long =
+            status_branch_long(&repo, head.as_ref())? + &status_long(&entries, head.is_none());
+        return write!(out, "{}", long).map_err(output_err);
+    }
+    if status.branch {
+        writeln!(out, "{}", status_branch_header(&repo, head.as_ref())?).map_err(output_err)?;
+    }
+    for entry in entries {
+        writeln!(out, "{}{} {}", entry.index, entry.worktree, entry.path).map_err(output_err)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Show {
+    /// The object to show, e.g. `HEAD`, `v1.0` or `HEAD:src/main.rs`
+    #[structopt(default_value = "HEAD")]
+    object: String,
+}
+
+pub fn cmd_show(show: &Show) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let mut out = Pager::start(&repo);
+    show_object(&repo, &show.object, &mut out)
+}
+
+fn show_object(repo: &Repo, object: &str, out: &mut dyn Write) -> Result<(), String> {
+    let sha = object::object_find(repo, object, None, false)?;
+    let obj = object::object_read(repo, &sha)?;
+    match obj.get_specific() {
+        ObjectSelect::Blob(b) => out.write_all(&b.data).map_err(output_err)?,
+        ObjectSelect::Tree(t) => {
+            writeln!(out, "tree {}\n", object).map_err(output_err)?;
+            for leaf in t.iterate_leaves() {
+                let suffix = if leaf.mode == MODE_TREE { "/" } else { "" };
+                writeln!(out, "{}{}", leaf.name(), suffix).map_err(output_err)?;
+            }
+        }
+        ObjectSelect::Commit(_) | ObjectSelect::Tag(_) => {
+            out.write_all(&obj.serialize()).map_err(output_err)?
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ShowIndex {
+    /// The pack index to read, instead of reading it from stdin
+    #[structopt(parse(from_os_str))]
+    file: Option<PathBuf>,
+}
+
+/// Prints each entry of a pack index as `<offset> <sha> (<crc32>)`, sorted by offset. Version 1
+/// indexes don't record a CRC, so their entries are just `<offset> <sha>`.
+pub fn cmd_show_index(si: &ShowIndex) -> Result<(), String> {
+    let data = match &si.file {
+        Some(file) => fs::read(file).map_err(|e| format!("Could not read {:?}: {:?}", file, e))?,
+        None => {
+            let mut data = vec![];
+            std::io::stdin()
+                .read_to_end(&mut data)
+                .map_err(|e| format!("Could not read pack index from stdin: {:?}", e))?;
+            data
+        }
+    };
+    let stdout = std::io::stdout();
+    show_index(&data, &mut stdout.lock())
+}
+
+fn show_index(data: &[u8], out: &mut dyn Write) -> Result<(), String> {
+    let index = pack::PackIndex::parse(data)?;
+    let mut entries: Vec<usize> = (0..index.shas.len()).collect();
+    entries.sort_by_key(|&i| index.offsets[i]);
+    for i in entries {
+        match index.crcs.get(i) {
+            Some(crc) => writeln!(out, "{} {} ({:08x})", index.offsets[i], index.shas[i], crc),
+            None => writeln!(out, "{} {}", index.offsets[i], index.shas[i]),
+        }
+        .map_err(output_err)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Checkout {
+    /// Create this branch at the commit given, `HEAD` by default, and switch the work tree
+    /// and index to it
+    #[structopt(short = "b", name = "new-branch")]
+    new_branch: Option<String>,
+    /// The commit or tree to checkout
+    commit: Option<String>,
+    /// The path at which to checkout
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+/// Checks out a commit or tree into an empty directory, or with `-b` creates a branch and
+/// switches to it.
+pub fn cmd_checkout(checkout: &Checkout) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    repo.require_worktree()?;
+    if let Some(branch) = &checkout.new_branch {
+        return checkout_new_branch(&repo, branch, checkout.commit.as_deref());
+    }
+    let (rev, path) = match (&checkout.commit, &checkout.path) {
+        (Some(rev), Some(path)) => (rev, path),
+        _ => return Err("Expected <commit> <path>, or -b <new-branch>".to_owned()),
+    };
+    let obj_inner = object::object_find(&repo, rev, None, true)?;
+    let obj = object::object_read(&repo, &obj_inner)?;
+    let tree = match obj.get_specific() {
+        ObjectSelect::Tree(tree) => tree,
+        ObjectSelect::Commit(commit) => {
+            let t_obj = commit
+                .tree()
+                .ok_or_else(|| format!("Commit \"{}\" does not have a tree!", rev))?;
+            let t_dyn = object::object_read(&repo, &t_obj)?;
+            if let ObjectSelect::Tree(tree) = t_dyn.get_specific() {
+                tree
+            } else {
+                return Err(format!(
+                    "Commit \"{}\"'s `tree` field points to object \"{}\" which is not a tree!",
+                    rev, t_obj
+                ));
+            }
+        }
+        _ => return Err(format!("Object \"{}\" is not a commit or tree", rev)),
+    };
+    if path.exists() {
+        if !path.is_dir() {
+            return Err(format!(
+                "\"{}\" is not a directory.",
+                path.to_string_lossy()
+            ));
+        }
+        if path.read_dir().unwrap().count() != 0 {
+            return Err(format!("\"{}\" is not empty!", path.to_string_lossy()));
+        }
+    } else {
+        fs::create_dir(path).map_err(|e| format!("Could not create dir: {:?}", e))?;
+    }
+
+    let plan = checkout_plan(&repo, &tree, path.clone())?;
+    checkout_apply(&repo, &plan)
+}
+
+/// Creates `branch` at `start` and makes it the current branch. If that moves `HEAD` to
+/// another commit, the work tree and index are switched to it, which takes the tracked files
+/// being unchanged from `HEAD`.
+fn checkout_new_branch(repo: &Repo, branch: &str, start: Option<&str>) -> Result<(), String> {
+    let lock = index::index_lock(repo)?;
+    let head = refs::ref_resolve(repo, "HEAD")?;
+    let target = object::object_find(
+        repo,
+        start.unwrap_or("HEAD"),
+        Some(ObjectType::Commit),
+        true,
+    );
+    if let (Some(head), Ok(target)) = (&head, &target) {
+        if head != target {
+            require_clean(repo, "checkout")?;
+        }
+    }
+
+    let sha = branch_create(repo, branch, start)?;
+    if head != Some(sha) {
+        worktree_checkout(repo, lock, &sha)?;
+    }
+    refs::ref_set_symbolic(repo, "HEAD", &format!("refs/heads/{}", branch))?;
+    println!("Switched to a new branch '{}'", branch);
+    Ok(())
+}
+
+/// Fails if any tracked file has changes, staged or not, that switching `HEAD` to another
+/// commit with `command` could overwrite. Untracked files don't count.
+fn require_clean(repo: &Repo, command: &str) -> Result<(), String> {
+    let tree = match refs::ref_resolve(repo, "HEAD")? {
+        Some(head) => object::commit_read(repo, &head)?.tree(),
+        None => None,
+    };
+    let index = index::index_read(repo)?.unwrap_or_default();
+    let dirty = status::status(repo, tree.as_ref(), &index)?
+        .into_iter()
+        .any(|e| e.index != '?');
+    if dirty {
+        return Err(format!(
+            "Your local changes would be overwritten by {}; commit them first",
+            command
+        ));
+    }
+    Ok(())
+}
+
+/// Switches the work tree and index from the commit `HEAD` points at to the commit `sha`, as
+/// checking it out does. `HEAD` itself is left for the caller to move, and local changes for
+/// the caller to check for with [`require_clean`], holding the index `lock` while it does.
+fn worktree_checkout(repo: &Repo, lock: LockFile, sha: &Oid) -> Result<(), String> {
+    worktree_switch(repo, sha)?;
+    let tree = object::commit_read(repo, sha)?
+        .tree()
+        .ok_or_else(|| format!("Commit {} does not have a tree", sha))?;
+    index::index_write(lock, &index::index_from_tree(repo, &tree)?)
+}
+
+/// Updates the work tree from the commit `HEAD` points at to the commit `sha`, touching only
+/// the paths that differ between their trees. `HEAD` itself is left for the caller to move.
+///
+/// Changed files are overwritten, so callers check for local changes first.
+pub fn worktree_switch(repo: &Repo, sha: &Oid) -> Result<(), String> {
+    repo.require_worktree()?;
+    let tree_of = |sha: &Oid| -> Result<Oid, String> {
+        object::commit_read(repo, sha)?
+            .tree()
+            .ok_or_else(|| format!("Commit {} does not have a tree", sha))
+    };
+    let old = match refs::ref_resolve(repo, "HEAD")? {
+        Some(head) => Some(tree_of(&head)?),
+        None => None,
+    };
+    let new = tree_of(sha)?;
+    let changes = diff::diff_trees(repo, old.as_ref(), Some(&new))?;
+    worktree_apply(repo, &changes)
+}
+
+/// Applies `changes` to the work tree: the old side of each is removed and the new side, if
+/// there is one, written in its place. Submodules are left alone.
+fn worktree_apply(repo: &Repo, changes: &[diff::TreeChange]) -> Result<(), String> {
+    // remove first, so a directory replaced by a file (or the reverse) is out of the way
+    for change in changes.iter().filter(|c| c.old.is_some()) {
+        if change.old.as_ref().is_some_and(|o| o.mode == MODE_GITLINK) {
+            continue;
+        }
+        let path = repo.worktree().join(object::from_git_path(&change.path));
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(format!("Could not remove {:?}: {:?}", path, e));
+            }
+            _ => (),
+        }
+        // prune directories left empty; `remove_dir` fails on the first non-empty one
+        let mut dir = path.parent();
+        while let Some(d) = dir.filter(|d| *d != repo.worktree()) {
+            if fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+
+    let mut plan = vec![];
+    for change in changes {
+        let new = match &change.new {
+            Some(new) if new.mode != MODE_GITLINK => new,
+            _ => continue,
+        };
+        let path = repo.worktree().join(object::from_git_path(&change.path));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Could not create dir {:?}: {:?}", parent, e))?;
+        }
+        plan.push(match new.mode {
+            MODE_SYMLINK => CheckoutAction::CreateSymlink { path, sha: new.sha },
+            mode => CheckoutAction::CreateFile {
+                path,
+                mode,
+                sha: new.sha,
+            },
+        });
+    }
+    checkout_apply(repo, &plan)
+}
+
+#[derive(Debug, StructOpt)]
+pub struct WriteTree {}
+
+/// Writes the index out as tree objects and prints the root tree's sha.
+pub fn cmd_write_tree(_wt: &WriteTree) -> Result<(), String> {
+    let repo = repository::repo_find::<&str>(None, false)?;
+    let index = index::index_read(&repo)?.unwrap_or_default();
+    println!("{}", index::index_write_tree(&repo, &index)?);
+    Ok(())
+}
+
+/// A single filesystem operation produced by planning a checkout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckoutAction {
+    CreateDir { path: PathBuf },
+    CreateFile { path: PathBuf, mode: u32, sha: Oid },
+    CreateSymlink { path: PathBuf, sha: Oid },
+}
+
+/// Walks `tree` and returns the actions needed to materialize it at `path`, without touching
+/// the filesystem. Only tree objects are read; blob contents are left to [`checkout_apply`].
+pub fn checkout_plan(
+    repo: &Repo,
+    tree: &Tree,
+    path: PathBuf,
+) -> Result<Vec<CheckoutAction>, String> {
+    let mut plan = vec![];
+    let mut dirs = HashSet::new();
+    tree.walk(repo, "", &mut |name, leaf| {
+        // create each directory before the first thing in it
+        let parents = name.rsplit_once('/').map_or("", |(parents, _)| parents);
+        let mut dir = String::new();
+        for component in parents.split('/').filter(|c| !c.is_empty()) {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir += component;
+            if dirs.insert(dir.clone()) {
+                plan.push(CheckoutAction::CreateDir {
+                    path: path.join(object::from_git_path(&dir)),
+                });
+            }
+        }
+
+        let dest = path.join(object::from_git_path(name));
+        match leaf.mode {
+            MODE_SYMLINK => plan.push(CheckoutAction::CreateSymlink {
+                path: dest,
+                sha: leaf.sha,
+            }),
+            // submodules are not checked out
+            MODE_GITLINK => (),
+            mode => plan.push(CheckoutAction::CreateFile {
+                path: dest,
+                mode,
+                sha: leaf.sha,
+            }),
+        }
+        Ok(())
+    })?;
+    Ok(plan)
+}
+
+/// Executes a plan produced by [`checkout_plan`] in order.
+pub fn checkout_apply(repo: &Repo, plan: &[CheckoutAction]) -> Result<(), String> {
+    for action in plan {
+        match action {
+            CheckoutAction::CreateDir { path } => {
+                fs::create_dir(path).map_err(|e| format!("Could not create dir: {:?}", e))?;
+            }
+            CheckoutAction::CreateFile { path, mode, sha } => {
                 let mut f = fs::OpenOptions::new()
                     .write(true)
                     .create_new(true)
-                    .open(&dest)
-                    .expect("open file in checkout");
-                f.write_all(&b.data).expect("write blob in checkout");
+                    .open(path)
+                    .map_err(|e| format!("Could not create file {:?}: {:?}", path, e))?;
+                object::blob_copy_to(repo, sha, &mut f)
+                    .map_err(|e| format!("Could not write file {:?}: {}", path, e))?;
+                #[cfg(unix)]
+                {
+                    if *mode == MODE_EXECUTABLE {
+                        use std::os::unix::fs::PermissionsExt;
+                        f.set_permissions(fs::Permissions::from_mode(0o755))
+                            .map_err(|e| format!("Could not set mode of {:?}: {:?}", path, e))?;
+                    }
+                }
+            }
+            CheckoutAction::CreateSymlink { path, sha } => {
+                let target = object::blob_read(repo, sha)?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::ffi::OsStrExt;
+                    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(&target), path)
+                        .map_err(|e| format!("Could not create symlink {:?}: {:?}", path, e))?;
+                }
+                #[cfg(not(unix))]
+                {
+                    fs::write(path, &target)
+                        .map_err(|e| format!("Could not write file {:?}: {:?}", path, e))?;
+                }
             }
-            _ => (),
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{TreeBuilder, MODE_FILE};
+    use crate::test_util::{args, TempRepo};
+
+    /// Output kept along with the largest single write, to check that output is streamed a
+    /// buffer at a time rather than written all at once.
+    #[derive(Default)]
+    struct Recorder {
+        data: Vec<u8>,
+        largest: usize,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            self.largest = self.largest.max(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A merge stopped on a conflict in `f.txt`, with the blobs of its three sides.
+    struct Conflict {
+        base: Oid,
+        ours: Oid,
+        theirs: Oid,
+    }
+
+    /// Leaves a merge of branch `theirs` into branch `ours` stopped on a conflict in `f.txt`,
+    /// as git would: the path's three stages in the index, conflict markers in the work tree,
+    /// and `MERGE_HEAD` and `MERGE_MSG` written. `theirs` also adds `g.txt`, which merges
+    /// cleanly and is staged. Merging isn't implemented, so the state is written directly.
+    fn conflicted_merge(t: &TempRepo) -> Conflict {
+        t.write("f.txt", "base\n");
+        let base = t.commit("base");
+        cmd_checkout(&args(&["-b", "theirs"])).unwrap();
+        t.write("f.txt", "theirs\n");
+        t.write("g.txt", "added\n");
+        let merge_head = t.commit("theirs");
+        cmd_checkout(&args(&["-b", "ours", &base.to_hex()])).unwrap();
+        t.write("f.txt", "ours\n");
+        t.commit("ours");
+
+        let repo = t.repo();
+        let conflict = Conflict {
+            base: t.blob("base\n"),
+            ours: t.blob("ours\n"),
+            theirs: t.blob("theirs\n"),
+        };
+        t.write("g.txt", "added\n");
+        cmd_add(&args(&["g.txt"])).unwrap();
+        let lock = index::index_lock(&repo).unwrap();
+        let mut index = index::index_read(&repo).unwrap().unwrap();
+        let staged = index
+            .entries
+            .iter()
+            .find(|e| e.path == "f.txt")
+            .unwrap()
+            .clone();
+        index.remove("f.txt");
+        let sides = [conflict.base, conflict.ours, conflict.theirs];
+        for (stage, sha) in (1..).zip(sides.iter()) {
+            let pos = index.entries.iter().position(|e| e.path.as_str() > "f.txt");
+            let entry = index::IndexEntry {
+                sha: *sha,
+                flags: staged.flags | stage << 12,
+                ..staged.clone()
+            };
+            index
+                .entries
+                .insert(pos.unwrap_or(index.entries.len()), entry);
+        }
+        index::index_write(lock, &index).unwrap();
+
+        t.write(
+            "f.txt",
+            "<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> theirs\n",
+        );
+        state_write(&repo, MERGE_HEAD, &merge_head.to_hex()).unwrap();
+        let message = "Merge branch 'theirs' into ours\n\n# Conflicts:\n#\tf.txt";
+        state_write(&repo, MERGE_MSG, message).unwrap();
+        conflict
+    }
+
+    #[test]
+    fn checkout_plan_lists_actions_in_tree_order() {
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let (text, script, target) = (t.blob("text\n"), t.blob("#!/bin/sh\n"), t.blob("a.txt"));
+        let write = |builder: TreeBuilder| object::object_write(Some(&repo), &builder.build());
+        let sub = write(TreeBuilder::new().entry(MODE_FILE, "deep.txt", &text)).unwrap();
+        let dir = write(TreeBuilder::new().entry(MODE_TREE, "sub", &sub)).unwrap();
+        let bin = write(TreeBuilder::new().entry(MODE_EXECUTABLE, "run", &script)).unwrap();
+        let root = TreeBuilder::new()
+            .entry(MODE_SYMLINK, "link", &target)
+            .entry(MODE_GITLINK, "module", &text)
+            .entry(MODE_TREE, "dir", &dir)
+            .entry(MODE_TREE, "bin", &bin)
+            .entry(MODE_FILE, "a.txt", &text)
+            .build();
+
+        let dest = PathBuf::from("/checkout");
+        let plan = checkout_plan(&repo, &root, dest.clone()).unwrap();
+        let file = |path: &str, mode, sha| CheckoutAction::CreateFile {
+            path: dest.join(path),
+            mode,
+            sha,
+        };
+        let dir = |path: &str| CheckoutAction::CreateDir {
+            path: dest.join(path),
+        };
+        assert_eq!(
+            plan,
+            vec![
+                file("a.txt", MODE_FILE, text),
+                dir("bin"),
+                file("bin/run", MODE_EXECUTABLE, script),
+                dir("dir"),
+                dir("dir/sub"),
+                file("dir/sub/deep.txt", MODE_FILE, text),
+                CheckoutAction::CreateSymlink {
+                    path: dest.join("link"),
+                    sha: target,
+                },
+            ]
+        );
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn config_unset_and_remove_section_are_saved() {
+        let t = TempRepo::new();
+        let config = t.repo().gitdir().join("config");
+        let before = "# my remote, do not touch\n\
+                      [core]\n\trepositoryformatversion = 0\n\tbare = false ; trailing\n\
+                      [remote \"origin\"]\n\turl = /srv/repo.git\n\
+                      \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+                      [user]\n\tname = A U Thor\n\temail = author@example.com\n\
+                      [alias]\n\tst = status\n";
+        fs::write(&config, before).unwrap();
+
+        cmd_config(&args(&["--unset", "user.email"])).unwrap();
+        let repo = t.repo();
+        assert_eq!(repo.config_value("user.email"), None);
+        assert_eq!(repo.config_value("user.name").as_deref(), Some("A U Thor"));
+        let unset = before.replace("\temail = author@example.com\n", "");
+        assert_eq!(fs::read_to_string(&config).unwrap(), unset);
+
+        cmd_config(&args(&["--remove-section", "remote.origin"])).unwrap();
+        let repo = t.repo();
+        assert_eq!(repo.config_value("remote.origin.url"), None);
+        assert_eq!(repo.config_value("remote.origin.fetch"), None);
+        assert_eq!(repo.config_value("user.name").as_deref(), Some("A U Thor"));
+        let removed = unset.replace(
+            "[remote \"origin\"]\n\turl = /srv/repo.git\n\
+             \tfetch = +refs/heads/*:refs/remotes/origin/*\n",
+            "",
+        );
+        assert_eq!(fs::read_to_string(&config).unwrap(), removed);
+
+        let err = cmd_config(&args(&["--unset", "user.email"])).unwrap_err();
+        assert!(err.starts_with(CONFIG_NOTHING_SET), "{}", err);
+        assert_eq!(fs::read_to_string(&config).unwrap(), removed);
+    }
+
+    #[test]
+    fn log_stops_at_the_shallow_boundary() {
+        let t = TempRepo::new();
+        let mut commits = vec![];
+        for contents in ["one", "two", "three"] {
+            t.write("file.txt", contents);
+            commits.push(t.commit(contents));
+        }
+        let repo = t.repo();
+        repository::shallow_write(&repo, &commits[1..2]).unwrap();
+        fs::remove_file(object::object_path(&repo, &commits[0], false).unwrap()).unwrap();
+
+        let repo = t.repo();
+        let shallow = repository::shallow_read(&repo).unwrap();
+        let mut out = vec![];
+        log_names(&repo, &commits[2], true, &shallow, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let logged: Vec<&str> = out
+            .lines()
+            .filter_map(|l| l.strip_prefix("commit "))
+            .collect();
+        assert_eq!(logged, vec![commits[2].to_hex(), commits[1].to_hex()]);
+        // the boundary commit shows its whole tree as added
+        assert!(out.ends_with("    two\n\nA\tfile.txt\n\n"), "{}", out);
+
+        let mut out = vec![];
+        log_graphviz(&repo, &commits[2], &mut HashSet::new(), &shallow, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("C_{} -> C_{};\n", commits[2], commits[1])
+        );
+    }
+
+    #[test]
+    fn ls_tree_and_show_take_rev_paths() {
+        let t = TempRepo::new();
+        t.write("subdir/inner/deep.txt", "deep\n");
+        t.write("subdir/file.txt", "file\n");
+        t.write("top.txt", "top\n");
+        t.commit("first");
+        let repo = t.repo();
+        let inner = object::object_find(&repo, "HEAD:subdir/inner", None, false).unwrap();
+        let blob = |contents: &[u8]| object::object_sha(ObjectType::Blob, contents);
+
+        let mut out = vec![];
+        ls_tree(&repo, "HEAD:subdir", false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "100644 blob {}\tfile.txt\n040000 tree {}\tinner\n",
+                blob(b"file\n"),
+                inner
+            )
+        );
+        let mut out = vec![];
+        ls_tree(&repo, "HEAD:subdir", true, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "100644 blob {}\tfile.txt\n100644 blob {}\tinner/deep.txt\n",
+                blob(b"file\n"),
+                blob(b"deep\n")
+            )
+        );
+
+        let mut out = vec![];
+        show_object(&repo, "HEAD:subdir/inner/deep.txt", &mut out).unwrap();
+        assert_eq!(out, b"deep\n");
+        let mut out = vec![];
+        show_object(&repo, "HEAD:subdir", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tree HEAD:subdir\n\nfile.txt\ninner/\n"
+        );
+    }
+
+    #[test]
+    fn log_name_status_lists_each_commits_changes() {
+        let t = TempRepo::new();
+        t.write("a.txt", "a\n");
+        t.write("b.txt", "b\n");
+        t.commit("add a and b");
+        t.write("a.txt", "a2\n");
+        t.write("dir/c.txt", "c\n");
+        t.commit("change a, add c");
+        fs::remove_file(t.path().join("b.txt")).unwrap();
+        let head = t.commit("delete b");
+
+        let repo = t.repo();
+        let status_lines = |with_status| {
+            let mut out = vec![];
+            log_names(&repo, &head, with_status, &HashSet::new(), &mut out).unwrap();
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .filter(|l| !l.is_empty() && !l.starts_with(|c: char| c.is_whitespace()))
+                .filter(|l| !l.starts_with("commit ") && !l.starts_with("Author: "))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            status_lines(true),
+            vec![
+                "D\tb.txt",
+                "M\ta.txt",
+                "A\tdir/c.txt",
+                "A\ta.txt",
+                "A\tb.txt"
+            ]
+        );
+        assert_eq!(
+            status_lines(false),
+            vec!["b.txt", "a.txt", "dir/c.txt", "a.txt", "b.txt"]
+        );
+    }
+
+    #[test]
+    fn cherry_marks_a_commit_already_upstream() {
+        let t = TempRepo::new();
+        t.write("a.txt", "one\ntwo\nthree\n");
+        t.write("b.txt", "b\n");
+        let base = t.commit("base");
+        cmd_checkout(&args(&["-b", "topic"])).unwrap();
+        t.write("a.txt", "one\n2\nthree\n");
+        let picked = t.commit("change a");
+        t.write("b.txt", "b changed\n");
+        let unpicked = t.commit("change b");
+        // an empty commit on each side has no patch to match the other by
+        let empty_commit = |branch: &str| {
+            let repo = t.repo();
+            let parent = t.head();
+            let commit = object::CommitBuilder::new()
+                .tree(&object::commit_read(&repo, &parent).unwrap().tree().unwrap())
+                .parent(&parent)
+                .author(object::Identity::from_env(&repo, "AUTHOR").unwrap())
+                .committer(object::Identity::from_env(&repo, "COMMITTER").unwrap())
+                .message("empty\n".to_owned())
+                .build();
+            let sha = object::object_write(Some(&repo), &commit).unwrap();
+            refs::ref_update(&repo, branch, &sha).unwrap();
+            sha
+        };
+        let empty = empty_commit("refs/heads/topic");
+        let topic = t.head();
+
+        cmd_checkout(&args(&["-b", "upstream", &base.to_hex()])).unwrap();
+        t.write("d.txt", "d\n");
+        t.commit("add d");
+        t.write("a.txt", "one\n2\nthree\n");
+        t.commit("change a again");
+        empty_commit("refs/heads/upstream");
+        let upstream = t.head();
+
+        let repo = t.repo();
+        assert_eq!(
+            cherry_marks(&repo, &upstream, &topic).unwrap(),
+            vec![(true, picked), (false, unpicked), (false, empty)]
+        );
+    }
+
+    #[test]
+    fn bisect_finds_the_first_bad_commit() {
+        let t = TempRepo::new();
+        let mut commits = vec![];
+        for i in 0..9 {
+            t.write("counter.txt", &format!("{}\n", i));
+            t.write("state.txt", if i < 6 { "good\n" } else { "bad\n" });
+            commits.push(t.commit(&format!("commit {}", i)));
+        }
+        let culprit = commits[6];
+
+        let bisect = |argv: &[&str]| cmd_bisect(&args(argv)).unwrap();
+        bisect(&["start", "HEAD", &commits[0].to_hex()]);
+        let mut steps = 0;
+        let found = loop {
+            let repo = t.repo();
+            let bad = state_read_shas(&repo, BISECT_BAD).unwrap()[0];
+            let good = state_read_shas(&repo, BISECT_GOOD).unwrap();
+            match graph::bisect_next(&repo, &bad, &good).unwrap() {
+                graph::BisectStep::Found(sha) => break sha,
+                graph::BisectStep::Test { sha, .. } => {
+                    // each step is checked out through the index, leaving nothing to commit
+                    assert_eq!(t.head(), sha);
+                    let tree = object::commit_read(&repo, &sha).unwrap().tree();
+                    let index = index::index_read(&repo).unwrap().unwrap();
+                    assert!(status::status(&repo, tree.as_ref(), &index)
+                        .unwrap()
+                        .is_empty());
+                    let verdict = t.read("state.txt");
+                    bisect(&[verdict.trim()]);
+                }
+            }
+            steps += 1;
+            assert!(steps < commits.len(), "bisect did not converge");
+        };
+        assert_eq!(found, culprit);
+        // eight suspects take three steps to narrow down
+        assert_eq!(steps, 3);
+
+        bisect(&["reset"]);
+        let repo = t.repo();
+        assert_eq!(
+            refs::ref_symbolic_target(&repo, "HEAD").unwrap().as_deref(),
+            Some("refs/heads/master")
+        );
+        assert_eq!(t.read("counter.txt"), "8\n");
+        assert_eq!(state_read(&repo, BISECT_START).unwrap(), None);
+    }
+
+    #[test]
+    fn blame_porcelain_matches_git() {
+        let t = TempRepo::new();
+        t.write("file.txt", "one\ntwo\nthree\n");
+        let first = t.commit("first");
+        t.write("file.txt", "one\n\"2\"\nthree\nfour\n");
+        let second = t.commit("second");
+
+        let repo = t.repo();
+        let lines = blame::blame(&repo, &second, "file.txt").unwrap();
+        let commits: HashMap<Oid, object::Commit> = [first, second]
+            .iter()
+            .map(|sha| (*sha, object::commit_read(&repo, sha).unwrap()))
+            .collect();
+        let mut out = vec![];
+        blame_porcelain(&lines, &commits, "file.txt", &mut out).unwrap();
+
+        let details = |time: u32, summary: &str| {
+            let mut out = String::new();
+            for role in ["author", "committer"] {
+                out += &format!(
+                    "{0} A U Thor\n{0}-mail <author@example.com>\n{0}-time {1}\n{0}-tz +0000\n",
+                    role, time
+                );
+            }
+            out + &format!("summary {}\n", summary)
+        };
+        let expected = format!(
+            "{first} 1 1 1\n{}boundary\nfilename file.txt\n\tone\n\
+             {second} 2 2 1\n{}previous {first} file.txt\nfilename file.txt\n\t\"2\"\n\
+             {first} 3 3 1\n\tthree\n\
+             {second} 4 4 1\n\tfour\n",
+            details(1_600_000_001, "first"),
+            details(1_600_000_002, "second"),
+            first = first,
+            second = second,
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn blame_json_maps_lines_to_commits() {
+        let t = TempRepo::new();
+        t.write("file.txt", "one\ntwo\nthree\n");
+        let first = t.commit("first");
+        t.write("file.txt", "one\n\"2\"\nthree\nfour\n");
+        let second = t.commit("second");
+
+        let repo = t.repo();
+        let lines = blame::blame(&repo, &second, "file.txt").unwrap();
+        let commits: HashMap<Oid, object::Commit> = [first, second]
+            .iter()
+            .map(|sha| (*sha, object::commit_read(&repo, sha).unwrap()))
+            .collect();
+        let mut out = vec![];
+        blame_json(&lines, &commits, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // each line's object is on a line of its own, with no nesting
+        let field = |object: &str, key: &str| -> String {
+            let start = object.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+            let rest = &object[start..];
+            let end = if let Some(quoted) = rest.strip_prefix('"') {
+                quoted.find('"').map(|i| i + 2).unwrap()
+            } else {
+                rest.find([',', '}']).unwrap()
+            };
+            rest[..end].to_owned()
+        };
+        let mut rows = out.lines();
+        assert_eq!(rows.next(), Some("["));
+        let parsed: Vec<(String, String, String, String)> = rows
+            .by_ref()
+            .take(4)
+            .map(|row| {
+                let object = row.trim().trim_end_matches(',');
+                assert!(object.starts_with('{') && object.ends_with('}'), "{}", row);
+                (
+                    field(object, "line"),
+                    field(object, "commit"),
+                    field(object, "orig_line"),
+                    field(object, "author"),
+                )
+            })
+            .collect();
+        assert_eq!(rows.collect::<Vec<_>>(), vec!["]"]);
+        let quoted = |sha: &Oid| format!("\"{}\"", sha);
+        let row = |line: &str, sha, orig: &str| {
+            (
+                line.to_owned(),
+                quoted(sha),
+                orig.to_owned(),
+                "\"A U Thor\"".to_owned(),
+            )
+        };
+        assert_eq!(
+            parsed,
+            vec![
+                row("1", &first, "1"),
+                row("2", &second, "2"),
+                row("3", &first, "3"),
+                row("4", &second, "4"),
+            ]
+        );
+        assert!(out.contains(r#""content": "\"2\"""#), "{}", out);
+    }
+
+    #[test]
+    fn dump_index_shows_a_crafted_index() {
+        let entry = |path: &str, mode, flags, extended_flags, sha: &str| index::IndexEntry {
+            ctime: index::IndexTime {
+                seconds: 1_600_000_000,
+                nanoseconds: 5,
+            },
+            mtime: index::IndexTime {
+                seconds: 1_600_000_100,
+                nanoseconds: 6,
+            },
+            dev: 7,
+            ino: 8,
+            mode,
+            uid: 1000,
+            gid: 100,
+            size: 42,
+            sha: Oid::from_hex(sha).unwrap(),
+            flags,
+            extended_flags,
+            path: path.to_owned(),
+        };
+        let crafted = index::Index {
+            version: 3,
+            entries: vec![
+                entry("a.txt", 0o100644, 0x8000, 0, &"a".repeat(40)),
+                entry("dir/b.sh", 0o100755, 0x2000, 0x2000, &"b".repeat(40)),
+            ],
+            extensions: vec![index::IndexExtension {
+                signature: "TREE".to_owned(),
+                data: vec![0; 4],
+            }],
+            checksum: String::new(),
+        };
+        let data = index::index_serialize(&crafted);
+        let parsed = index::index_parse(&data).unwrap();
+
+        let mut out = vec![];
+        dump_index(&parsed, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "version 3
+entries 2
+
+path a.txt
+  ctime 1600000000:5
+  mtime 1600000100:6
+  dev 7  ino 8
+  mode 100644
+  uid 1000  gid 100
+  size 42
+  sha {}
+  flags 0x8005  extended 0x0000
+  stage 0
+  assume-valid
+
+path dir/b.sh
+  ctime 1600000000:5
+  mtime 1600000100:6
+  dev 7  ino 8
+  mode 100755
+  uid 1000  gid 100
+  size 42
+  sha {}
+  flags 0x6008  extended 0x2000
+  stage 2
+  intent-to-add
+
+extension TREE (4 bytes)
+
+checksum {}
+",
+                "a".repeat(40),
+                "b".repeat(40),
+                Oid::from_bytes(&data[data.len() - 20..]).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn hash_object_writes_to_git_object_directory() {
+        let t = TempRepo::new();
+        t.write("file.txt", "hello\n");
+        let sha = object::object_sha(ObjectType::Blob, b"hello\n").to_hex();
+
+        std::env::set_var("GIT_OBJECT_DIRECTORY", "elsewhere");
+        let result = cmd_hash_object(&args(&["-w", "file.txt"]));
+        std::env::remove_var("GIT_OBJECT_DIRECTORY");
+        result.unwrap();
+
+        let path = |dir: &str| t.path().join(dir).join(&sha[..2]).join(&sha[2..]);
+        assert!(path("elsewhere").is_file());
+        assert!(!path(".git/objects").exists());
+    }
+
+    #[test]
+    fn checkout_streams_a_large_blob() {
+        let t = TempRepo::new();
+        let data: Vec<u8> = (0..16u32 << 20).map(|i| (i % 251) as u8).collect();
+        let sha = object::object_write_raw(Some(&t.repo()), ObjectType::Blob, &data).unwrap();
+
+        let repo = t.repo();
+        let mut out = Recorder::default();
+        assert_eq!(
+            object::blob_copy_to(&repo, &sha, &mut out).unwrap(),
+            data.len() as u64
+        );
+        assert!(out.data == data);
+        assert!(out.largest <= 64 << 10, "{} byte write", out.largest);
+
+        let dest = t.path().join("big.bin");
+        let plan = [CheckoutAction::CreateFile {
+            path: dest.clone(),
+            mode: MODE_FILE,
+            sha,
+        }];
+        checkout_apply(&repo, &plan).unwrap();
+        assert!(fs::read(&dest).unwrap() == data);
+        // the blob was never read whole through the object store
+        assert_eq!(repo.object_cache().store_reads(), 0);
+    }
+
+    #[test]
+    fn cat_file_batch_frames_each_object() {
+        let t = TempRepo::new();
+        let big: Vec<u8> = (0..12u32 << 20).map(|i| (i % 241) as u8).collect();
+        let repo = t.repo();
+        let big_sha = object::object_write_raw(Some(&repo), ObjectType::Blob, &big).unwrap();
+        let small_sha = t.blob("small\n");
+
+        let input = format!("{}\nno-such-object\n{}\n", big_sha, small_sha);
+        let mut out = Recorder::default();
+        cat_file_batch(&t.repo(), &mut input.as_bytes(), &mut out).unwrap();
+        assert!(out.largest <= 64 << 10, "{} byte write", out.largest);
+
+        let mut expected = format!("{} blob {}\n", big_sha, big.len()).into_bytes();
+        expected.extend_from_slice(&big);
+        let rest = format!("\nno-such-object missing\n{} blob 6\nsmall\n\n", small_sha);
+        expected.extend_from_slice(rest.as_bytes());
+        assert!(out.data == expected);
+    }
+
+    #[test]
+    fn conflicts_lists_the_stages_of_a_conflicted_path() {
+        let t = TempRepo::new();
+        let conflict = conflicted_merge(&t);
+        let mut out = vec![];
+        conflicts_list(&index::index_read(&t.repo()).unwrap().unwrap(), &mut out).unwrap();
+        let expected = format!(
+            "f.txt\n  base   100644 {}\n  ours   100644 {}\n  theirs 100644 {}\n",
+            conflict.base, conflict.ours, conflict.theirs
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn add_resolves_a_conflict_for_commit() {
+        let t = TempRepo::new();
+        conflicted_merge(&t);
+        let ours = t.head();
+        let err = cmd_commit(&args(&["-m", "merge"])).unwrap_err();
+        assert!(err.contains("unmerged files: f.txt"), "{}", err);
+        assert_eq!(t.head(), ours);
+
+        t.write("f.txt", "resolved\n");
+        cmd_add(&args(&["f.txt"])).unwrap();
+        let index = index::index_read(&t.repo()).unwrap().unwrap();
+        let staged: Vec<(&str, u8, Oid)> = index
+            .entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.stage(), e.sha))
+            .collect();
+        let resolved = t.blob("resolved\n");
+        assert_eq!(
+            staged,
+            [("f.txt", 0, resolved), ("g.txt", 0, t.blob("added\n"))]
+        );
+
+        cmd_commit(&args(&["-m", "merge"])).unwrap();
+        let repo = t.repo();
+        let tree = object::commit_read(&repo, &t.head())
+            .unwrap()
+            .tree()
+            .unwrap();
+        let sha = object::object_find(&repo, &format!("{}:f.txt", tree), None, false).unwrap();
+        assert_eq!(sha, resolved);
+    }
+
+    #[test]
+    fn commit_completes_a_merge_from_its_state_files() {
+        let t = TempRepo::new();
+        conflicted_merge(&t);
+        let repo = t.repo();
+        let ours = t.head();
+        let theirs = object::object_find(&repo, "theirs", None, false).unwrap();
+        t.write("f.txt", "resolved\n");
+        cmd_add(&args(&["f.txt"])).unwrap();
+        cmd_commit(&args(&[])).unwrap();
+
+        let merge = object::commit_read(&repo, &t.head()).unwrap();
+        assert_eq!(merge.parents(), [ours, theirs]);
+        assert_eq!(
+            merge.message(),
+            "Merge branch 'theirs' into ours\n\n# Conflicts:\n#\tf.txt\n"
+        );
+        for name in [MERGE_HEAD, MERGE_MSG] {
+            assert_eq!(state_read(&repo, name).unwrap(), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn hash_object_hashes_each_path_from_stdin() {
+        let t = TempRepo::new();
+        let paths = ["one.txt", "dir/two.txt", "three.bin"];
+        for (i, path) in paths.iter().enumerate() {
+            t.write(path, &format!("contents {}\n", i).repeat(i + 1));
+        }
+        let mut singly = vec![];
+        for path in paths {
+            hash_object(&args(&[path]), &mut &b""[..], &mut singly).unwrap();
+        }
+
+        let input = paths.join("\n") + "\n";
+        let mut out = vec![];
+        hash_object(
+            &args(&["-w", "--stdin-paths"]),
+            &mut input.as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(singly).unwrap()
+        );
+        let repo = t.repo();
+        for path in paths {
+            let sha = object::object_sha(ObjectType::Blob, t.read(path).as_bytes());
+            assert!(object::object_exists(&repo, &sha), "{}", path);
+        }
+    }
+
+    #[test]
+    fn status_in_a_bare_repository_needs_a_work_tree() {
+        let t = TempRepo::new();
+        let bare = t.sibling("bare.git");
+        repository::repo_create_bare(&bare).unwrap();
+        std::env::set_current_dir(&bare).unwrap();
+        assert!(repository::repo_find::<&str>(None, false)
+            .unwrap()
+            .is_bare());
+        for err in [
+            cmd_status(&args(&[])).unwrap_err(),
+            cmd_add(&args(&["."])).unwrap_err(),
+        ] {
+            assert_eq!(err, "this operation must be run in a work tree");
+        }
+    }
+
+    #[test]
+    fn rebase_drops_a_commit_that_becomes_empty_unless_kept() {
+        let t = TempRepo::new();
+        t.write("f.txt", "base\n");
+        let base = t.commit("base");
+        cmd_checkout(&args(&["-b", "upstream"])).unwrap();
+        t.write("g.txt", "picked\n");
+        let upstream = t.commit("upstream has g");
+        cmd_checkout(&args(&["-b", "topic", &base.to_hex()])).unwrap();
+        t.write("g.txt", "picked\n");
+        t.commit("topic has g too");
+        t.write("h.txt", "topic\n");
+        let topic = t.commit("topic has h");
+
+        let repo = t.repo();
+        let history = |sha: Oid| {
+            let mut out = vec![];
+            let mut sha = Some(sha);
+            while let Some(commit) = sha.filter(|sha| *sha != upstream) {
+                let commit = object::commit_read(&repo, &commit).unwrap();
+                out.push(commit.message().trim_end().to_owned());
+                sha = commit.parents().first().copied();
+            }
+            out
+        };
+        cmd_rebase(&args(&["upstream"])).unwrap();
+        assert_eq!(history(t.head()), ["topic has h"]);
+        assert_eq!(t.read("g.txt"), "picked\n");
+
+        cmd_checkout(&args(&["-b", "kept", &topic.to_hex()])).unwrap();
+        cmd_rebase(&args(&["--keep-empty", "upstream"])).unwrap();
+        assert_eq!(history(t.head()), ["topic has h", "topic has g too"]);
+        let tree = |sha: &Oid| object::commit_read(&repo, sha).unwrap().tree();
+        let emptied = object::commit_read(&repo, &t.head()).unwrap().parents()[0];
+        assert_eq!(tree(&emptied), tree(&upstream));
+    }
+
+    #[test]
+    fn a_stale_index_lock_blocks_add() {
+        let t = TempRepo::new();
+        t.write("f.txt", "one\n");
+        t.commit("one");
+        let repo = t.repo();
+        let index_path = repo.gitdir().join("index");
+        let before = fs::read(&index_path).unwrap();
+        let lock = repo.gitdir().join("index.lock");
+        fs::write(&lock, "").unwrap();
+
+        t.write("f.txt", "two\n");
+        let err = cmd_add(&args(&["f.txt"])).unwrap_err();
+        assert!(err.contains("index.lock"), "{}", err);
+        assert!(err.contains("Another wyag process"), "{}", err);
+        // the lock is someone else's to remove, and the index is untouched
+        assert!(lock.exists());
+        assert!(fs::read(&index_path).unwrap() == before);
+
+        fs::remove_file(&lock).unwrap();
+        cmd_add(&args(&["f.txt"])).unwrap();
+        assert!(!lock.exists());
+        assert!(fs::read(&index_path).unwrap() != before);
+    }
+
+    #[test]
+    fn show_index_lists_entries_by_offset() {
+        let sha = |digit: &str| Oid::from_hex(&digit.repeat(40)).unwrap();
+        // each sha with its CRC
+        let entries = [
+            (sha("1"), 0xdead_beefu32),
+            (sha("2"), 0x0123_4567),
+            (sha("3"), 0x89ab_cdef),
+        ];
+        let fanout: Vec<u8> = (0..=255u8)
+            .flat_map(|first| {
+                let count = entries
+                    .iter()
+                    .filter(|e| e.0.as_bytes()[0] <= first)
+                    .count();
+                (count as u32).to_be_bytes()
+            })
+            .collect();
+        // the pack's checksum and the index's own, which show-index doesn't check
+        let checksums = [0xaa; 40];
+
+        // version 2, with the last offset, 1 << 32, in the table of large ones
+        let mut v2 = [&b"\xfftOc\0\0\0\x02"[..], &fanout].concat();
+        entries.iter().for_each(|e| v2.extend(e.0.as_bytes()));
+        entries.iter().for_each(|e| v2.extend(&e.1.to_be_bytes()));
+        for offset in [300, 12, 0x8000_0000u32] {
+            v2.extend(&offset.to_be_bytes());
+        }
+        v2.extend(&(1u64 << 32).to_be_bytes());
+        v2.extend(&checksums);
+        let mut out = vec![];
+        show_index(&v2, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "12 {} (01234567)\n300 {} (deadbeef)\n4294967296 {} (89abcdef)\n",
+                sha("2"),
+                sha("1"),
+                sha("3")
+            )
+        );
+
+        // version 1 has no magic number or CRCs, and an offset before each sha
+        let mut v1 = fanout.clone();
+        for (sha, offset) in entries.iter().map(|e| e.0).zip([300u32, 12, 150]) {
+            v1.extend(&offset.to_be_bytes());
+            v1.extend(sha.as_bytes());
+        }
+        v1.extend(&checksums);
+        let mut out = vec![];
+        show_index(&v1, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("12 {}\n150 {}\n300 {}\n", sha("2"), sha("3"), sha("1"))
+        );
+    }
+
+    #[test]
+    fn cat_file_prints_a_tag_verbatim_and_peel_follows_it() {
+        let t = TempRepo::new();
+        t.write("f.txt", "tagged\n");
+        let commit = t.commit("tagged");
+        let repo = t.repo();
+        let tree = object::commit_read(&repo, &commit).unwrap().tree().unwrap();
+        let tag_of = |object: &Oid, kind: &str, name: &str| {
+            let tag = format!(
+                "object {}\ntype {}\ntag {}\ntagger A U Thor <author@example.com> 1600000000 +0000\n\nRelease {}\n",
+                object, kind, name, name
+            );
+            let sha =
+                object::object_write_raw(Some(&repo), ObjectType::Tag, tag.as_bytes()).unwrap();
+            refs::ref_update(&repo, &format!("refs/tags/{}", name), &sha).unwrap();
+            (sha, tag)
+        };
+        let (v1, v1_text) = tag_of(&commit, "commit", "v1");
+        // a tag of a tag, peeled through both
+        let (signed, _) = tag_of(&v1, "tag", "v1-signed");
+
+        let mut out = vec![];
+        cat_file_pretty(&repo, "v1", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), v1_text);
+
+        let find = |name: &str| object::object_find(&repo, name, None, false);
+        assert_eq!(find("v1").unwrap(), v1);
+        for name in ["v1", "v1-signed"] {
+            assert_eq!(find(&format!("{}^{{}}", name)).unwrap(), commit);
+            assert_eq!(find(&format!("{}^{{commit}}", name)).unwrap(), commit);
+            assert_eq!(find(&format!("{}^{{tree}}", name)).unwrap(), tree);
+        }
+        assert_eq!(find("v1-signed^{tag}").unwrap(), signed);
+        assert_eq!(find("v1-signed^{object}").unwrap(), signed);
+        let err = find("v1^{blob}").unwrap_err();
+        assert!(err.contains("is not a blob"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_failing_pre_commit_hook_aborts_the_commit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let t = TempRepo::new();
+        t.write("f.txt", "one\n");
+        let first = t.commit("one");
+        let hook = t.repo().gitdir().join("hooks").join("pre-commit");
+        fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        fs::write(&hook, "#!/bin/sh\necho rejected >&2\nexit 3\n").unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+        t.write("f.txt", "two\n");
+        cmd_add(&args(&["f.txt"])).unwrap();
+        let err = cmd_commit(&args(&["-m", "two"])).unwrap_err();
+        assert_eq!(err, "The pre-commit hook failed with exit status 3");
+        assert_eq!(t.head(), first);
+
+        cmd_commit(&args(&["--no-verify", "-m", "two"])).unwrap();
+        let commit = object::commit_read(&t.repo(), &t.head()).unwrap();
+        assert_eq!(commit.parents(), [first]);
+    }
+
+    #[test]
+    fn rerere_replays_a_recorded_resolution() {
+        let t = TempRepo::new();
+        t.config("rerere.enabled", "true");
+        conflicted_merge(&t);
+        t.write("f.txt", "resolved\n");
+        cmd_add(&args(&["f.txt"])).unwrap();
+        // the resolution is kept though the merge isn't
+        cmd_merge(&args(&["--abort"])).unwrap();
+
+        // replaying theirs onto ours meets the same conflict
+        cmd_checkout(&args(&["-b", "replay", "theirs"])).unwrap();
+        t.config("rerere.enabled", "false");
+        let err = cmd_rebase(&args(&["ours"])).unwrap_err();
+        assert!(err.contains("both sides changed f.txt"), "{}", err);
+        t.config("rerere.enabled", "true");
+        cmd_rebase(&args(&["ours"])).unwrap();
+
+        assert_eq!(t.read("f.txt"), "resolved\n");
+        assert_eq!(t.read("g.txt"), "added\n");
+        let repo = t.repo();
+        let ours = object::object_find(&repo, "ours", None, false).unwrap();
+        let replayed = object::commit_read(&repo, &t.head()).unwrap();
+        assert_eq!(replayed.parents(), [ours]);
+        let sha = object::object_find(&repo, "HEAD:f.txt", None, false).unwrap();
+        assert_eq!(sha, t.blob("resolved\n"));
+    }
+
+    #[test]
+    fn status_branch_header_counts_ahead_and_behind() {
+        let t = TempRepo::new();
+        t.write("f.txt", "base\n");
+        let base = t.commit("base");
+        cmd_checkout(&args(&["-b", "elsewhere"])).unwrap();
+        t.write("upstream.txt", "upstream\n");
+        let upstream = t.commit("upstream");
+        cmd_checkout(&args(&["-b", "topic", &base.to_hex()])).unwrap();
+        for i in 0..2 {
+            t.write("f.txt", &format!("topic {}\n", i));
+            t.commit(&format!("topic {}", i));
+        }
+        let header = || {
+            let repo = t.repo();
+            status_branch_header(&repo, Some(&t.head())).unwrap()
+        };
+        assert_eq!(header(), "## topic");
+
+        t.config("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*");
+        t.config("branch.topic.remote", "origin");
+        t.config("branch.topic.merge", "refs/heads/topic");
+        assert_eq!(header(), "## topic...origin/topic [gone]");
+        let tracking = "refs/remotes/origin/topic";
+        refs::ref_update(&t.repo(), tracking, &t.head()).unwrap();
+        assert_eq!(header(), "## topic...origin/topic");
+        refs::ref_update(&t.repo(), tracking, &base).unwrap();
+        assert_eq!(header(), "## topic...origin/topic [ahead 2]");
+        refs::ref_update(&t.repo(), tracking, &upstream).unwrap();
+        assert_eq!(header(), "## topic...origin/topic [ahead 2, behind 1]");
+    }
+
+    #[test]
+    fn set_upstream_is_saved_and_used_by_status() {
+        let t = TempRepo::new();
+        t.write("f.txt", "base\n");
+        let base = t.commit("base");
+        t.config("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*");
+        refs::ref_update(&t.repo(), "refs/remotes/origin/main", &base).unwrap();
+        t.write("f.txt", "ahead\n");
+        t.commit("ahead");
+        let path = t.repo().gitdir().join("config");
+        let before = fs::read_to_string(&path).unwrap() + "# keep me\n";
+        fs::write(&path, &before).unwrap();
+
+        let err = cmd_branch(&args(&["--set-upstream-to", "origin/nope"])).unwrap_err();
+        assert!(err.contains("does not exist"), "{}", err);
+        cmd_branch(&args(&["--set-upstream-to", "origin/main"])).unwrap();
+        let repo = t.repo();
+        let config = |name: &str| repo.config_value(name);
+        assert_eq!(config("branch.master.remote").as_deref(), Some("origin"));
+        assert_eq!(
+            config("branch.master.merge").as_deref(),
+            Some("refs/heads/main")
+        );
+        assert_eq!(
+            transport::branch_upstream(&repo, "master")
+                .unwrap()
+                .as_deref(),
+            Some("refs/remotes/origin/main")
+        );
+        assert_eq!(
+            status_branch_long(&repo, Some(&t.head())).unwrap(),
+            "On branch master\nYour branch is ahead of 'origin/main' by 1 commit.\n\n"
+        );
+        let set = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            set,
+            before + "[branch \"master\"]\n\tremote = origin\n\tmerge = refs/heads/main\n"
+        );
+
+        // a branch started from a remote-tracking ref tracks it
+        cmd_checkout(&args(&["-b", "from-origin", "origin/main"])).unwrap();
+        let repo = t.repo();
+        assert_eq!(
+            transport::branch_upstream(&repo, "from-origin")
+                .unwrap()
+                .as_deref(),
+            Some("refs/remotes/origin/main")
+        );
+        assert_eq!(
+            status_branch_long(&repo, Some(&base)).unwrap(),
+            "On branch from-origin\nYour branch is up to date with 'origin/main'.\n\n"
+        );
+        assert!(fs::read_to_string(&path).unwrap().starts_with(&set));
+    }
+
+    #[test]
+    fn merge_abort_restores_the_pre_merge_tree() {
+        let t = TempRepo::new();
+        let err = cmd_merge(&args(&["--abort"])).unwrap_err();
+        assert!(err.contains("There is no merge to abort"), "{}", err);
+
+        conflicted_merge(&t);
+        let ours = t.head();
+        t.write(
+            "scratch.txt",
+            "never staged
+",
+        );
+        cmd_merge(&args(&["--abort"])).unwrap();
+
+        let repo = t.repo();
+        let index = index::index_read(&repo).unwrap().unwrap();
+        assert!(index.entries.iter().all(|e| e.stage() == 0));
+        assert_eq!(
+            Some(index::index_write_tree(&repo, &index).unwrap()),
+            object::commit_read(&repo, &ours).unwrap().tree()
+        );
+        assert_eq!(t.head(), ours);
+        assert_eq!(t.read("f.txt"), "ours\n");
+        assert!(!t.path().join("g.txt").exists());
+        assert_eq!(t.read("scratch.txt"), "never staged\n");
+        assert_eq!(state_read(&repo, MERGE_HEAD).unwrap(), None);
+        assert_eq!(state_read(&repo, MERGE_MSG).unwrap(), None);
+
+        let err = cmd_merge(&args(&["--abort"])).unwrap_err();
+        assert!(err.contains("There is no merge to abort"), "{}", err);
+    }
+
+    #[test]
+    fn commit_tree_takes_options_before_the_tree() {
+        let t = TempRepo::new();
+        t.write("f.txt", "base\n");
+        let base = t.commit("base");
+        t.write("f.txt", "side\n");
+        let side = t.commit("side");
+        let repo = t.repo();
+        let tree = object::commit_read(&repo, &side).unwrap().tree().unwrap();
+
+        let ct = args(&["-m", "root", &tree.to_hex()]);
+        let root = object::commit_read(&repo, &commit_tree(&repo, &ct).unwrap()).unwrap();
+        assert_eq!(root.tree(), Some(tree));
+        assert_eq!(root.parents(), vec![]);
+        assert_eq!(root.message(), "root\n");
+
+        let (base_hex, side_hex) = (base.to_hex(), side.to_hex());
+        let ct = args(&[
+            "-p",
+            &base_hex,
+            "-p",
+            &side_hex,
+            "-m",
+            "one",
+            "-m",
+            "two",
+            &tree.to_hex(),
+        ]);
+        let merge = object::commit_read(&repo, &commit_tree(&repo, &ct).unwrap()).unwrap();
+        assert_eq!(merge.tree(), Some(tree));
+        assert_eq!(merge.parents(), vec![base, side]);
+        assert_eq!(merge.message(), "one\n\ntwo\n");
+    }
+}
@@ -0,0 +1,178 @@
+use crate::object::Oid;
+use crate::repository::Repo;
+use crypto::{digest::Digest, sha1::Sha1};
+use std::fs;
+
+/// `CGPH`, the signature starting a commit-graph file
+const GRAPH_SIGNATURE: &[u8] = b"CGPH";
+
+// ids of the chunks read
+const CHUNK_OID_FANOUT: &[u8] = b"OIDF";
+const CHUNK_OID_LOOKUP: &[u8] = b"OIDL";
+const CHUNK_COMMIT_DATA: &[u8] = b"CDAT";
+const CHUNK_EXTRA_EDGES: &[u8] = b"EDGE";
+
+/// A parent position meaning the commit has no parent in that slot
+const PARENT_NONE: u32 = 0x7000_0000;
+/// Set in a second parent position that is an index into the extra edges of an octopus merge
+/// rather than a commit, and on the last of those edges
+const PARENT_EXTRA: u32 = 0x8000_0000;
+
+/// The generation of a commit the graph doesn't have. Such a commit is newer than the graph,
+/// so it can only be reached from other commits the graph doesn't have.
+pub const GENERATION_INFINITY: u32 = u32::MAX;
+
+/// What the commit-graph records about a commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphCommit {
+    pub tree: Oid,
+    pub parents: Vec<Oid>,
+    /// The committer timestamp, in seconds since the epoch
+    pub time: i64,
+    /// The commit's topological level: 1 for a root commit, otherwise one more than the
+    /// highest level of its parents
+    pub generation: u32,
+}
+
+/// The contents of `objects/info/commit-graph`: the shas of the commits it covers, sorted,
+/// and for each its tree, parents, commit time and generation number, so a walk can skip
+/// reading the commit objects.
+#[derive(Debug, Clone)]
+pub struct CommitGraph {
+    pub shas: Vec<Oid>,
+    pub commits: Vec<GraphCommit>,
+}
+
+fn be_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "Commit-graph is truncated".to_owned())
+}
+
+fn be_u64(data: &[u8], pos: usize) -> Result<u64, String> {
+    Ok((be_u32(data, pos)? as u64) << 32 | be_u32(data, pos + 4)? as u64)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl CommitGraph {
+    /// Parses a version 1 commit-graph file using SHA-1. Files that are part of a split
+    /// commit-graph chain are not supported.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 28 || !data.starts_with(GRAPH_SIGNATURE) {
+            return Err("Not a commit-graph file".to_owned());
+        }
+        match (data[4], data[5]) {
+            (1, 1) => (),
+            (1, hash) => return Err(format!("Unsupported commit-graph hash version {}", hash)),
+            (v, _) => return Err(format!("Unsupported commit-graph version {}", v)),
+        }
+        if data[7] != 0 {
+            return Err("Commit-graph chains are not supported".to_owned());
+        }
+        let (body, trailer) = data.split_at(data.len() - 20);
+        let mut sha = Sha1::new();
+        sha.input(body);
+        if sha.result_str() != hex(trailer) {
+            return Err("Commit-graph checksum mismatch".to_owned());
+        }
+
+        // the chunk table: an id and offset per chunk, ended by an entry with id 0
+        let chunk = |id: &[u8]| -> Result<Option<usize>, String> {
+            for i in 0..data[6] as usize {
+                let entry = 8 + i * 12;
+                if data.get(entry..entry + 4) == Some(id) {
+                    return Ok(Some(be_u64(data, entry + 4)? as usize));
+                }
+            }
+            Ok(None)
+        };
+        let required = |id: &[u8]| -> Result<usize, String> {
+            chunk(id)?
+                .ok_or_else(|| format!("Commit-graph has no {} chunk", String::from_utf8_lossy(id)))
+        };
+        let fanout = required(CHUNK_OID_FANOUT)?;
+        let lookup = required(CHUNK_OID_LOOKUP)?;
+        let commit_data = required(CHUNK_COMMIT_DATA)?;
+        let extra_edges = chunk(CHUNK_EXTRA_EDGES)?;
+        let count = be_u32(data, fanout + 255 * 4)? as usize;
+
+        let mut shas = Vec::with_capacity(count);
+        for i in 0..count {
+            let sha = data
+                .get(lookup + i * 20..lookup + i * 20 + 20)
+                .ok_or("Commit-graph is truncated")?;
+            shas.push(Oid::from_bytes(sha)?);
+        }
+        let position = |pos: u32| -> Result<Oid, String> {
+            shas.get(pos as usize)
+                .copied()
+                .ok_or_else(|| format!("Commit-graph parent position {} is out of range", pos))
+        };
+
+        let mut commits = Vec::with_capacity(count);
+        for i in 0..count {
+            // the tree, two parent positions, then the generation and time packed together
+            let entry = commit_data + i * 36;
+            let tree = data
+                .get(entry..entry + 20)
+                .ok_or("Commit-graph is truncated")?;
+            let mut parents = vec![];
+            let first = be_u32(data, entry + 20)?;
+            if first != PARENT_NONE {
+                parents.push(position(first)?);
+            }
+            let second = be_u32(data, entry + 24)?;
+            if second & PARENT_EXTRA != 0 {
+                let edges = extra_edges.ok_or("Commit-graph has no EDGE chunk")?;
+                let mut edge = (second & !PARENT_EXTRA) as usize;
+                loop {
+                    let pos = be_u32(data, edges + edge * 4)?;
+                    parents.push(position(pos & !PARENT_EXTRA)?);
+                    if pos & PARENT_EXTRA != 0 {
+                        break;
+                    }
+                    edge += 1;
+                }
+            } else if second != PARENT_NONE {
+                parents.push(position(second)?);
+            }
+            let packed = be_u64(data, entry + 28)?;
+            commits.push(GraphCommit {
+                tree: Oid::from_bytes(tree)?,
+                parents,
+                time: (packed & 0x3_ffff_ffff) as i64,
+                generation: (packed >> 34) as u32,
+            });
+        }
+
+        Ok(Self { shas, commits })
+    }
+
+    /// Reads the repository's commit-graph. Returns `None` if there isn't one, or if it
+    /// can't be used, in which case callers read commit objects as they would without it.
+    pub fn load(repo: &Repo) -> Option<Self> {
+        let path = repo.object_dir().join("info").join("commit-graph");
+        let data = fs::read(&path).ok()?;
+        match Self::parse(&data) {
+            Ok(graph) => Some(graph),
+            Err(e) => {
+                warn!("Ignoring commit-graph {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// What the graph records about `sha`, if it covers that commit.
+    pub fn find(&self, sha: &Oid) -> Option<&GraphCommit> {
+        self.shas.binary_search(sha).ok().map(|i| &self.commits[i])
+    }
+
+    /// The generation of `sha`, or [`GENERATION_INFINITY`] if the graph doesn't cover it.
+    pub fn generation(&self, sha: &Oid) -> u32 {
+        self.find(sha)
+            .map_or(GENERATION_INFINITY, |commit| commit.generation)
+    }
+}
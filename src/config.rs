@@ -0,0 +1,533 @@
+use crate::attributes;
+use crate::lockfile::LockFile;
+use ini::Ini;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Location of the user's global config file, `~/.gitconfig`.
+pub fn config_global_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".gitconfig"))
+}
+
+/// Loads the global config file, returning an empty config if it does not exist.
+/// `gitdir` is used to evaluate `includeIf` conditions.
+pub fn config_global(gitdir: Option<&Path>) -> Result<Ini, String> {
+    match config_global_path() {
+        Some(path) if path.exists() => config_load(&path, gitdir),
+        _ => Ok(Ini::new()),
+    }
+}
+
+/// Reads a single config file as written, without resolving includes.
+pub fn config_read_file(path: &Path) -> Result<Ini, String> {
+    Ini::load_from_file(path).map_err(|e| format!("Could not read config file {:?}: {}", path, e))
+}
+
+/// Loads the config file at `path` and resolves its `[include]` and
+/// `[includeIf "gitdir:..."]` sections. As in git, an include's values override those set
+/// before it in the including file, but not those set after it. `gitdir` is the repository
+/// the config is being loaded for, if any.
+pub fn config_load(path: &Path, gitdir: Option<&Path>) -> Result<Ini, String> {
+    let mut stack = vec![];
+    config_load_inner(path, gitdir, &mut stack)
+}
+
+fn config_load_inner(
+    path: &Path,
+    gitdir: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Ini, String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format!("Could not read config file {:?}: {:?}", path, e))?;
+    if stack.contains(&canonical) {
+        return Err(format!("Config include cycle detected at {:?}", path));
+    }
+    let mut ini = config_read_file(path)?;
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read config file {:?}: {:?}", path, e))?;
+    let lines = config_lines(&text);
+    let include_dir = canonical
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    let mut includes = vec![];
+    for (section, props) in ini.iter() {
+        let dotted = match section {
+            Some(s) => section_to_dotted(s),
+            None => continue,
+        };
+        let wanted = if dotted == "include" {
+            true
+        } else if let Some(condition) = dotted.strip_prefix("includeif.") {
+            include_condition_matches(condition, &include_dir, gitdir)
+        } else {
+            false
+        };
+        if !wanted {
+            continue;
+        }
+        if let Some((_, include)) = props.iter().find(|(k, _)| k.eq_ignore_ascii_case("path")) {
+            let line = ConfigLine::Key(dotted, "path".to_owned());
+            let at = lines.iter().rposition(|(_, kind)| *kind == line);
+            includes.push((at.unwrap_or(0), expand_path(include, &include_dir)));
+        }
+    }
+    includes.sort();
+
+    stack.push(canonical);
+    for (at, include) in includes {
+        // like git, missing include files are ignored
+        if !include.exists() {
+            continue;
+        }
+        let set_after: Vec<(String, String)> = lines[at..]
+            .iter()
+            .filter_map(|(_, kind)| match kind {
+                ConfigLine::Key(section, key) => Some((section.clone(), key.clone())),
+                _ => None,
+            })
+            .collect();
+        let included = config_load_inner(&include, gitdir, stack)?;
+        for (section, props) in included.iter() {
+            let dotted = section.as_ref().map(|s| section_to_dotted(s));
+            for (k, v) in props.iter() {
+                let later = dotted
+                    .as_ref()
+                    .is_some_and(|dotted| set_after.contains(&(dotted.clone(), k.to_lowercase())));
+                if !later {
+                    ini.with_section(section.clone())
+                        .set(k.as_str(), v.as_str());
+                }
+            }
+        }
+    }
+    stack.pop();
+
+    Ok(ini)
+}
+
+/// Expands a leading `~/` and makes relative paths relative to `base`.
+fn expand_path(path: &str, base: &Path) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = std::env::var_os("HOME") {
+            return PathBuf::from(home).join(rest);
+        }
+    }
+    base.join(path)
+}
+
+/// Evaluates the condition of an `includeIf` section. Only `gitdir:` and `gitdir/i:` are
+/// supported; any other condition never matches.
+fn include_condition_matches(condition: &str, include_dir: &Path, gitdir: Option<&Path>) -> bool {
+    let (pattern, icase) = if let Some(p) = condition.strip_prefix("gitdir:") {
+        (p, false)
+    } else if let Some(p) = condition.strip_prefix("gitdir/i:") {
+        (p, true)
+    } else {
+        return false;
+    };
+    let gitdir = match gitdir {
+        Some(g) => g.to_string_lossy().into_owned(),
+        None => return false,
+    };
+
+    let mut pattern = if let Some(rest) = pattern.strip_prefix("./") {
+        include_dir.join(rest).to_string_lossy().into_owned()
+    } else if pattern.starts_with("~/") || pattern.starts_with('/') {
+        expand_path(pattern, include_dir)
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        format!("**/{}", pattern)
+    };
+    if pattern.ends_with('/') {
+        pattern += "**";
+    }
+
+    let re = format!(
+        "{}^{}$",
+        if icase { "(?i)" } else { "" },
+        attributes::glob_regex(&pattern)
+    );
+    regex::Regex::new(&re)
+        .map(|re| re.is_match(&gitdir))
+        .unwrap_or(false)
+}
+
+/// Converts an ini section header such as `remote "origin"` into its dotted form,
+/// `remote.origin`. Section names are case insensitive and are lowercased; subsection
+/// names are kept as written.
+fn section_to_dotted(section: &str) -> String {
+    match section.find(char::is_whitespace) {
+        Some(idx) => {
+            let subsection = section[idx..].trim().trim_matches('"');
+            format!("{}.{}", section[..idx].to_lowercase(), subsection)
+        }
+        None => section.to_lowercase(),
+    }
+}
+
+/// Flattens every key in `ini` into `(section.key, value)` pairs, e.g.
+/// `("remote.origin.url", "...")`, sorted by name.
+pub fn config_flatten(ini: &Ini) -> Vec<(String, String)> {
+    let mut out = vec![];
+    for (section, props) in ini.iter() {
+        let prefix = section.as_ref().map(|s| section_to_dotted(s));
+        for (key, value) in props.iter() {
+            let name = match &prefix {
+                Some(prefix) => format!("{}.{}", prefix, key.to_lowercase()),
+                None => key.to_lowercase(),
+            };
+            out.push((name, value.clone()));
+        }
+    }
+    out.sort();
+    out
+}
+
+/// Normalizes a user supplied dotted section name, lowercasing the section but not the
+/// subsection.
+fn normalize_dotted(name: &str) -> String {
+    match name.find('.') {
+        Some(idx) => format!("{}{}", name[..idx].to_lowercase(), &name[idx..]),
+        None => name.to_lowercase(),
+    }
+}
+
+/// Finds the ini section header for a dotted section name such as `remote.origin`.
+fn find_section(ini: &Ini, dotted: &str) -> Option<String> {
+    let dotted = normalize_dotted(dotted);
+    ini.sections()
+        .filter_map(|s| s.as_ref())
+        .find(|s| section_to_dotted(s) == dotted)
+        .cloned()
+}
+
+/// Splits `section.key` into the ini section header holding it and the key as written.
+fn find_key(ini: &Ini, name: &str) -> Option<(String, String)> {
+    let idx = name.rfind('.')?;
+    let (section, key) = (&name[..idx], &name[idx + 1..]);
+    let section = find_section(ini, section)?;
+    let key = ini
+        .section(Some(section.as_str()))
+        .and_then(|props| props.keys().find(|k| k.eq_ignore_ascii_case(key)).cloned())?;
+    Some((section, key))
+}
+
+/// Looks up the value of `section.key`, e.g. `remote.origin.url`.
+pub fn config_get<'a>(ini: &'a Ini, name: &str) -> Option<&'a str> {
+    let (section, key) = find_key(ini, name)?;
+    ini.get_from(Some(section), &key)
+}
+
+/// Sets `section.key` to `value`, creating the section if needed. A dotted section such as
+/// `remote.origin` is written as `[remote "origin"]`.
+pub fn config_set(ini: &mut Ini, name: &str, value: &str) -> Result<(), String> {
+    let idx = name
+        .rfind('.')
+        .ok_or_else(|| format!("Key does not contain a section: {}", name))?;
+    let (dotted, key) = (&name[..idx], &name[idx + 1..]);
+    if let Some((section, key)) = find_key(ini, name) {
+        ini.with_section(Some(section)).set(key, value);
+        return Ok(());
+    }
+    let section = find_section(ini, dotted).unwrap_or_else(|| match dotted.find('.') {
+        Some(idx) => format!("{} \"{}\"", &dotted[..idx], &dotted[idx + 1..]),
+        None => dotted.to_owned(),
+    });
+    ini.with_section(Some(section)).set(key, value);
+    Ok(())
+}
+
+/// What a line of a config file holds, for the editors below that change some lines and
+/// write every other one back as it was.
+#[derive(Debug, PartialEq)]
+enum ConfigLine {
+    /// A `[section]` header, by its dotted name.
+    Section(String),
+    /// A `key = value` line, by its dotted section and lowercased key.
+    Key(String, String),
+    /// A line continuing the value of the key above it, which ended with a `\`.
+    Continuation,
+    /// A blank line or a comment.
+    Other,
+}
+
+/// Splits `text` into its lines, each with its line ending, and what each holds.
+fn config_lines(text: &str) -> Vec<(&str, ConfigLine)> {
+    let mut section = None;
+    let mut continued = false;
+    let mut out = vec![];
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let kind = if continued {
+            ConfigLine::Continuation
+        } else if trimmed.starts_with('[') {
+            let dotted = section_to_dotted(header_name(trimmed));
+            section = Some(dotted.clone());
+            ConfigLine::Section(dotted)
+        } else if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            ConfigLine::Other
+        } else if let Some(section) = &section {
+            let mut key = trimmed.split(|c: char| c == '=' || c.is_whitespace());
+            ConfigLine::Key(section.clone(), key.next().unwrap_or("").to_lowercase())
+        } else {
+            ConfigLine::Other
+        };
+        continued = matches!(kind, ConfigLine::Key(..) | ConfigLine::Continuation)
+            && trimmed.ends_with('\\');
+        out.push((line, kind));
+    }
+    out
+}
+
+/// The name inside a trimmed `[section "subsection"]` header, skipping any `]` in the quoted
+/// subsection and anything after the closing bracket.
+fn header_name(header: &str) -> &str {
+    let body = &header[1..];
+    let from = body
+        .find('"')
+        .and_then(|open| body[open + 1..].find('"').map(|close| open + close + 2))
+        .unwrap_or(0);
+    match body[from..].find(']') {
+        Some(end) => &body[..from + end],
+        None => body,
+    }
+}
+
+/// Splits a user supplied `section.key` into the dotted section, normalized, and the key
+/// lowercased, the forms [`config_lines`] gives them in.
+fn split_name(name: &str) -> Result<(String, String), String> {
+    let idx = name
+        .rfind('.')
+        .ok_or_else(|| format!("Key does not contain a section: {}", name))?;
+    Ok((
+        normalize_dotted(&name[..idx]),
+        name[idx + 1..].to_lowercase(),
+    ))
+}
+
+/// Quotes `value` if the ini reader would otherwise take part of it as a comment, an escape
+/// or surrounding whitespace.
+fn quote_value(value: &str) -> String {
+    let plain = !value.contains(['#', ';', '"', '\\', '\n']) && value.trim() == value;
+    if plain {
+        return value.to_owned();
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// Rewrites the config file at `path` while holding its lock. `edit` is given the file's
+/// text, or an empty string if there is no file yet, and the file is only written if it
+/// succeeds.
+pub fn config_file_edit<T>(
+    path: &Path,
+    edit: impl FnOnce(&mut String) -> Result<T, String>,
+) -> Result<T, String> {
+    let lock = LockFile::acquire(path)?;
+    let mut text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Could not read config file {:?}: {:?}", path, e)),
+    };
+    let out = edit(&mut text)?;
+    lock.commit(text.as_bytes())?;
+    Ok(out)
+}
+
+/// Sets `section.key` to `value` in the config file `text`. The last line setting the key
+/// is replaced, or a line is added at the end of the last section of that name, or the
+/// section is added at the end of the file. Every other line is left as written.
+pub fn config_edit_set(text: &mut String, name: &str, value: &str) -> Result<(), String> {
+    let (section, key) = split_name(name)?;
+    let written_key = &name[name.rfind('.').unwrap_or(0) + 1..];
+    let lines = config_lines(text);
+    let is_key = |kind: &ConfigLine| *kind == ConfigLine::Key(section.clone(), key.clone());
+
+    let mut out = String::new();
+    if let Some(at) = lines.iter().rposition(|(_, kind)| is_key(kind)) {
+        let line = lines[at].0;
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let key_len = line
+            .trim_start()
+            .find(|c: char| c == '=' || c.is_whitespace());
+        let key = &line.trim_start()[..key_len.unwrap_or(line.trim_start().len())];
+        let rest = lines[at + 1..]
+            .iter()
+            .skip_while(|(_, kind)| *kind == ConfigLine::Continuation);
+        out.extend(lines[..at].iter().map(|(line, _)| *line));
+        out += &format!("{}{} = {}\n", indent, key, quote_value(value));
+        out.extend(rest.map(|(line, _)| *line));
+    } else if let Some(header) = lines
+        .iter()
+        .rposition(|(_, kind)| *kind == ConfigLine::Section(section.clone()))
+    {
+        // after the section's last key, so comments and blank lines before the next header
+        // stay where they are
+        let end = lines[header + 1..]
+            .iter()
+            .position(|(_, kind)| matches!(kind, ConfigLine::Section(_)))
+            .map_or(lines.len(), |next| header + 1 + next);
+        let at = lines[header + 1..end]
+            .iter()
+            .rposition(|(_, kind)| *kind != ConfigLine::Other)
+            .map_or(header + 1, |last| header + 2 + last);
+        out.extend(lines[..at].iter().map(|(line, _)| *line));
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out += &format!("\t{} = {}\n", written_key, quote_value(value));
+        out.extend(lines[at..].iter().map(|(line, _)| *line));
+    } else {
+        out = text.clone();
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        let dotted = &name[..name.rfind('.').unwrap_or(0)];
+        let header = match dotted.find('.') {
+            Some(idx) => format!("{} \"{}\"", &dotted[..idx], &dotted[idx + 1..]),
+            None => dotted.to_owned(),
+        };
+        out += &format!("[{}]\n\t{} = {}\n", header, written_key, quote_value(value));
+    }
+    *text = out;
+    Ok(())
+}
+
+/// Removes the line setting `section.key`, and any lines continuing its value, from the
+/// config file `text`. Returns `false` if the key is not set, and fails if it is set more
+/// than once.
+pub fn config_edit_unset(text: &mut String, name: &str) -> Result<bool, String> {
+    let (section, key) = split_name(name)?;
+    let lines = config_lines(text);
+    let is_key = |kind: &ConfigLine| *kind == ConfigLine::Key(section.clone(), key.clone());
+    match lines.iter().filter(|(_, kind)| is_key(kind)).count() {
+        0 => return Ok(false),
+        1 => {}
+        _ => return Err(format!("{} has multiple values", name)),
+    }
+
+    let mut removing = false;
+    let mut out = String::new();
+    for (line, kind) in &lines {
+        removing = is_key(kind) || (removing && *kind == ConfigLine::Continuation);
+        if !removing {
+            out += line;
+        }
+    }
+    *text = out;
+    Ok(true)
+}
+
+/// Removes every section named `section` (e.g. `remote.origin`) from the config file `text`,
+/// each header along with the lines up to the next one. Returns `false` if there was no such
+/// section.
+pub fn config_edit_remove_section(text: &mut String, section: &str) -> bool {
+    let section = normalize_dotted(section);
+    let mut found = false;
+    let mut removing = false;
+    let mut out = String::new();
+    for (line, kind) in config_lines(text) {
+        if let ConfigLine::Section(name) = &kind {
+            removing = *name == section;
+            found |= removing;
+        }
+        if !removing {
+            out += line;
+        }
+    }
+    *text = out;
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempRepo;
+
+    #[test]
+    fn flatten_names_subsections_as_written() {
+        let ini = Ini::load_from_str(
+            "[core]\n\tBare = false\n\
+             [remote \"Origin\"]\n\tURL = /srv/repo.git\n\tfetch = +refs/heads/*:refs/remotes/Origin/*\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config_flatten(&ini),
+            vec![
+                ("core.bare".to_owned(), "false".to_owned()),
+                (
+                    "remote.Origin.fetch".to_owned(),
+                    "+refs/heads/*:refs/remotes/Origin/*".to_owned()
+                ),
+                ("remote.Origin.url".to_owned(), "/srv/repo.git".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn include_if_gitdir_supplies_user_email() {
+        let t = TempRepo::new();
+        let gitdir = t.repo().gitdir().to_owned();
+        std::fs::write(
+            gitdir.join("identity.inc"),
+            "[user]\nemail = included@example.com\nsigningkey = included\n[core]\nabbrev = 12\n",
+        )
+        .unwrap();
+        t.config("core.abbrev", "7");
+        std::fs::write(gitdir.join("other.inc"), "[user]\nname = Someone Else\n").unwrap();
+        let config = gitdir.join("config");
+        let mut conf = std::fs::read_to_string(&config).unwrap();
+        conf += &format!(
+            "[includeIf \"gitdir:{}/\"]\npath = identity.inc\n\
+             [includeIf \"gitdir:/nonexistent/\"]\npath = other.inc\n\
+             [user]\n\tsigningkey = after\n",
+            t.repo().worktree().display()
+        );
+        std::fs::write(&config, conf).unwrap();
+
+        let repo = t.repo();
+        assert_eq!(
+            repo.config_value("user.email").as_deref(),
+            Some("included@example.com")
+        );
+        assert_eq!(repo.config_value("user.name").as_deref(), Some("A U Thor"));
+        // the include overrides what comes before it, but not what comes after
+        assert_eq!(repo.config_value("core.abbrev").as_deref(), Some("12"));
+        assert_eq!(
+            repo.config_value("user.signingkey").as_deref(),
+            Some("after")
+        );
+    }
+
+    #[test]
+    fn edit_set_changes_only_the_line_it_sets() {
+        let before = "; settings\n[core]\n    bare = false # never\n\n[alias]\n\tst = status\n";
+
+        let mut text = before.to_owned();
+        config_edit_set(&mut text, "core.bare", "true").unwrap();
+        assert_eq!(text, before.replace("bare = false # never", "bare = true"));
+
+        let mut text = before.to_owned();
+        config_edit_set(&mut text, "Core.fileMode", "false").unwrap();
+        assert_eq!(
+            text,
+            before.replace("# never\n", "# never\n\tfileMode = false\n")
+        );
+
+        let mut text = before.to_owned();
+        config_edit_set(&mut text, "remote.origin.url", "/srv/a;b.git").unwrap();
+        assert_eq!(
+            text,
+            format!("{}[remote \"origin\"]\n\turl = \"/srv/a;b.git\"\n", before)
+        );
+        let ini = Ini::load_from_str(&text).unwrap();
+        assert_eq!(config_get(&ini, "remote.origin.url"), Some("/srv/a;b.git"));
+    }
+}
@@ -0,0 +1,659 @@
+use crate::attributes::{self, AttributeValue};
+use crate::object::{self, Oid, TreeLeaf, MODE_GITLINK, MODE_TREE};
+use crate::repository::Repo;
+use crypto::{digest::Digest, sha1::Sha1};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeStatus {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl ChangeStatus {
+    /// The status letter git uses in `--name-status` and raw diff output
+    pub fn letter(self) -> char {
+        match self {
+            ChangeStatus::Added => 'A',
+            ChangeStatus::Modified => 'M',
+            ChangeStatus::Deleted => 'D',
+        }
+    }
+}
+
+/// One side of a change: the mode and sha of a blob
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffSide {
+    pub mode: u32,
+    pub sha: Oid,
+}
+
+/// A path whose blob differs between two trees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeChange {
+    pub status: ChangeStatus,
+    /// Repo-relative path, `/` separated
+    pub path: String,
+    pub old: Option<DiffSide>,
+    pub new: Option<DiffSide>,
+}
+
+fn tree_entries(repo: &Repo, sha: Option<&Oid>) -> Result<BTreeMap<String, TreeLeaf>, String> {
+    let mut out = BTreeMap::new();
+    if let Some(sha) = sha {
+        for leaf in object::tree_read(repo, sha)?.iterate_leaves() {
+            out.insert(object::to_git_path(&leaf.path)?, leaf.clone());
+        }
+    }
+    Ok(out)
+}
+
+/// Compares two trees, recursing into subtrees, and returns the changed blobs sorted by path.
+/// A missing tree (`None`) is treated as empty, e.g. for the parent of a root commit.
+pub fn diff_trees(
+    repo: &Repo,
+    old: Option<&Oid>,
+    new: Option<&Oid>,
+) -> Result<Vec<TreeChange>, String> {
+    let mut out = vec![];
+    diff_trees_inner(repo, old, new, "", &mut out)?;
+    out.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(out)
+}
+
+fn diff_trees_inner(
+    repo: &Repo,
+    old: Option<&Oid>,
+    new: Option<&Oid>,
+    prefix: &str,
+    out: &mut Vec<TreeChange>,
+) -> Result<(), String> {
+    if old == new {
+        return Ok(());
+    }
+    let old_entries = tree_entries(repo, old)?;
+    let new_entries = tree_entries(repo, new)?;
+
+    let mut names: Vec<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        let path = format!("{}{}", prefix, name);
+        let (old_leaf, new_leaf) = (old_entries.get(name), new_entries.get(name));
+        if let (Some(o), Some(n)) = (old_leaf, new_leaf) {
+            if o.mode == n.mode && o.sha == n.sha {
+                continue;
+            }
+        }
+        // split each side into its subtree and its blob, so a path that changed between a
+        // tree and a blob shows up as a delete and an add
+        let subtree = |leaf: Option<&TreeLeaf>| leaf.filter(|l| l.mode == MODE_TREE).map(|l| l.sha);
+        let blob = |leaf: Option<&TreeLeaf>| {
+            leaf.filter(|l| l.mode != MODE_TREE).map(|l| DiffSide {
+                mode: l.mode,
+                sha: l.sha,
+            })
+        };
+
+        let (old_tree, new_tree) = (subtree(old_leaf), subtree(new_leaf));
+        if old_tree.is_some() || new_tree.is_some() {
+            diff_trees_inner(
+                repo,
+                old_tree.as_ref(),
+                new_tree.as_ref(),
+                &format!("{}/", path),
+                out,
+            )?;
+        }
+
+        let (old, new) = (blob(old_leaf), blob(new_leaf));
+        let status = match (&old, &new) {
+            (None, None) => continue,
+            (Some(_), Some(_)) => ChangeStatus::Modified,
+            (Some(_), None) => ChangeStatus::Deleted,
+            (None, Some(_)) => ChangeStatus::Added,
+        };
+        out.push(TreeChange {
+            status,
+            path,
+            old,
+            new,
+        });
+    }
+    Ok(())
+}
+
+/// A line in a line-by-line diff, holding indices into the old and/or new lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Computes the shortest edit script turning `a` into `b` with Myers' O(ND) algorithm.
+pub fn diff_lines<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let idx = |k: isize| (k + max + 1) as usize;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // the furthest reaching x on each diagonal k, before each round d
+    let mut trace = vec![];
+
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[idx(k - 1)] < v[idx(k + 1)]) {
+                v[idx(k + 1)]
+            } else {
+                v[idx(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut edits = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[idx(k - 1)] < v[idx(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[idx(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal(x as usize - 1, y as usize - 1));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert(y as usize - 1));
+            } else {
+                edits.push(Edit::Delete(x as usize - 1));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.reverse();
+    edits
+}
+
+/// Lines of context around each hunk
+const CONTEXT: usize = 3;
+
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+fn hunk_range(start: usize, len: usize) -> String {
+    // an empty range is given as the line before it
+    let start = if len == 0 { start } else { start + 1 };
+    if len == 1 {
+        format!("{}", start)
+    } else {
+        format!("{},{}", start, len)
+    }
+}
+
+/// Formats the hunks of a unified diff between two texts, with git's default of three lines
+/// of context.
+pub fn unified_hunks(old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+    let edits = diff_lines(&a, &b);
+    let mut out = String::new();
+
+    let changes: Vec<usize> = (0..edits.len())
+        .filter(|i| !matches!(edits[*i], Edit::Equal(..)))
+        .collect();
+    let mut c = 0;
+    while c < changes.len() {
+        // extend the hunk while the next change is within reach of its context
+        let start = changes[c].saturating_sub(CONTEXT);
+        let mut last = changes[c];
+        while c + 1 < changes.len() && changes[c + 1] - last <= 2 * CONTEXT {
+            c += 1;
+            last = changes[c];
+        }
+        let end = (last + CONTEXT + 1).min(edits.len());
+        c += 1;
+
+        let hunk = &edits[start..end];
+        // the number of old and new lines before the hunk
+        let (a_start, b_start) = edits[..start].iter().fold((0, 0), |(a, b), e| match e {
+            Edit::Equal(..) => (a + 1, b + 1),
+            Edit::Delete(_) => (a + 1, b),
+            Edit::Insert(_) => (a, b + 1),
+        });
+        let a_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Insert(_)))
+            .count();
+        let b_len = hunk
+            .iter()
+            .filter(|e| !matches!(e, Edit::Delete(_)))
+            .count();
+        out += &format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(a_start, a_len),
+            hunk_range(b_start, b_len)
+        );
+        for edit in hunk {
+            match *edit {
+                Edit::Equal(i, _) => push_line(&mut out, ' ', a[i]),
+                Edit::Delete(i) => push_line(&mut out, '-', a[i]),
+                Edit::Insert(j) => push_line(&mut out, '+', b[j]),
+            }
+        }
+    }
+    out
+}
+
+fn side_content(repo: &Repo, side: &Option<DiffSide>) -> Result<Vec<u8>, String> {
+    match side {
+        Some(side) if side.mode == MODE_GITLINK => {
+            Ok(format!("Subproject commit {}\n", side.sha).into_bytes())
+        }
+        Some(side) => object::blob_read(repo, &side.sha),
+        None => Ok(vec![]),
+    }
+}
+
+/// The `diff.<driver>.textconv` command configured for `path` by its `diff=<driver>`
+/// attribute, if any.
+fn textconv_command(repo: &Repo, path: &str) -> Result<Option<String>, String> {
+    Ok(match attributes::attributes_for(repo, path)?.get("diff") {
+        Some(AttributeValue::Value(driver)) => {
+            repo.config_value(&format!("diff.{}.textconv", driver))
+        }
+        _ => None,
+    })
+}
+
+/// Runs the textconv `command` on `data` as git does, passing it the path of a temporary file
+/// holding the data and taking its output. The file is created fresh, and an existing file or
+/// symlink at its path is an error rather than something to write through.
+fn textconv_run(command: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    let path = std::env::temp_dir().join(format!(
+        "wyag-textconv-{}-{}",
+        std::process::id(),
+        object::object_sha(object::ObjectType::Blob, data)
+    ));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| format!("Could not create {:?} for textconv: {:?}", path, e))?
+        .write_all(data)
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&path);
+            format!("Could not write {:?} for textconv: {:?}", path, e)
+        })?;
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", command))
+        .arg(command)
+        .arg(&path)
+        .output();
+    let _ = std::fs::remove_file(&path);
+    let output = output.map_err(|e| format!("Could not run textconv {:?}: {:?}", command, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "textconv {:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Formats `changes` as a git style patch, with a `diff --git` header for each path. With
+/// `textconv`, files whose `diff` driver has a `textconv` command are compared as that
+/// command's output, which makes readable diffs of binary formats but not patches that apply.
+pub fn format_patch(repo: &Repo, changes: &[TreeChange], textconv: bool) -> Result<String, String> {
+    let mut out = String::new();
+    for change in changes {
+        let path = &change.path;
+        out += &format!("diff --git a/{} b/{}\n", path, path);
+        match (&change.old, &change.new) {
+            (None, Some(new)) => out += &format!("new file mode {:06}\n", new.mode),
+            (Some(old), None) => out += &format!("deleted file mode {:06}\n", old.mode),
+            (Some(old), Some(new)) if old.mode != new.mode => {
+                out += &format!("old mode {:06}\nnew mode {:06}\n", old.mode, new.mode)
+            }
+            _ => (),
+        }
+        let short = |side: &Option<DiffSide>| match side {
+            Some(side) => side.sha.short(),
+            None => "0000000".to_owned(),
+        };
+        out += &format!("index {}..{}", short(&change.old), short(&change.new));
+        match (&change.old, &change.new) {
+            (Some(old), Some(new)) if old.mode == new.mode => out += &format!(" {:06}\n", old.mode),
+            _ => out.push('\n'),
+        }
+
+        let mut old = side_content(repo, &change.old)?;
+        let mut new = side_content(repo, &change.new)?;
+        if textconv {
+            if let Some(command) = textconv_command(repo, path)? {
+                let convert = |side: &Option<DiffSide>, data: Vec<u8>| match side {
+                    Some(side) if side.mode != MODE_GITLINK => textconv_run(&command, &data),
+                    _ => Ok(data),
+                };
+                old = convert(&change.old, old)?;
+                new = convert(&change.new, new)?;
+            }
+        }
+        let old_name = match change.old {
+            Some(_) => format!("a/{}", path),
+            None => "/dev/null".to_owned(),
+        };
+        let new_name = match change.new {
+            Some(_) => format!("b/{}", path),
+            None => "/dev/null".to_owned(),
+        };
+        match (std::str::from_utf8(&old), std::str::from_utf8(&new)) {
+            (Ok(old), Ok(new)) if !old.contains('\0') && !new.contains('\0') => {
+                if old != new {
+                    out += &format!("--- {}\n+++ {}\n", old_name, new_name);
+                    out += &unified_hunks(old, new);
+                }
+            }
+            _ => out += &format!("Binary files {} and {} differ\n", old_name, new_name),
+        }
+    }
+    Ok(out)
+}
+
+/// Formats `changes` in git's raw diff format, a `:<old mode> <new mode> <old sha> <new sha>
+/// <status>\t<path>` line for each path. The missing side of an add or a delete has mode
+/// `000000` and an all zeros sha.
+pub fn format_raw(changes: &[TreeChange]) -> String {
+    let side = |side: &Option<DiffSide>| match side {
+        Some(side) => (format!("{:06}", side.mode), side.sha.to_hex()),
+        None => ("000000".to_owned(), "0".repeat(40)),
+    };
+    let mut out = String::new();
+    for change in changes {
+        let (old_mode, old_sha) = side(&change.old);
+        let (new_mode, new_sha) = side(&change.new);
+        out += &format!(
+            ":{} {} {} {} {}\t{}\n",
+            old_mode,
+            new_mode,
+            old_sha,
+            new_sha,
+            change.status.letter(),
+            change.path
+        );
+    }
+    out
+}
+
+/// The paths changed by a commit, relative to its first parent. The root commit (or a
+/// commit on the shallow boundary) is compared against the empty tree.
+pub fn commit_changes(
+    repo: &Repo,
+    sha: &Oid,
+    shallow: &HashSet<Oid>,
+) -> Result<Vec<TreeChange>, String> {
+    let commit = object::commit_read(repo, sha)?;
+    let parent_tree = match object::commit_parents(&commit, sha, shallow).first() {
+        Some(parent) => object::commit_read(repo, parent)?.tree(),
+        None => None,
+    };
+    diff_trees(repo, parent_tree.as_ref(), commit.tree().as_ref())
+}
+
+/// The patch introduced by a commit, relative to its first parent
+pub fn commit_patch(repo: &Repo, sha: &Oid, shallow: &HashSet<Oid>) -> Result<String, String> {
+    format_patch(repo, &commit_changes(repo, sha, shallow)?, false)
+}
+
+/// Computes the patch id of a diff, matching `git patch-id`: a sha1 over the diff with
+/// whitespace, `index` lines and hunk line numbers removed, so the same change applied at a
+/// different place in a file hashes the same. Returns `None` if there is no diff.
+pub fn patch_id(diff: &str) -> Option<String> {
+    let mut sha = Sha1::new();
+    let mut patchlen = 0;
+    // lines left in the current hunk on each side, -1 while in a file header
+    let (mut before, mut after): (isize, isize) = (-1, -1);
+
+    for line in diff.lines() {
+        // skip anything preceding the diff, such as a commit message
+        if patchlen == 0 && !line.starts_with("diff ") {
+            continue;
+        }
+        if before == -1 {
+            if line.starts_with("index ") {
+                continue;
+            } else if line.starts_with("--- ") {
+                before = 1;
+                after = 1;
+            } else if !line.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                break;
+            }
+        }
+        if before == 0 && after == 0 {
+            if let Some(header) = line.strip_prefix("@@ -") {
+                let (b, a) = hunk_header_lengths(header);
+                before = b;
+                after = a;
+                continue;
+            }
+            if !line.starts_with("diff ") {
+                break;
+            }
+            before = -1;
+            after = -1;
+        }
+        if line.starts_with('-') || line.starts_with(' ') {
+            before -= 1;
+        }
+        if line.starts_with('+') || line.starts_with(' ') {
+            after -= 1;
+        }
+
+        let stripped: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        patchlen += stripped.len();
+        sha.input_str(&stripped);
+    }
+
+    if patchlen == 0 {
+        None
+    } else {
+        Some(sha.result_str())
+    }
+}
+
+/// If `line` starts a new commit in `log -p` or `format-patch` output, the commit's sha.
+fn commit_line_sha(line: &str) -> Option<&str> {
+    let line = line.trim_end_matches('\n');
+    let rest = line
+        .strip_prefix("commit ")
+        .or_else(|| line.strip_prefix("From "))?;
+    let sha = rest.split(' ').next()?;
+    if sha.len() == 40 && sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(sha)
+    } else {
+        None
+    }
+}
+
+/// Splits `input`, either a single diff or the output of `log -p`, into patches at each
+/// `commit <sha>` line and computes their patch ids. Returns `(patch id, commit)` pairs; the
+/// commit is all zeros for a diff without a commit line.
+pub fn patch_ids(input: &str) -> Vec<(String, String)> {
+    let zero = "0".repeat(40);
+    let mut out = vec![];
+    let mut commit = zero;
+    let mut patch = String::new();
+    for line in input.split_inclusive('\n') {
+        if let Some(sha) = commit_line_sha(line) {
+            if let Some(id) = patch_id(&patch) {
+                out.push((id, commit));
+            }
+            commit = sha.to_owned();
+            patch.clear();
+        } else {
+            patch.push_str(line);
+        }
+    }
+    if let Some(id) = patch_id(&patch) {
+        out.push((id, commit));
+    }
+    out
+}
+
+/// Parses the line counts out of a hunk header, given the text after `@@ -`.
+fn hunk_header_lengths(header: &str) -> (isize, isize) {
+    let len = |range: &str| -> isize {
+        match range.split_once(',') {
+            Some((_, len)) => len.parse().unwrap_or(1),
+            None => 1,
+        }
+    };
+    let mut parts = header.split(' ');
+    let before = parts.next().map_or(1, len);
+    let after = parts
+        .next()
+        .and_then(|r| r.strip_prefix('+'))
+        .map_or(1, len);
+    (before, after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{TreeBuilder, MODE_EXECUTABLE, MODE_FILE};
+    use crate::test_util::TempRepo;
+
+    const PATCH: &str = "diff --git a/file.txt b/file.txt
+index 1111111..2222222 100644
+--- a/file.txt
++++ b/file.txt
+@@ -3,3 +3,3 @@ context
+ three
+-four
++FOUR
+ five
+";
+
+    #[test]
+    fn patch_id_ignores_line_numbers() {
+        let moved = PATCH
+            .replace("@@ -3,3 +3,3 @@ context", "@@ -40,3 +41,3 @@ other")
+            .replace("1111111..2222222", "3333333..4444444");
+        // as `git patch-id` computes it
+        let id = "3532e5bf8900061e444ab80281204e7f9b06b6db";
+        assert_eq!(patch_id(PATCH).as_deref(), Some(id));
+        assert_eq!(patch_id(&moved).as_deref(), Some(id));
+        assert_ne!(
+            patch_id(&PATCH.replace("+FOUR", "+Four")).as_deref(),
+            Some(id)
+        );
+        assert_eq!(patch_id(""), None);
+
+        let log = format!(
+            "commit {}\nAuthor: A U Thor <author@example.com>\n\n    message\n\n{}commit {}\n\n{}",
+            "1".repeat(40),
+            PATCH,
+            "2".repeat(40),
+            moved
+        );
+        assert_eq!(
+            patch_ids(&log),
+            vec![
+                (id.to_owned(), "1".repeat(40)),
+                (id.to_owned(), "2".repeat(40))
+            ]
+        );
+    }
+
+    #[test]
+    fn textconv_makes_a_binary_change_readable() {
+        let t = TempRepo::new();
+        t.write(".gitattributes", "*.bin diff=hex\n");
+        t.write("data.bin", "\0\x01\x02");
+        let old = t.commit("old");
+        t.write("data.bin", "\0\x01\x03");
+        let new = t.commit("new");
+        t.config("diff.hex.textconv", "od -An -tx1 -v");
+
+        let repo = t.repo();
+        let tree = |sha: &Oid| object::commit_read(&repo, sha).unwrap().tree();
+        let changes = diff_trees(&repo, tree(&old).as_ref(), tree(&new).as_ref()).unwrap();
+        let patch = format_patch(&repo, &changes, true).unwrap();
+        assert!(
+            patch
+                .ends_with("--- a/data.bin\n+++ b/data.bin\n@@ -1 +1 @@\n- 00 01 02\n+ 00 01 03\n"),
+            "{}",
+            patch
+        );
+        let patch = format_patch(&repo, &changes, false).unwrap();
+        assert!(
+            patch.ends_with("Binary files a/data.bin and b/data.bin differ\n"),
+            "{}",
+            patch
+        );
+    }
+
+    #[test]
+    fn format_raw_lists_adds_modifies_and_deletes() {
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let (keep, one, two) = (t.blob("keep\n"), t.blob("one\n"), t.blob("two\n"));
+        let write = |builder: TreeBuilder| object::object_write(Some(&repo), &builder.build());
+        let old_dir = write(TreeBuilder::new().entry(MODE_FILE, "gone.txt", &one)).unwrap();
+        let new_dir = write(TreeBuilder::new().entry(MODE_EXECUTABLE, "new.sh", &two)).unwrap();
+        let old = write(
+            TreeBuilder::new()
+                .entry(MODE_FILE, "keep.txt", &keep)
+                .entry(MODE_FILE, "mod.txt", &one)
+                .entry(MODE_TREE, "dir", &old_dir),
+        )
+        .unwrap();
+        let new = write(
+            TreeBuilder::new()
+                .entry(MODE_FILE, "keep.txt", &keep)
+                .entry(MODE_FILE, "mod.txt", &two)
+                .entry(MODE_TREE, "dir", &new_dir),
+        )
+        .unwrap();
+
+        let changes = diff_trees(&repo, Some(&old), Some(&new)).unwrap();
+        let zeros = "0".repeat(40);
+        assert_eq!(
+            format_raw(&changes),
+            format!(
+                ":100644 000000 {one} {zeros} D\tdir/gone.txt\n\
+                 :000000 100755 {zeros} {two} A\tdir/new.sh\n\
+                 :100644 100644 {one} {two} M\tmod.txt\n",
+                one = one,
+                two = two,
+                zeros = zeros
+            )
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use crate::object::{self, Commit, ObjectType, Oid, Tag, Tree, MODE_GITLINK, MODE_TREE};
+use crate::pack;
+use crate::refs;
+use crate::repository::{self, Repo};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Something wrong, or merely of note, found while checking a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// A pack or its index failed a whole-file check, such as its checksum
+    BadPack { path: PathBuf, detail: String },
+    /// An object that can't be read, or whose contents don't hash to its name
+    Corrupt { sha: Oid, detail: String },
+    /// An object referred to by a ref or another object but not in the repository
+    Missing { kind: ObjectType, sha: Oid },
+    /// An object in the repository that no ref leads to
+    Unreachable { kind: ObjectType, sha: Oid },
+}
+
+impl FsckProblem {
+    /// Whether this means the repository is damaged; unreachable objects are harmless.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, FsckProblem::Unreachable { .. })
+    }
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckProblem::BadPack { path, detail } => {
+                write!(f, "error: {}: {}", path.to_string_lossy(), detail)
+            }
+            FsckProblem::Corrupt { sha, detail } => write!(f, "error: {}: {}", sha, detail),
+            FsckProblem::Missing { kind, sha } => write!(f, "missing {} {}", kind, sha),
+            FsckProblem::Unreachable { kind, sha } => write!(f, "unreachable {} {}", kind, sha),
+        }
+    }
+}
+
+/// The objects `kind`/`data` refers to, with the type each is expected to have. A commit or
+/// tag that can't be parsed is an error.
+fn object_links(
+    sha: &Oid,
+    kind: ObjectType,
+    data: &[u8],
+    shallow: &HashSet<Oid>,
+) -> Result<Vec<(ObjectType, Oid)>, String> {
+    let mut links = vec![];
+    match kind {
+        ObjectType::Commit => {
+            let commit = Commit::deserialize(data)?;
+            if let Some(tree) = commit.tree() {
+                links.push((ObjectType::Tree, tree));
+            }
+            for parent in object::commit_parents(&commit, sha, shallow) {
+                links.push((ObjectType::Commit, parent));
+            }
+        }
+        ObjectType::Tree => {
+            for leaf in Tree::deserialize(data).iterate_leaves() {
+                match leaf.mode {
+                    MODE_TREE => links.push((ObjectType::Tree, leaf.sha)),
+                    // submodule commits live in another repository
+                    MODE_GITLINK => (),
+                    _ => links.push((ObjectType::Blob, leaf.sha)),
+                }
+            }
+        }
+        ObjectType::Tag => {
+            let tag = Tag::deserialize(data)?;
+            if let Some(target) = tag.object() {
+                let kind = tag
+                    .get("type")
+                    .and_then(|t| t[0].parse().ok())
+                    .unwrap_or(ObjectType::Commit);
+                links.push((kind, target));
+            }
+        }
+        ObjectType::Blob => (),
+    }
+    Ok(links)
+}
+
+/// Checks the whole object store: every pack against its checksums, every object, loose or
+/// packed, against its sha, and that every object a ref, commit, tree or tag refers to is
+/// present. Also reports the objects that no ref reaches.
+pub fn fsck(repo: &Repo) -> Result<Vec<FsckProblem>, String> {
+    let shallow = repository::shallow_read(repo)?;
+    let mut problems = vec![];
+    // every object whose contents check out, with its type and the objects it refers to
+    let mut objects: HashMap<Oid, (ObjectType, Vec<(ObjectType, Oid)>)> = HashMap::new();
+    let mut corrupt = HashSet::new();
+
+    for pack in pack::pack_list(repo)?.iter() {
+        for (sha, detail) in pack.verify(repo)? {
+            match sha {
+                Some(sha) => {
+                    corrupt.insert(sha);
+                    problems.push(FsckProblem::Corrupt { sha, detail })
+                }
+                None => problems.push(FsckProblem::BadPack {
+                    path: pack.path.clone(),
+                    detail,
+                }),
+            }
+        }
+        for (sha, offset) in pack.index.shas.iter().zip(pack.index.offsets.iter()) {
+            if corrupt.contains(sha) || objects.contains_key(sha) {
+                continue;
+            }
+            let (kind, data) = pack.read_at(repo, *offset)?;
+            match object_links(sha, kind, &data, &shallow) {
+                Ok(links) => {
+                    objects.insert(*sha, (kind, links));
+                }
+                Err(detail) => {
+                    corrupt.insert(*sha);
+                    problems.push(FsckProblem::Corrupt { sha: *sha, detail });
+                }
+            }
+        }
+    }
+
+    for sha in object::object_list_loose(repo)? {
+        let (kind, data) = match object::object_read_loose(repo, &sha) {
+            Ok(found) => found,
+            Err(detail) => {
+                problems.push(FsckProblem::Corrupt { sha, detail });
+                continue;
+            }
+        };
+        let actual = object::object_sha(kind, &data);
+        if actual != sha {
+            problems.push(FsckProblem::Corrupt {
+                sha,
+                detail: format!("hash mismatch, contents hash to {}", actual),
+            });
+            continue;
+        }
+        let links = match object_links(&sha, kind, &data, &shallow) {
+            Ok(links) => links,
+            Err(detail) => {
+                if corrupt.insert(sha) {
+                    problems.push(FsckProblem::Corrupt { sha, detail });
+                }
+                continue;
+            }
+        };
+        // a good loose copy makes up for a damaged packed one
+        if corrupt.remove(&sha) {
+            problems.retain(|p| !matches!(p, FsckProblem::Corrupt { sha: s, .. } if *s == sha));
+        }
+        objects.insert(sha, (kind, links));
+    }
+
+    // objects in alternates are taken on trust, as they belong to another repository
+    let present = |sha: &Oid| {
+        objects.contains_key(sha) || corrupt.contains(sha) || object::object_exists(repo, sha)
+    };
+    let mut missing = HashSet::new();
+    let mut report_missing = |kind: ObjectType, sha: &Oid, problems: &mut Vec<FsckProblem>| {
+        if missing.insert(*sha) {
+            problems.push(FsckProblem::Missing { kind, sha: *sha });
+        }
+    };
+
+    let mut names: Vec<&Oid> = objects.keys().collect();
+    names.sort();
+    for sha in names.iter() {
+        for (kind, target) in objects[*sha].1.iter() {
+            if !present(target) {
+                report_missing(*kind, target, &mut problems);
+            }
+        }
+    }
+
+    let mut tips: Vec<Oid> = refs::ref_list(repo)?
+        .into_iter()
+        .map(|(_, sha)| sha)
+        .collect();
+    tips.extend(refs::ref_resolve(repo, "HEAD")?);
+    let mut reached = HashSet::new();
+    let mut stack = vec![];
+    for sha in tips {
+        if present(&sha) {
+            stack.push(sha);
+        } else {
+            report_missing(ObjectType::Commit, &sha, &mut problems);
+        }
+    }
+    while let Some(sha) = stack.pop() {
+        if !reached.insert(sha) {
+            continue;
+        }
+        if let Some((_, links)) = objects.get(&sha) {
+            stack.extend(links.iter().filter(|(_, s)| present(s)).map(|(_, s)| *s));
+        }
+    }
+    for sha in names {
+        if !reached.contains(sha) {
+            problems.push(FsckProblem::Unreachable {
+                kind: objects[sha].0,
+                sha: *sha,
+            });
+        }
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempRepo;
+
+    #[test]
+    fn fsck_flags_a_tampered_pack() {
+        let t = TempRepo::new();
+        t.write("file.txt", "some contents worth compressing\n");
+        t.commit("first");
+        let blob = object::object_sha(ObjectType::Blob, b"some contents worth compressing\n");
+        let pack = pack::repack(&t.repo(), true).unwrap().pack;
+        assert!(!fsck(&t.repo()).unwrap().iter().any(FsckProblem::is_error));
+
+        let mut data = std::fs::read(&pack.path).unwrap();
+        let offset = pack.index.find(&blob).unwrap() as usize;
+        // past the entry's header, into its compressed contents
+        data[offset + 4] ^= 0xff;
+        std::fs::write(&pack.path, data).unwrap();
+
+        let problems = fsck(&t.repo()).unwrap();
+        assert!(
+            problems
+                .iter()
+                .any(|p| matches!(p, FsckProblem::BadPack { path, .. } if *path == pack.path)),
+            "{:?}",
+            problems
+        );
+        assert!(
+            problems
+                .iter()
+                .any(|p| matches!(p, FsckProblem::Corrupt { sha, .. } if *sha == blob)),
+            "{:?}",
+            problems
+        );
+    }
+
+    #[test]
+    fn fsck_flags_a_commit_with_a_malformed_parent() {
+        let t = TempRepo::new();
+        t.write("file.txt", "contents\n");
+        let good = t.commit("first");
+        let repo = t.repo();
+        let tree = object::commit_read(&repo, &good).unwrap().tree().unwrap();
+        let raw = format!(
+            "tree {}\nparent {}\nauthor A U Thor <author@example.com> 1600000000 +0000\n\
+             committer A U Thor <author@example.com> 1600000000 +0000\n\nsecond\n",
+            tree,
+            &good.to_hex()[..39]
+        );
+        let bad =
+            object::object_write_raw(Some(&repo), ObjectType::Commit, raw.as_bytes()).unwrap();
+        crate::refs::ref_update(&repo, "refs/heads/master", &bad).unwrap();
+
+        let problems = fsck(&t.repo()).unwrap();
+        assert!(
+            problems
+                .iter()
+                .any(|p| matches!(p, FsckProblem::Corrupt { sha, detail }
+                if *sha == bad && detail.contains("Malformed parent header"))),
+            "{:?}",
+            problems
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use crate::commit_graph::{CommitGraph, GENERATION_INFINITY};
+use crate::object::{self, Oid};
+use crate::repository::{self, Repo};
+use std::collections::{BinaryHeap, HashSet};
+
+/// Every commit reachable from `tips`, including the tips themselves.
+pub fn ancestors(repo: &Repo, tips: &[Oid]) -> Result<HashSet<Oid>, String> {
+    let shallow = repository::shallow_read(repo)?;
+    let mut seen = HashSet::new();
+    let mut stack: Vec<Oid> = tips.to_vec();
+    while let Some(sha) = stack.pop() {
+        if !seen.insert(sha) {
+            continue;
+        }
+        let commit = object::commit_read(repo, &sha)?;
+        stack.extend(object::commit_parents(&commit, &sha, &shallow));
+    }
+    Ok(seen)
+}
+
+/// The commit time and parents of `sha`, from the commit-graph if it covers the commit and
+/// from the commit object otherwise.
+fn commit_info(
+    repo: &Repo,
+    graph: Option<&CommitGraph>,
+    shallow: &HashSet<Oid>,
+    sha: &Oid,
+) -> Result<(Option<i64>, Vec<Oid>), String> {
+    if let Some(commit) = graph.and_then(|graph| graph.find(sha)) {
+        return Ok((Some(commit.time), commit.parents.clone()));
+    }
+    let commit = object::commit_read(repo, sha)?;
+    let parents = object::commit_parents(&commit, sha, shallow);
+    Ok((commit.commit_time(), parents))
+}
+
+/// Lists the commits reachable from `include` but not from `exclude`, newest first by commit
+/// time, like `git rev-list include ^exclude`.
+///
+/// With a commit-graph, commits are looked up in it instead of being read, and the commits
+/// reachable from `exclude` are only walked as far as needed: a commit can only be reached
+/// from commits of a higher generation, so deciding whether it is excluded only takes
+/// walking those. Commits newer than the graph are read and walked as without one. The graph
+/// is not used in a shallow repository, as it records the parents cut off there.
+pub fn rev_list(repo: &Repo, include: &[Oid], exclude: &[Oid]) -> Result<Vec<Oid>, String> {
+    let shallow = repository::shallow_read(repo)?;
+    let graph = if shallow.is_empty() {
+        CommitGraph::load(repo)
+    } else {
+        None
+    };
+    let generation = |sha: &Oid| {
+        graph
+            .as_ref()
+            .map_or(GENERATION_INFINITY, |graph| graph.generation(sha))
+    };
+
+    // the commits known to be reachable from `exclude`, and those of them still to walk,
+    // highest generation first
+    let mut excluded: HashSet<Oid> = exclude.iter().copied().collect();
+    let mut pending: BinaryHeap<(u32, Oid)> =
+        exclude.iter().map(|sha| (generation(sha), *sha)).collect();
+    let mut is_excluded = |sha: &Oid| -> Result<bool, String> {
+        let gen = generation(sha);
+        while let Some((next, _)) = pending.peek() {
+            if *next <= gen && *next != GENERATION_INFINITY {
+                break;
+            }
+            let (_, next) = pending.pop().unwrap();
+            for parent in commit_info(repo, graph.as_ref(), &shallow, &next)?.1 {
+                if excluded.insert(parent) {
+                    pending.push((generation(&parent), parent));
+                }
+            }
+        }
+        Ok(excluded.contains(sha))
+    };
+
+    let mut seen: HashSet<Oid> = HashSet::new();
+    let mut queue = BinaryHeap::new();
+    for sha in include {
+        if !is_excluded(sha)? && seen.insert(*sha) {
+            let (time, _) = commit_info(repo, graph.as_ref(), &shallow, sha)?;
+            queue.push((time, *sha));
+        }
+    }
+
+    let mut out = vec![];
+    while let Some((_, sha)) = queue.pop() {
+        for parent in commit_info(repo, graph.as_ref(), &shallow, &sha)?.1 {
+            if !is_excluded(&parent)? && seen.insert(parent) {
+                let (time, _) = commit_info(repo, graph.as_ref(), &shallow, &parent)?;
+                queue.push((time, parent));
+            }
+        }
+        out.push(sha);
+    }
+    Ok(out)
+}
+
+/// The outcome of narrowing down a bisection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BisectStep {
+    /// Only the bad commit remains suspect, so it introduced the change
+    Found(Oid),
+    /// Test `sha` next; at most `left` untested suspects remain after that
+    Test { sha: Oid, left: usize },
+}
+
+/// Picks the next commit to test when bisecting between `bad` and the `good` commits. The
+/// suspects are the commits in `good..bad`; the one chosen is the suspect whose own suspect
+/// ancestors split them most evenly, so either answer about halves the range.
+pub fn bisect_next(repo: &Repo, bad: &Oid, good: &[Oid]) -> Result<BisectStep, String> {
+    let shallow = repository::shallow_read(repo)?;
+    let suspects = rev_list(repo, &[*bad], good)?;
+    if suspects.len() <= 1 {
+        return Ok(BisectStep::Found(*bad));
+    }
+    let suspect_set: HashSet<&Oid> = suspects.iter().collect();
+
+    let n = suspects.len();
+    let mut best: Option<(usize, usize, &Oid)> = None;
+    for sha in suspects.iter() {
+        // the suspects reachable from `sha`, i.e. those left if it turns out bad
+        let mut reached = HashSet::new();
+        let mut stack = vec![*sha];
+        while let Some(sha) = stack.pop() {
+            if !suspect_set.contains(&sha) || !reached.insert(sha) {
+                continue;
+            }
+            let commit = object::commit_read(repo, &sha)?;
+            stack.extend(object::commit_parents(&commit, &sha, &shallow));
+        }
+        let weight = reached.len();
+        let score = weight.min(n - weight);
+        if best.is_none_or(|(best_score, _, _)| score > best_score) {
+            best = Some((score, weight, sha));
+        }
+    }
+
+    // `bad` itself always scores 0 while any other suspect remains
+    let (_, weight, sha) = best.expect("there are at least two suspects");
+    Ok(BisectStep::Test {
+        sha: *sha,
+        left: weight.max(n - weight) - 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::cmd_checkout;
+    use crate::test_util::{args, TempRepo};
+    use crypto::{digest::Digest, sha1::Sha1};
+    use std::collections::HashMap;
+    use std::fs;
+
+    /// Writes a commit-graph of every commit reachable from `tips`, laid out as git writes
+    /// one: the fanout, lookup and commit data chunks, then the checksum.
+    fn graph_write(repo: &Repo, tips: &[Oid]) {
+        let mut shas: Vec<Oid> = ancestors(repo, tips).unwrap().into_iter().collect();
+        shas.sort();
+        let commits: Vec<object::Commit> = shas
+            .iter()
+            .map(|sha| object::commit_read(repo, sha).unwrap())
+            .collect();
+        let mut generations: HashMap<Oid, u64> = HashMap::new();
+        while generations.len() < shas.len() {
+            for (sha, commit) in shas.iter().zip(commits.iter()) {
+                let parents: Option<Vec<u64>> = commit
+                    .parents()
+                    .iter()
+                    .map(|p| generations.get(p).copied())
+                    .collect();
+                if let Some(parents) = parents {
+                    generations.insert(*sha, 1 + parents.into_iter().max().unwrap_or(0));
+                }
+            }
+        }
+
+        let n = shas.len();
+        // after the header, and a table of the three chunks ended by an empty entry
+        let fanout = 8 + 4 * 12;
+        let lookup = fanout + 256 * 4;
+        let data = lookup + 20 * n;
+        let mut out = b"CGPH\x01\x01\x03\x00".to_vec();
+        for (id, offset) in [
+            (b"OIDF", fanout),
+            (b"OIDL", lookup),
+            (b"CDAT", data),
+            (&[0; 4], data + 36 * n),
+        ] {
+            out.extend(id);
+            out.extend(&(offset as u64).to_be_bytes());
+        }
+        for first in 0..=255u8 {
+            let count = shas.partition_point(|sha| sha.as_bytes()[0] <= first);
+            out.extend(&(count as u32).to_be_bytes());
+        }
+        shas.iter().for_each(|sha| out.extend(sha.as_bytes()));
+        for (sha, commit) in shas.iter().zip(commits.iter()) {
+            out.extend(commit.tree().unwrap().as_bytes());
+            let parents = commit.parents();
+            assert!(parents.len() <= 2, "octopus merges need an EDGE chunk");
+            for i in 0..2 {
+                // 0x7000_0000 is the position of a missing parent
+                let pos = parents
+                    .get(i)
+                    .map_or(0x7000_0000, |p| shas.binary_search(p).unwrap() as u32);
+                out.extend(&pos.to_be_bytes());
+            }
+            let packed = generations[sha] << 34 | commit.commit_time().unwrap() as u64;
+            out.extend(&packed.to_be_bytes());
+        }
+        let mut checksum = Sha1::new();
+        checksum.input(&out);
+        let mut trailer = [0; 20];
+        checksum.result(&mut trailer);
+        out.extend(&trailer);
+
+        let dir = repo.object_dir().join("info");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("commit-graph"), out).unwrap();
+    }
+
+    #[test]
+    fn rev_list_with_a_commit_graph_matches_walking_objects() {
+        let t = TempRepo::new();
+        t.write("f.txt", "base\n");
+        let base = t.commit("base");
+        let mut side = vec![];
+        for (branch, start) in [("left", "HEAD".to_owned()), ("right", base.to_hex())] {
+            cmd_checkout(&args(&["-b", branch, &start])).unwrap();
+            for i in 0..2 {
+                t.write(&format!("{}.txt", branch), &format!("{}\n", i));
+                side.push(t.commit(&format!("{} {}", branch, i)));
+            }
+        }
+        let repo = t.repo();
+        fs::write(repo.gitdir().join("MERGE_HEAD"), format!("{}\n", side[1])).unwrap();
+        let merge = t.commit("merge");
+        t.write("f.txt", "after\n");
+        let tip = t.commit("after");
+
+        let queries: [(&[Oid], &[Oid]); 4] = [
+            (&[tip], &[]),
+            (&[tip], &[side[0]]),
+            (&[side[1]], &[side[3]]),
+            (&[tip, side[1]], &[merge]),
+        ];
+        let walked: Vec<Vec<Oid>> = queries
+            .iter()
+            .map(|(include, exclude)| rev_list(&t.repo(), include, exclude).unwrap())
+            .collect();
+        assert_eq!(walked[0].len(), 7);
+        assert_eq!(walked[0][0], tip);
+        assert_eq!(walked[0][6], base);
+
+        // a graph of every commit, so none need reading
+        graph_write(&repo, &[tip]);
+        for ((include, exclude), expected) in queries.iter().zip(walked.iter()) {
+            let repo = t.repo();
+            assert_eq!(rev_list(&repo, include, exclude).unwrap(), *expected);
+            assert_eq!(repo.object_cache().store_reads(), 0);
+        }
+        // a stale graph, missing the merge and what follows it
+        graph_write(&repo, &side);
+        for ((include, exclude), expected) in queries.iter().zip(walked.iter()) {
+            assert_eq!(rev_list(&t.repo(), include, exclude).unwrap(), *expected);
+        }
+    }
+}
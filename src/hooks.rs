@@ -0,0 +1,54 @@
+use crate::repository::Repo;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The directory hooks are run from: `core.hooksPath`, taken relative to the top of the work
+/// tree if it isn't absolute, or else `.git/hooks`.
+pub fn hooks_dir(repo: &Repo) -> PathBuf {
+    match repo.config_value("core.hookspath") {
+        Some(path) if !repo.is_bare() => repo.worktree().join(path),
+        Some(path) => repo.gitdir().join(path),
+        None => repo.gitdir().join("hooks"),
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &fs::Metadata) -> bool {
+    true
+}
+
+/// Runs the hook `name` with `args`, from the top of the work tree as git runs it, and with
+/// `GIT_INDEX_FILE` pointing at the index. A hook that doesn't exist or isn't executable is
+/// skipped, as git skips it; one that exits with a nonzero status is an error.
+pub fn hook_run(repo: &Repo, name: &str, args: &[&str]) -> Result<(), String> {
+    let path = hooks_dir(repo).join(name);
+    match fs::metadata(&path) {
+        Ok(meta) if meta.is_file() && is_executable(&meta) => (),
+        _ => return Ok(()),
+    }
+    let dir = if repo.is_bare() {
+        repo.gitdir()
+    } else {
+        repo.worktree()
+    };
+    let status = Command::new(&path)
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_INDEX_FILE", repo.gitdir().join("index"))
+        .status()
+        .map_err(|e| format!("Could not run the {} hook {:?}: {:?}", name, path, e))?;
+    if !status.success() {
+        return Err(match status.code() {
+            Some(code) => format!("The {} hook failed with exit status {}", name, code),
+            None => format!("The {} hook was killed by a signal", name),
+        });
+    }
+    Ok(())
+}
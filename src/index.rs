@@ -0,0 +1,533 @@
+use crate::lockfile::LockFile;
+use crate::object::{self, Oid, TreeBuilder, MODE_TREE};
+use crate::repository::Repo;
+use crypto::{digest::Digest, sha1::Sha1};
+use std::collections::BTreeMap;
+use std::fs;
+
+/// `DIRC`, the signature starting every index file
+const INDEX_SIGNATURE: &[u8] = b"DIRC";
+
+// bits of an entry's flags
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+
+// bits of a version 3 and later entry's extended flags
+const EXTENDED_SKIP_WORKTREE: u16 = 0x4000;
+const EXTENDED_INTENT_TO_ADD: u16 = 0x2000;
+
+/// A timestamp as the index stores it, seconds and nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IndexTime {
+    pub seconds: u32,
+    pub nanoseconds: u32,
+}
+
+/// A path staged in the index, with the stat data of its work tree file when it was staged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub ctime: IndexTime,
+    pub mtime: IndexTime,
+    pub dev: u32,
+    pub ino: u32,
+    /// The file mode, e.g. `0o100644`; unlike tree leaf modes this is the real number
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub sha: Oid,
+    pub flags: u16,
+    /// Only present in version 3 and later indexes, when `flags` has the extended bit
+    pub extended_flags: u16,
+    /// Repo-relative path, `/` separated
+    pub path: String,
+}
+
+impl IndexEntry {
+    /// The merge stage: 0 for a normal entry, 1 to 3 for the base, ours and theirs sides of a
+    /// conflict
+    pub fn stage(&self) -> u8 {
+        ((self.flags & FLAG_STAGE_MASK) >> 12) as u8
+    }
+
+    pub fn assume_valid(&self) -> bool {
+        self.flags & FLAG_ASSUME_VALID != 0
+    }
+
+    pub fn skip_worktree(&self) -> bool {
+        self.extended_flags & EXTENDED_SKIP_WORKTREE != 0
+    }
+
+    pub fn intent_to_add(&self) -> bool {
+        self.extended_flags & EXTENDED_INTENT_TO_ADD != 0
+    }
+}
+
+/// An index extension, such as the cached tree (`TREE`) or resolve undo (`REUC`) data, kept
+/// undecoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexExtension {
+    pub signature: String,
+    pub data: Vec<u8>,
+}
+
+/// The index, `.git/index`: the staging area between the work tree and the next commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    pub version: u32,
+    pub entries: Vec<IndexEntry>,
+    pub extensions: Vec<IndexExtension>,
+    /// The checksum of the index file's contents, from its trailer
+    pub checksum: String,
+}
+
+impl Index {
+    /// An empty version 2 index.
+    pub fn new() -> Self {
+        Index {
+            version: 2,
+            entries: vec![],
+            extensions: vec![],
+            checksum: String::new(),
+        }
+    }
+
+    /// Stages `entry`, replacing every entry for its path, including the stages of a conflict,
+    /// and any a tree couldn't hold alongside it: entries for its parent directories as files,
+    /// and entries inside it as a directory.
+    pub fn add(&mut self, entry: IndexEntry) {
+        self.remove(&entry.path);
+        let inside = format!("{}/", entry.path);
+        self.entries.retain(|e| {
+            !e.path.starts_with(&inside) && !entry.path.starts_with(&format!("{}/", e.path))
+        });
+        let pos = self.entries.partition_point(|e| {
+            (e.path.as_bytes(), e.stage()) < (entry.path.as_bytes(), entry.stage())
+        });
+        self.entries.insert(pos, entry);
+    }
+
+    /// Removes every entry for `path`, returning whether there were any.
+    pub fn remove(&mut self, path: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.path != path);
+        // extensions are kept undecoded, so there's no updating them to match
+        self.extensions.clear();
+        self.entries.len() != before
+    }
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IndexEntry {
+    /// An entry staging the blob `sha` for `path`, with the stat data in `meta`, which should
+    /// come from `symlink_metadata` so that symlinks are staged as links.
+    pub fn from_metadata(path: &str, sha: &Oid, meta: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        let mode = if meta.file_type().is_symlink() {
+            0o120000
+        } else if cfg!(unix) && is_executable(meta) {
+            0o100755
+        } else {
+            0o100644
+        };
+        #[cfg(unix)]
+        let (ctime, mtime, dev, ino, uid, gid) = (
+            IndexTime {
+                seconds: meta.ctime() as u32,
+                nanoseconds: meta.ctime_nsec() as u32,
+            },
+            IndexTime {
+                seconds: meta.mtime() as u32,
+                nanoseconds: meta.mtime_nsec() as u32,
+            },
+            meta.dev() as u32,
+            meta.ino() as u32,
+            meta.uid(),
+            meta.gid(),
+        );
+        #[cfg(not(unix))]
+        let (ctime, mtime, dev, ino, uid, gid) = Default::default();
+
+        IndexEntry {
+            ctime,
+            mtime,
+            dev,
+            ino,
+            mode,
+            uid,
+            gid,
+            size: meta.len() as u32,
+            sha: *sha,
+            flags: 0,
+            extended_flags: 0,
+            path: path.to_owned(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// A cursor over the bytes of an index file
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| format!("Index is truncated at byte {}", self.pos))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn time(&mut self) -> Result<IndexTime, String> {
+        Ok(IndexTime {
+            seconds: self.u32()?,
+            nanoseconds: self.u32()?,
+        })
+    }
+
+    /// Reads up to and past the next nul byte, returning what came before it.
+    fn until_nul(&mut self) -> Result<&'a [u8], String> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or("Index path is not nul terminated")?;
+        let bytes = self.take(len)?;
+        self.pos += 1;
+        Ok(bytes)
+    }
+
+    /// Reads an offset encoded as in version 4 path compression: 7 bits a byte, most
+    /// significant first, with 1 added per extra byte.
+    fn offset_varint(&mut self) -> Result<usize, String> {
+        let mut byte = self.take(1)?[0];
+        let mut value = (byte & 0x7f) as usize;
+        while byte & 0x80 != 0 {
+            byte = self.take(1)?[0];
+            value = ((value + 1) << 7) | (byte & 0x7f) as usize;
+        }
+        Ok(value)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses the contents of an index file of version 2, 3 or 4.
+pub fn index_parse(data: &[u8]) -> Result<Index, String> {
+    if data.len() < 32 || !data.starts_with(INDEX_SIGNATURE) {
+        return Err("Not an index file".to_owned());
+    }
+    let (body, trailer) = data.split_at(data.len() - 20);
+    let mut sha = Sha1::new();
+    sha.input(body);
+    if sha.result_str() != hex(trailer) {
+        return Err("Index checksum mismatch".to_owned());
+    }
+
+    let mut r = Reader { data: body, pos: 4 };
+    let version = r.u32()?;
+    if !(2..=4).contains(&version) {
+        return Err(format!("Unsupported index version {}", version));
+    }
+    let count = r.u32()?;
+
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut previous_path: Vec<u8> = vec![];
+    for _ in 0..count {
+        let start = r.pos;
+        let ctime = r.time()?;
+        let mtime = r.time()?;
+        let (dev, ino, mode, uid, gid, size) =
+            (r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+        let sha = Oid::from_bytes(r.take(20)?)?;
+        let flags = r.u16()?;
+        let extended_flags = if version >= 3 && flags & FLAG_EXTENDED != 0 {
+            r.u16()?
+        } else {
+            0
+        };
+
+        let path = if version == 4 {
+            // the path is the previous one minus some trailing bytes, plus a new suffix
+            let strip = r.offset_varint()?;
+            let keep = previous_path
+                .len()
+                .checked_sub(strip)
+                .ok_or("Index path compression strips too much")?;
+            let mut path = previous_path[..keep].to_vec();
+            path.extend_from_slice(r.until_nul()?);
+            path
+        } else {
+            let path = r.until_nul()?.to_vec();
+            if (flags & FLAG_NAME_MASK) as usize != path.len().min(FLAG_NAME_MASK as usize) {
+                return Err(format!(
+                    "Index entry {:?} has the wrong name length",
+                    String::from_utf8_lossy(&path)
+                ));
+            }
+            // entries are padded with nuls to a multiple of 8 bytes
+            let len = r.pos - start;
+            r.take((8 - len % 8) % 8)?;
+            path
+        };
+
+        entries.push(IndexEntry {
+            ctime,
+            mtime,
+            dev,
+            ino,
+            mode,
+            uid,
+            gid,
+            size,
+            sha,
+            flags,
+            extended_flags,
+            path: String::from_utf8_lossy(&path).into_owned(),
+        });
+        previous_path = path;
+    }
+
+    let mut extensions = vec![];
+    while r.pos < body.len() {
+        let signature = String::from_utf8_lossy(r.take(4)?).into_owned();
+        let len = r.u32()? as usize;
+        extensions.push(IndexExtension {
+            signature,
+            data: r.take(len)?.to_vec(),
+        });
+    }
+
+    Ok(Index {
+        version,
+        entries,
+        extensions,
+        checksum: hex(trailer),
+    })
+}
+
+/// Reads the repository's index, or `None` if it doesn't have one yet.
+pub fn index_read(repo: &Repo) -> Result<Option<Index>, String> {
+    let path = repo.gitdir().join("index");
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let data = fs::read(&path).map_err(|e| format!("Could not read index: {:?}", e))?;
+    index_parse(&data).map(Some)
+}
+
+/// Encodes an offset as version 4 path compression does; the inverse of
+/// `Reader::offset_varint`.
+fn offset_varint_encode(mut value: usize, out: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Serializes `index` as an index file, trailing checksum included. A version 2 index with
+/// extended flags on any entry is written as version 3, as version 2 can't store them.
+pub fn index_serialize(index: &Index) -> Vec<u8> {
+    let version = if index.version == 2 && index.entries.iter().any(|e| e.extended_flags != 0) {
+        3
+    } else {
+        index.version
+    };
+    let mut out = INDEX_SIGNATURE.to_vec();
+    out.extend_from_slice(&version.to_be_bytes());
+    out.extend_from_slice(&(index.entries.len() as u32).to_be_bytes());
+
+    let mut previous_path: &[u8] = &[];
+    for entry in index.entries.iter() {
+        let start = out.len();
+        for field in [
+            entry.ctime.seconds,
+            entry.ctime.nanoseconds,
+            entry.mtime.seconds,
+            entry.mtime.nanoseconds,
+            entry.dev,
+            entry.ino,
+            entry.mode,
+            entry.uid,
+            entry.gid,
+            entry.size,
+        ] {
+            out.extend_from_slice(&field.to_be_bytes());
+        }
+        out.extend_from_slice(entry.sha.as_bytes());
+
+        let path = entry.path.as_bytes();
+        let extended = version >= 3 && entry.extended_flags != 0;
+        let mut flags = entry.flags & (FLAG_ASSUME_VALID | FLAG_STAGE_MASK);
+        flags |= path.len().min(FLAG_NAME_MASK as usize) as u16;
+        if extended {
+            flags |= FLAG_EXTENDED;
+        }
+        out.extend_from_slice(&flags.to_be_bytes());
+        if extended {
+            out.extend_from_slice(&entry.extended_flags.to_be_bytes());
+        }
+
+        if version == 4 {
+            let common = previous_path
+                .iter()
+                .zip(path.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            offset_varint_encode(previous_path.len() - common, &mut out);
+            out.extend_from_slice(&path[common..]);
+            out.push(0);
+        } else {
+            out.extend_from_slice(path);
+            // at least one nul, then padding to a multiple of 8 bytes
+            let len = out.len() - start + 1;
+            out.resize(out.len() + 1 + (8 - len % 8) % 8, 0);
+        }
+        previous_path = path;
+    }
+
+    for extension in index.extensions.iter() {
+        out.extend_from_slice(extension.signature.as_bytes());
+        out.extend_from_slice(&(extension.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&extension.data);
+    }
+
+    let mut sha = Sha1::new();
+    sha.input(&out);
+    let mut checksum = [0u8; 20];
+    sha.result(&mut checksum);
+    out.extend_from_slice(&checksum);
+    out
+}
+
+/// Takes `.git/index.lock`, which a command changing the index holds from before it reads the
+/// index until [`index_write`] replaces it, so a second process changing the index at the same
+/// time fails instead of one of their changes being lost.
+pub fn index_lock(repo: &Repo) -> Result<LockFile, String> {
+    LockFile::acquire(&repo.gitdir().join("index"))
+}
+
+/// Writes `index` as the repository's index through `lock`, taken with [`index_lock`], and
+/// releases it.
+pub fn index_write(lock: LockFile, index: &Index) -> Result<(), String> {
+    lock.commit(&index_serialize(index))
+}
+
+/// An index matching the tree `tree_sha` as it has been checked out: each entry takes its
+/// mode and blob from the tree, and its stat data from the file in the work tree.
+pub fn index_from_tree(repo: &Repo, tree_sha: &Oid) -> Result<Index, String> {
+    let mut index = Index::new();
+    object::tree_read(repo, tree_sha)?.walk(repo, "", &mut |path, leaf| {
+        let full = repo.worktree().join(object::from_git_path(path));
+        let meta = fs::symlink_metadata(&full)
+            .map_err(|e| format!("Could not stat {:?}: {:?}", full, e))?;
+        let mut entry = IndexEntry::from_metadata(path, &leaf.sha, &meta);
+        entry.mode = index_mode(leaf.mode);
+        index.add(entry);
+        Ok(())
+    })?;
+    Ok(index)
+}
+
+/// The paths with conflicting entries, each with its base, ours and theirs entries (stages 1
+/// to 3), any of which may be absent, e.g. the base when both sides added the path.
+pub fn index_unmerged(index: &Index) -> BTreeMap<&str, [Option<&IndexEntry>; 3]> {
+    let mut unmerged: BTreeMap<&str, [Option<&IndexEntry>; 3]> = BTreeMap::new();
+    for entry in index.entries.iter().filter(|e| e.stage() != 0) {
+        unmerged.entry(&entry.path).or_default()[entry.stage() as usize - 1] = Some(entry);
+    }
+    unmerged
+}
+
+/// Converts an index mode such as `0o100644` to a tree leaf mode such as `100644`.
+pub fn tree_mode(mode: u32) -> u32 {
+    format!("{:o}", mode).parse().unwrap_or(mode)
+}
+
+/// Converts a tree leaf mode such as `100644` to an index mode such as `0o100644`; the inverse
+/// of [`tree_mode`].
+pub fn index_mode(mode: u32) -> u32 {
+    u32::from_str_radix(&mode.to_string(), 8).unwrap_or(mode)
+}
+
+/// Writes a tree for each directory in the index and returns the sha of the root tree.
+/// Intent-to-add entries are left out, and unmerged paths are an error, since a tree can hold
+/// only one version of each path.
+pub fn index_write_tree(repo: &Repo, index: &Index) -> Result<Oid, String> {
+    let mut unmerged: Vec<&str> = index
+        .entries
+        .iter()
+        .filter(|e| e.stage() != 0)
+        .map(|e| e.path.as_str())
+        .collect();
+    unmerged.dedup();
+    if !unmerged.is_empty() {
+        return Err(format!(
+            "Cannot write a tree with unmerged paths: {}",
+            unmerged.join(", ")
+        ));
+    }
+
+    let entries: Vec<(&str, &IndexEntry)> = index
+        .entries
+        .iter()
+        .filter(|e| !e.intent_to_add())
+        .map(|e| (e.path.as_str(), e))
+        .collect();
+    write_tree_level(repo, &entries)
+}
+
+/// Writes the tree holding `entries`, given by their paths relative to the tree.
+fn write_tree_level(repo: &Repo, entries: &[(&str, &IndexEntry)]) -> Result<Oid, String> {
+    let mut builder = TreeBuilder::new();
+    let mut subdirs: BTreeMap<&str, Vec<(&str, &IndexEntry)>> = BTreeMap::new();
+    for (path, entry) in entries {
+        match path.split_once('/') {
+            Some((dir, rest)) => subdirs.entry(dir).or_default().push((rest, *entry)),
+            None => builder = builder.entry(tree_mode(entry.mode), path, &entry.sha),
+        }
+    }
+    for (dir, entries) in subdirs {
+        let sha = write_tree_level(repo, &entries)?;
+        builder = builder.entry(MODE_TREE, dir, &sha);
+    }
+    object::object_write(Some(repo), &builder.build())
+}
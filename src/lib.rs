@@ -3,8 +3,45 @@
 #[macro_use]
 extern crate log;
 
+/// Looking up the `.gitattributes` that apply to a path.
+pub mod attributes;
+/// Attributing each line of a file to the commit that last changed it.
+pub mod blame;
 /// Wrappers that translate CLI commands into the underlying library.
 pub mod commands;
+/// Reading the commit-graph, a cache of each commit's parents, time and generation number.
+pub mod commit_graph;
+/// Reading and editing git config files.
+pub mod config;
+/// Comparing trees and files.
+pub mod diff;
+/// Checking a repository's objects for corruption.
+pub mod fsck;
+/// Walking the commit graph.
+pub mod graph;
+/// Running the scripts in `.git/hooks` that let users check or veto what a command does.
+pub mod hooks;
+/// Reading and writing the index, the staging area for the next commit.
+pub mod index;
+/// Taking a lock on a file while it is rewritten.
+pub mod lockfile;
+/// Combining the changes made on two lines of history.
+pub mod merge;
 pub mod object;
+/// Reading pack files and their indexes.
+pub mod pack;
+/// Sending long output through a pager.
+pub mod pager;
+/// Reading and updating references such as branches and tags.
+pub mod refs;
 /// Functions and types for dealing with repositories.
 pub mod repository;
+/// Recording how conflicts were resolved, to resolve them the same way when they recur.
+pub mod rerere;
+/// Comparing the work tree and index against `HEAD`.
+pub mod status;
+/// Temporary repositories for the tests to run commands against.
+#[cfg(test)]
+mod test_util;
+/// Fetching objects and refs from other repositories.
+pub mod transport;
@@ -0,0 +1,66 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Exclusive access to a file such as `.git/index` or a ref while it is rewritten, as git
+/// does it: `<file>.lock` is created, failing if it already exists, the new contents are
+/// written to it, and it is renamed over the file. Readers only ever see the old contents or
+/// the new, and a second writer fails instead of interleaving its changes.
+///
+/// Dropping the lock without committing it removes the lock file, leaving the file as it was.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl LockFile {
+    /// Takes the lock on `path`.
+    pub fn acquire(path: &Path) -> Result<Self, String> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => format!(
+                    "Unable to create {:?}: File exists. Another wyag process seems to be \
+                     running in this repository; if not, remove the file and try again",
+                    lock_path
+                ),
+                _ => format!("Unable to create {:?}: {:?}", lock_path, e),
+            })?;
+        Ok(Self {
+            path: path.to_owned(),
+            lock_path,
+            file: Some(file),
+        })
+    }
+
+    /// Replaces the locked file with `contents` and releases the lock.
+    pub fn commit(mut self, contents: &[u8]) -> Result<(), String> {
+        let mut file = self.file.take().expect("lock file is open until committed");
+        file.write_all(contents)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("Could not write {:?}: {:?}", self.lock_path, e))?;
+        drop(file);
+        fs::rename(&self.lock_path, &self.path).map_err(|e| {
+            format!(
+                "Could not rename {:?} to {:?}: {:?}",
+                self.lock_path, self.path, e
+            )
+        })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // still open means the lock was never committed
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
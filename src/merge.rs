@@ -0,0 +1,121 @@
+use crate::object::{self, Oid, TreeBuilder, MODE_TREE};
+use crate::repository::Repo;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A non-tree leaf's mode and sha
+pub type Leaf = (u32, Oid);
+
+/// The outcome of merging two trees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeMerge {
+    /// Every change fitted together; the sha of the merged tree
+    Clean(Oid),
+    /// The paths both sides changed differently, sorted
+    Conflicts(Vec<String>),
+}
+
+/// Every non-tree leaf below `sha` by its full path, with its mode and sha. A missing tree
+/// (`None`) is empty.
+fn tree_paths(repo: &Repo, sha: Option<&Oid>) -> Result<BTreeMap<String, Leaf>, String> {
+    let mut out = BTreeMap::new();
+    if let Some(sha) = sha {
+        object::tree_read(repo, sha)?.walk(repo, "", &mut |path, leaf| {
+            out.insert(path.to_owned(), (leaf.mode, leaf.sha));
+            Ok(())
+        })?;
+    }
+    Ok(out)
+}
+
+/// Writes the tree holding `entries`, given by their paths relative to the tree.
+fn tree_write_paths(repo: &Repo, entries: &[(&str, &Leaf)]) -> Result<Oid, String> {
+    let mut builder = TreeBuilder::new();
+    let mut subdirs: BTreeMap<&str, Vec<(&str, &Leaf)>> = BTreeMap::new();
+    for (path, leaf) in entries {
+        match path.split_once('/') {
+            Some((dir, rest)) => subdirs.entry(dir).or_default().push((rest, *leaf)),
+            None => builder = builder.entry(leaf.0, path, &leaf.1),
+        }
+    }
+    for (dir, entries) in subdirs {
+        let sha = tree_write_paths(repo, &entries)?;
+        builder = builder.entry(MODE_TREE, dir, &sha);
+    }
+    object::object_write(Some(repo), &builder.build())
+}
+
+/// Applies the changes from `base` to `theirs` on top of `ours`, path by path, and writes
+/// the merged tree. A path changed on only one side takes that side's version. A path both
+/// sides changed differently is a conflict, as is a file one side adds where the other has a
+/// directory; file contents are never merged. Missing trees (`None`) are empty.
+pub fn merge_trees(
+    repo: &Repo,
+    base: Option<&Oid>,
+    ours: Option<&Oid>,
+    theirs: Option<&Oid>,
+) -> Result<TreeMerge, String> {
+    merge_trees_resolving(repo, base, ours, theirs, |_, _| Ok(None))
+}
+
+/// Like [`merge_trees`], but each path both sides changed differently is first given to
+/// `resolve` with its base, ours and theirs leaves. A leaf it returns is taken as the merged
+/// version; `None` leaves the path in conflict.
+pub fn merge_trees_resolving<F>(
+    repo: &Repo,
+    base: Option<&Oid>,
+    ours: Option<&Oid>,
+    theirs: Option<&Oid>,
+    mut resolve: F,
+) -> Result<TreeMerge, String>
+where
+    F: FnMut(&str, [Option<&Leaf>; 3]) -> Result<Option<Leaf>, String>,
+{
+    let base = tree_paths(repo, base)?;
+    let ours = tree_paths(repo, ours)?;
+    let theirs = tree_paths(repo, theirs)?;
+
+    let paths: BTreeSet<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    // leaves a resolution gave, owned here as `merged` borrows the rest from the trees
+    let mut resolved = vec![];
+    let mut merged = BTreeMap::new();
+    let mut conflicts = vec![];
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let result = if o == t || b == t {
+            o
+        } else if b == o {
+            t
+        } else {
+            match resolve(path, [b, o, t])? {
+                Some(leaf) => resolved.push((path.as_str(), leaf)),
+                None => conflicts.push(path.clone()),
+            }
+            continue;
+        };
+        if let Some(leaf) = result {
+            merged.insert(path.as_str(), leaf);
+        }
+    }
+    for (path, leaf) in resolved.iter() {
+        merged.insert(path, leaf);
+    }
+    for path in merged.keys() {
+        let file_in_the_way = path
+            .match_indices('/')
+            .any(|(i, _)| merged.contains_key(&path[..i]));
+        if file_in_the_way {
+            conflicts.push((*path).to_owned());
+        }
+    }
+    if !conflicts.is_empty() {
+        conflicts.sort();
+        return Ok(TreeMerge::Conflicts(conflicts));
+    }
+
+    let entries: Vec<(&str, &Leaf)> = merged.into_iter().collect();
+    tree_write_paths(repo, &entries).map(TreeMerge::Clean)
+}
@@ -1,9 +1,13 @@
+use crate::pack;
+use crate::refs;
 use crate::repository::Repo;
 use crypto::{digest::Digest, sha1::Sha1};
 use flate2::{read::ZlibDecoder, write::ZlibEncoder};
+use std::cell::{Cell, RefCell};
 use std::collections::*;
+use std::convert::TryFrom;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::{fs, str};
 
 /// Generic VCS object type
@@ -14,26 +18,36 @@ pub trait Object: std::fmt::Debug {
     fn get_specific(&self) -> ObjectSelect;
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ObjectType {
     Commit,
     Tag,
     Tree,
+    #[default]
     Blob,
 }
 
-impl std::default::Default for ObjectType {
-    fn default() -> Self {
-        ObjectType::Blob
-    }
-}
-
 const OBJECT_TYPE_VARIANTS: &[&str] = &["commit", "tag", "tree", "blob"];
 
 impl ObjectType {
     pub const fn variants() -> &'static [&'static str] {
         OBJECT_TYPE_VARIANTS
     }
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ObjectType::Commit => "commit",
+            ObjectType::Tag => "tag",
+            ObjectType::Tree => "tree",
+            ObjectType::Blob => "blob",
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl str::FromStr for ObjectType {
@@ -50,6 +64,72 @@ impl str::FromStr for ObjectType {
     }
 }
 
+/// An object id, the SHA-1 an object is named by. It always holds a whole 20 byte hash, so
+/// anything built from one, such as a loose object's path, is well formed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Oid([u8; 20]);
+
+impl Oid {
+    /// Parses a full 40 digit hex sha, in either case.
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid object name \"{}\", expected 40 hex digits", hex);
+        if hex.len() != 40 {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 20];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+            let high = hex_digit_to_num(digits[0]).ok_or_else(invalid)?;
+            let low = hex_digit_to_num(digits[1]).ok_or_else(invalid)?;
+            *byte = high << 4 | low;
+        }
+        Ok(Self(bytes))
+    }
+
+    /// Takes a raw 20 byte sha, as trees, the index and packs store them.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        <[u8; 20]>::try_from(bytes).map(Self).map_err(|_| {
+            format!(
+                "Invalid object name, expected 20 bytes but found {}",
+                bytes.len()
+            )
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// The full sha as 40 lowercase hex digits
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The first 7 hex digits, the abbreviation git shows by default
+    pub fn short(&self) -> String {
+        self.to_hex()[..7].to_owned()
+    }
+}
+
+impl std::fmt::Display for Oid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl std::fmt::Debug for Oid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Oid({})", self.to_hex())
+    }
+}
+
+impl str::FromStr for Oid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(s)
+    }
+}
+
 /// Used to select a specific type of object
 #[derive(Debug, Clone)]
 pub enum ObjectSelect {
@@ -59,21 +139,251 @@ pub enum ObjectSelect {
     Blob(Blob),
 }
 
+impl ObjectSelect {
+    pub fn object_type(&self) -> ObjectType {
+        match self {
+            ObjectSelect::Commit(_) => ObjectType::Commit,
+            ObjectSelect::Tag(_) => ObjectType::Tag,
+            ObjectSelect::Tree(_) => ObjectType::Tree,
+            ObjectSelect::Blob(_) => ObjectType::Blob,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Commit {
-    inner: BTreeMap<String, Vec<String>>,
+    inner: Kvlm,
 }
 
 impl Commit {
-    pub fn deserialize(bytes: &[u8]) -> Self {
-        Self {
-            inner: kvlm_parse(std::str::from_utf8(bytes).unwrap()),
-        }
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, String> {
+        let raw = str::from_utf8(bytes).map_err(|e| format!("Commit is not UTF-8: {}", e))?;
+        let inner = kvlm_parse(raw)?;
+        kvlm_check_shas(&inner, "tree")?;
+        kvlm_check_shas(&inner, "parent")?;
+        Ok(Self { inner })
     }
 
     pub fn get(&self, key: &str) -> Option<&Vec<String>> {
         self.inner.get(key)
     }
+
+    /// The sha of this commit's tree
+    pub fn tree(&self) -> Option<Oid> {
+        self.inner.get("tree").and_then(|v| v[0].parse().ok())
+    }
+
+    /// The shas of this commit's parents
+    pub fn parents(&self) -> Vec<Oid> {
+        self.inner
+            .get("parent")
+            .map(|v| v.iter().filter_map(|p| p.parse().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn message(&self) -> &str {
+        self.inner.get("message").map_or("", |v| v[0].as_str())
+    }
+
+    /// The committer timestamp, in seconds since the epoch
+    pub fn commit_time(&self) -> Option<i64> {
+        // `committer Name <email> 1580000000 +0000`
+        let committer = self.inner.get("committer")?;
+        committer[0].rsplit(' ').nth(1)?.parse().ok()
+    }
+
+    pub fn author(&self) -> Option<Identity> {
+        Identity::parse(&self.inner.get("author")?[0])
+    }
+
+    pub fn committer(&self) -> Option<Identity> {
+        Identity::parse(&self.inner.get("committer")?[0])
+    }
+}
+
+/// Assembles a commit with its headers in git's order: `tree`, each `parent`, `author` and
+/// `committer`, then the message. Headers that aren't set are left out.
+#[derive(Debug, Clone, Default)]
+pub struct CommitBuilder {
+    tree: Option<Oid>,
+    parents: Vec<Oid>,
+    author: Option<Identity>,
+    committer: Option<Identity>,
+    message: String,
+}
+
+impl CommitBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tree(mut self, sha: &Oid) -> Self {
+        self.tree = Some(*sha);
+        self
+    }
+
+    /// Adds a parent; parents are recorded in the order they are added.
+    pub fn parent(mut self, sha: &Oid) -> Self {
+        self.parents.push(*sha);
+        self
+    }
+
+    pub fn author(mut self, author: Identity) -> Self {
+        self.author = Some(author);
+        self
+    }
+
+    pub fn committer(mut self, committer: Identity) -> Self {
+        self.committer = Some(committer);
+        self
+    }
+
+    /// The message is stored as given, so it should normally end with a newline.
+    pub fn message(mut self, message: String) -> Self {
+        self.message = message;
+        self
+    }
+
+    pub fn build(self) -> Commit {
+        let mut inner = Kvlm::default();
+        if let Some(tree) = self.tree {
+            inner.push("tree", tree.to_hex());
+        }
+        for parent in self.parents {
+            inner.push("parent", parent.to_hex());
+        }
+        if let Some(author) = self.author {
+            inner.push("author", author.to_string());
+        }
+        if let Some(committer) = self.committer {
+            inner.push("committer", committer.to_string());
+        }
+        inner.push("message", self.message);
+        Commit { inner }
+    }
+}
+
+/// Who made a change, and when: an `author` or `committer` header such as
+/// `Name <email> 1580000000 +0000`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the epoch
+    pub time: i64,
+    /// The timezone offset as written, e.g. `+0200`
+    pub tz: String,
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name, self.email, self.time, self.tz
+        )
+    }
+}
+
+impl Identity {
+    /// The identity to record for `role`, `AUTHOR` or `COMMITTER`, taken like git from
+    /// `GIT_<role>_NAME`, `GIT_<role>_EMAIL` and `GIT_<role>_DATE`, falling back to the
+    /// `user.name` and `user.email` config and the current time in UTC.
+    pub fn from_env(repo: &Repo, role: &str) -> Result<Self, String> {
+        let lookup = |var: &str, key: &str| {
+            std::env::var(format!("GIT_{}_{}", role, var))
+                .ok()
+                .or_else(|| repo.config_value(key))
+                .filter(|v| !v.is_empty())
+        };
+        let unknown = || {
+            format!(
+                "{}{} identity unknown, set user.name and user.email",
+                &role[..1],
+                role[1..].to_lowercase()
+            )
+        };
+        let name = lookup("NAME", "user.name").ok_or_else(unknown)?;
+        let email = lookup("EMAIL", "user.email").ok_or_else(unknown)?;
+
+        let (time, tz) = match std::env::var(format!("GIT_{}_DATE", role)) {
+            // only git's internal `<seconds> <offset>` format is supported
+            Ok(date) => {
+                let date = date.trim();
+                let date = date.strip_prefix('@').unwrap_or(date);
+                let (time, tz) = date.split_once(' ').unwrap_or((date, "+0000"));
+                let time = time
+                    .parse()
+                    .map_err(|_| format!("Unsupported date format \"{}\"", date))?;
+                (time, tz.to_owned())
+            }
+            Err(_) => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_err(|e| format!("System time is before the epoch: {:?}", e))?;
+                (now.as_secs() as i64, "+0000".to_owned())
+            }
+        };
+        Ok(Self {
+            name,
+            email,
+            time,
+            tz,
+        })
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.rsplitn(3, ' ');
+        let tz = parts.next()?;
+        let time = parts.next()?.parse().ok()?;
+        let (name, email) = parts.next()?.split_once(" <")?;
+        Some(Self {
+            name: name.to_owned(),
+            email: email.strip_suffix('>')?.to_owned(),
+            time,
+            tz: tz.to_owned(),
+        })
+    }
+
+    /// The time in its own timezone, formatted like `2020-01-26 00:53:20 +0200`
+    pub fn date(&self) -> String {
+        let offset = match (self.tz.get(..1), self.tz.get(1..3), self.tz.get(3..5)) {
+            (Some(sign), Some(h), Some(m)) => {
+                let secs =
+                    h.parse::<i64>().unwrap_or(0) * 3600 + m.parse::<i64>().unwrap_or(0) * 60;
+                if sign == "-" {
+                    -secs
+                } else {
+                    secs
+                }
+            }
+            _ => 0,
+        };
+        let local = self.time + offset;
+        let (days, secs) = (local.div_euclid(86400), local.rem_euclid(86400));
+
+        // civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            self.tz
+        )
+    }
 }
 
 impl Object for Commit {
@@ -91,17 +401,31 @@ impl Object for Commit {
 }
 
 #[derive(Debug, Clone)]
-pub struct Tag {}
+pub struct Tag {
+    inner: Kvlm,
+}
 
 impl Tag {
-    pub fn deserialize(bytes: &[u8]) -> Self {
-        todo!("deserialize tag");
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, String> {
+        let raw = str::from_utf8(bytes).map_err(|e| format!("Tag is not UTF-8: {}", e))?;
+        let inner = kvlm_parse(raw)?;
+        kvlm_check_shas(&inner, "object")?;
+        Ok(Self { inner })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Vec<String>> {
+        self.inner.get(key)
+    }
+
+    /// The sha of the object this tag points to
+    pub fn object(&self) -> Option<Oid> {
+        self.inner.get("object").and_then(|v| v[0].parse().ok())
     }
 }
 
 impl Object for Tag {
     fn serialize(&self) -> Vec<u8> {
-        todo!("serialize tag");
+        kvlm_serializie(&self.inner)
     }
 
     fn fmt_header(&self) -> &'static str {
@@ -132,6 +456,29 @@ impl Tree {
     pub fn iterate_leaves(&self) -> impl Iterator<Item = &TreeLeaf> {
         self.leaves.iter()
     }
+
+    /// Calls `f` with every non-tree leaf below this tree, in tree order, recursing into
+    /// subtrees. Each leaf comes with its full `/` separated path, starting with `prefix` if it
+    /// isn't empty. Submodules are passed to `f` like any other leaf.
+    pub fn walk<F>(&self, repo: &dyn ObjectStore, prefix: &str, f: &mut F) -> Result<(), String>
+    where
+        F: FnMut(&str, &TreeLeaf) -> Result<(), String>,
+    {
+        for leaf in self.leaves.iter() {
+            let name = to_git_path(&leaf.path)?;
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            if leaf.mode == MODE_TREE {
+                tree_read(repo, &leaf.sha)?.walk(repo, &path, f)?;
+            } else {
+                f(&path, leaf)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Object for Tree {
@@ -151,11 +498,58 @@ impl Object for Tree {
     }
 }
 
+/// Collects the entries of a single tree and orders them as git requires: by name, with
+/// subtrees compared as if their names ended in `/`.
+#[derive(Debug, Clone, Default)]
+pub struct TreeBuilder {
+    leaves: Vec<TreeLeaf>,
+}
+
+impl TreeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry; `name` is a single path component. An entry with the same name as an
+    /// earlier one replaces it.
+    pub fn entry(mut self, mode: u32, name: &str, sha: &Oid) -> Self {
+        let path = from_git_path(name);
+        self.leaves.retain(|leaf| leaf.path != path);
+        self.leaves.push(TreeLeaf {
+            mode,
+            path,
+            sha: *sha,
+        });
+        self
+    }
+
+    pub fn build(mut self) -> Tree {
+        let key = |leaf: &TreeLeaf| {
+            let mut key = leaf.name().into_bytes();
+            if leaf.mode == MODE_TREE {
+                key.push(b'/');
+            }
+            key
+        };
+        self.leaves.sort_by_key(key);
+        Tree {
+            leaves: self.leaves,
+        }
+    }
+}
+
+/// Tree leaf modes, stored as the decimal reading of git's octal mode string
+pub const MODE_TREE: u32 = 40000;
+pub const MODE_FILE: u32 = 100644;
+pub const MODE_EXECUTABLE: u32 = 100755;
+pub const MODE_SYMLINK: u32 = 120000;
+pub const MODE_GITLINK: u32 = 160000;
+
 #[derive(Debug, Clone)]
 pub struct TreeLeaf {
     pub mode: u32,
     pub path: PathBuf,
-    pub sha: String,
+    pub sha: Oid,
 }
 
 fn hex_digit_to_num(digit: u8) -> Option<u8> {
@@ -170,35 +564,65 @@ fn hex_digit_to_num(digit: u8) -> Option<u8> {
         b'7' => 7,
         b'8' => 8,
         b'9' => 9,
-        b'A' => 10,
-        b'B' => 11,
-        b'C' => 12,
-        b'D' => 13,
-        b'E' => 14,
-        b'F' => 15,
+        b'a' | b'A' => 10,
+        b'b' | b'B' => 11,
+        b'c' | b'C' => 12,
+        b'd' | b'D' => 13,
+        b'e' | b'E' => 14,
+        b'f' | b'F' => 15,
         _ => return None,
     })
 }
 
+/// Converts a relative path to the form trees and the index store it in: components separated
+/// by `/` whatever the platform's separator, with `.` components dropped. An absolute path, or
+/// one with a `..` component that could leave the repository, is an error.
+pub fn to_git_path(path: &Path) -> Result<String, String> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_string_lossy()),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                return Err(format!("Path {:?} has a '..' component", path));
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(format!("Path {:?} is not relative", path));
+            }
+        }
+    }
+    Ok(names.join("/"))
+}
+
+/// Converts a `/` separated path from a tree or the index to a relative path for this platform.
+pub fn from_git_path(path: &str) -> PathBuf {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
 impl TreeLeaf {
+    /// The leaf's name within its tree, a single path component, as the tree stores it
+    pub fn name(&self) -> String {
+        self.path.as_os_str().to_string_lossy().into_owned()
+    }
+
+    /// The type of the object this leaf points to, as implied by its mode
+    pub fn object_type(&self) -> ObjectType {
+        match self.mode {
+            MODE_TREE => ObjectType::Tree,
+            MODE_GITLINK => ObjectType::Commit,
+            _ => ObjectType::Blob,
+        }
+    }
+
     fn serialize(&self, out: &mut Vec<u8>) {
         out.extend(format!("{}", self.mode).as_bytes());
         out.push(b' ');
-        out.extend(format!("{}", self.path.to_string_lossy()).as_bytes());
+        out.extend(self.name().as_bytes());
         out.push(0);
-        self.sha_to_bytes(out);
-    }
-
-    fn sha_to_bytes(&self, bytes: &mut Vec<u8>) {
-        let sha_bytes: &[u8] = self.sha.as_ref();
-        for byte_seg in sha_bytes.chunks_exact(2) {
-            let byte = hex_digit_to_num(byte_seg[1]).unwrap()
-                | (hex_digit_to_num(byte_seg[0]).unwrap() << 4);
-            bytes.push(byte);
-        }
+        out.extend(self.sha.as_bytes());
     }
 
-    fn deserialize<'a>(bytes: &'a [u8]) -> Result<(&'a [u8], Self), String> {
+    fn deserialize(bytes: &[u8]) -> Result<(&[u8], Self), String> {
         let spc_pos = bytes
             .iter()
             .position(|i| *i == b' ')
@@ -215,25 +639,22 @@ impl TreeLeaf {
             .parse::<u32>()
             .map_err(|e| format!("Error parsing mode: {:?}", e))?;
 
-        let nul_pos = bytes[spc_pos + 1..]
-            .iter()
-            .position(|i| *i == 0)
-            .ok_or_else(|| "Error parsing tree node, expected nul terminator".to_owned())?;
+        let nul_pos = spc_pos
+            + 1
+            + bytes[spc_pos + 1..]
+                .iter()
+                .position(|i| *i == 0)
+                .ok_or_else(|| "Error parsing tree node, expected nul terminator".to_owned())?;
         let path_str = str::from_utf8(&bytes[spc_pos + 1..nul_pos])
             .map_err(|e| format!("Error reading path: {:?}", e))?;
-        let path = PathBuf::from(path_str);
-        let sha_bytes = &bytes[nul_pos + 1..nul_pos + 21];
-        if sha_bytes.len() < 20 {
+        let path = from_git_path(path_str);
+        if bytes.len() < nul_pos + 21 {
             return Err(format!(
                 "Error: expected 20 bytes for the hash, found {}",
-                sha_bytes.len()
+                bytes.len() - nul_pos - 1
             ));
         }
-        // TODO: clean up this code; can be done without extra allocation and format
-        let mut sha = String::with_capacity(20);
-        for i in 0..20 {
-            sha += &format!("{:X}", sha_bytes[i]);
-        }
+        let sha = Oid::from_bytes(&bytes[nul_pos + 1..nul_pos + 21])?;
 
         Ok((&bytes[nul_pos + 21..], Self { mode, path, sha }))
     }
@@ -247,7 +668,7 @@ pub struct Blob {
 impl Blob {
     pub fn deserialize(bytes: &[u8]) -> Self {
         Self {
-            data: bytes.iter().cloned().collect(),
+            data: bytes.to_vec(),
         }
     }
 }
@@ -266,105 +687,569 @@ impl Object for Blob {
     }
 }
 
-pub fn object_read(repo: &Repo, sha_str: &str) -> Result<Box<dyn Object>, String> {
-    let file = repo.repo_file(
-        format!("objects/{}/{}", &sha_str[0..2], &sha_str[2..]),
-        false,
-    )?;
+/// Path of the loose object file for `sha`
+pub fn object_path(repo: &Repo, sha: &Oid, mkdir: bool) -> Result<PathBuf, String> {
+    let sha = sha.to_hex();
+    let dir = repo.object_dir().join(&sha[..2]);
+    if mkdir && !dir.is_dir() {
+        fs::create_dir_all(&dir).map_err(|e| format!("Could not create directories: {:?}", e))?;
+    }
+    Ok(dir.join(&sha[2..]))
+}
+
+/// The path of the loose object `sha` in whichever object directory has it.
+fn object_path_loose(repo: &Repo, sha: &Oid) -> Option<PathBuf> {
+    let sha = sha.to_hex();
+    repo.object_dirs()
+        .map(|dir| dir.join(&sha[..2]).join(&sha[2..]))
+        .find(|path| path.is_file())
+}
+
+/// Whether `sha` is in the repository, loose or packed.
+pub fn object_exists(repo: &Repo, sha: &Oid) -> bool {
+    object_path_loose(repo, sha).is_some()
+        || pack::pack_list(repo)
+            .is_ok_and(|packs| packs.iter().any(|p| p.index.find(sha).is_some()))
+}
+
+/// Copies the object `sha` from `src` into `dest`. Loose objects are copied without decoding
+/// them; packed ones are written to `dest` as loose objects.
+pub fn object_copy(src: &Repo, dest: &Repo, sha: &Oid) -> Result<(), String> {
+    if object_exists(dest, sha) {
+        return Ok(());
+    }
+    let path = match object_path_loose(src, sha) {
+        Some(path) => path,
+        None => {
+            let (kind, data) = object_read_raw(src, sha)?;
+            return object_write_raw(Some(dest), kind, &data).map(|_| ());
+        }
+    };
+    fs::copy(path, object_path(dest, sha, true)?)
+        .map(|_| ())
+        .map_err(|e| format!("Could not copy object {}: {:?}", sha, e))
+}
+
+/// The longest `type size\0` header a loose object can have: `commit`, a space, a 64-bit size
+/// and the nul.
+const LOOSE_HEADER_MAX: usize = 6 + 1 + 20 + 1;
+
+/// Reads a `type size\0` object header from `reader`, leaving it at the start of the contents.
+fn object_header_read<R: Read>(reader: &mut R) -> Result<(ObjectType, usize), String> {
+    let mut header = vec![];
+    let mut byte = [0u8];
+    loop {
+        let read = reader
+            .read(&mut byte)
+            .map_err(|e| format!("Could not read object header: {:?}", e))?;
+        if read == 0 || header.len() == LOOSE_HEADER_MAX {
+            return Err("Malformed object header, no nul byte found".to_string());
+        }
+        if byte[0] == 0 {
+            break;
+        }
+        header.push(byte[0]);
+    }
+    // everything up to the nul has been read, so a space after it is never found here
+    let space_idx = header.iter().position(|i| *i == b' ').ok_or_else(|| {
+        format!(
+            "Malformed object header {:?}, no space before the nul byte",
+            String::from_utf8_lossy(&header)
+        )
+    })?;
+    let fmt = &header[..space_idx];
+
+    // as in git, the size is plain decimal digits without leading zeros
+    let size_field = &header[space_idx + 1..];
+    let well_formed = !size_field.is_empty()
+        && size_field.iter().all(u8::is_ascii_digit)
+        && (size_field[0] != b'0' || size_field.len() == 1);
+    let size = str::from_utf8(size_field)
+        .ok()
+        .filter(|_| well_formed)
+        .and_then(|size| size.parse::<usize>().ok())
+        .ok_or_else(|| {
+            format!(
+                "Malformed object header {:?}, bad size field",
+                String::from_utf8_lossy(&header)
+            )
+        })?;
+
+    let kind = str::from_utf8(fmt)
+        .ok()
+        .and_then(|fmt| fmt.parse().ok())
+        .ok_or_else(|| format!("Unknown object type {:?}", String::from_utf8_lossy(fmt)))?;
+    Ok((kind, size))
+}
+
+/// Opens the loose object `sha`, reading its `type size\0` header and leaving the returned
+/// decoder positioned at the start of its contents.
+fn object_open_loose(
+    repo: &Repo,
+    sha_str: &Oid,
+) -> Result<(ObjectType, usize, ZlibDecoder<fs::File>), String> {
+    let file =
+        object_path_loose(repo, sha_str).ok_or_else(|| format!("Object {} not found", sha_str))?;
     let reader = fs::OpenOptions::new()
         .read(true)
         .open(file)
         .map_err(|e| format!("Could not open file to read in object_read: {:?}", e))?;
-    let decoder = ZlibDecoder::new(reader);
-    let raw_bytes = decoder
-        .bytes()
-        .collect::<Result<Vec<u8>, _>>()
-        .map_err(|e| format!("Could not decode as zlib: {:?}", e))?;
-    let space_idx = raw_bytes
-        .iter()
-        .position(|i| *i == b' ')
-        .ok_or_else(|| format!("Format error, no space byte found"))?;
-    let fmt = &raw_bytes[..space_idx];
-
-    let nul_idx = raw_bytes
-        .iter()
-        .position(|i| *i == 0)
-        .ok_or_else(|| format!("Format error, no nul byte found"))?;
-    let size = {
-        let size_str =
-            str::from_utf8(&raw_bytes[space_idx + 1..nul_idx]).expect("valid utf8 for size field");
-        size_str.parse::<usize>().map_err(|e| {
-            format!(
-                "could not parse size field, \"{}\" as a number: {:?}",
-                size_str, e
-            )
-        })?
-    };
+    let mut decoder = ZlibDecoder::new(reader);
+    let (kind, size) =
+        object_header_read(&mut decoder).map_err(|e| format!("Object {}: {}", sha_str, e))?;
+    Ok((kind, size, decoder))
+}
+
+/// Reads one uncompressed object, a `type size\0` header and then its contents, from `reader`
+/// without needing a repository, e.g. for objects piped in or received from a transport.
+/// Exactly the object's bytes are consumed, so several objects can be read in turn.
+pub fn read_object_stream<R: Read>(mut reader: R) -> Result<(ObjectType, Vec<u8>), String> {
+    let (kind, size) = object_header_read(&mut reader)?;
+    let mut data = Vec::with_capacity(size);
+    reader
+        .take(size as u64)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Could not read object contents: {:?}", e))?;
+    if data.len() != size {
+        return Err(format!(
+            "Object is truncated, {} of {} bytes",
+            data.len(),
+            size
+        ));
+    }
+    Ok((kind, data))
+}
 
-    if size != raw_bytes.len() - nul_idx - 1 {
+/// Parses the contents of an object of type `kind`, as stored after its header.
+pub fn parse_object(kind: ObjectType, data: &[u8]) -> Result<ObjectSelect, String> {
+    Ok(match kind {
+        ObjectType::Commit => ObjectSelect::Commit(Commit::deserialize(data)?),
+        ObjectType::Tree => ObjectSelect::Tree(Tree::deserialize(data)),
+        ObjectType::Tag => ObjectSelect::Tag(Tag::deserialize(data)?),
+        ObjectType::Blob => ObjectSelect::Blob(Blob::deserialize(data)),
+    })
+}
+
+/// Reads the loose object `sha` as its type and contents, without the `type size\0` header.
+pub fn object_read_loose(repo: &Repo, sha_str: &Oid) -> Result<(ObjectType, Vec<u8>), String> {
+    let (kind, size, mut decoder) = object_open_loose(repo, sha_str)?;
+    let mut data = Vec::with_capacity(size);
+    decoder
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Could not decode as zlib: {:?}", e))?;
+    if size != data.len() {
         return Err(format!("Malformed object {}: bad length", sha_str));
     }
+    Ok((kind, data))
+}
+
+/// The size of the contents of `sha`, read from a loose object's header or a pack entry's,
+/// without reading the contents themselves.
+pub fn object_size(repo: &Repo, sha: &Oid) -> Result<usize, String> {
+    if object_path_loose(repo, sha).is_some() {
+        return object_open_loose(repo, sha).map(|(_, size, _)| size);
+    }
+    pack::pack_size(repo, sha)?.ok_or_else(|| format!("Object {} not found", sha))
+}
 
-    Ok(match fmt {
-        b"commit" => Box::new(Commit::deserialize(&raw_bytes[nul_idx + 1..])),
-        b"tree" => Box::new(Tree::deserialize(&raw_bytes[nul_idx + 1..])),
-        b"tag" => Box::new(Tag::deserialize(&raw_bytes[nul_idx + 1..])),
-        b"blob" => Box::new(Blob::deserialize(&raw_bytes[nul_idx + 1..])),
-        otherwise => {
-            return Err(format!(
-                "Unknown object type {:?} for object: {}",
-                str::from_utf8(otherwise),
-                sha_str
-            ));
+/// Opens `sha` for reading its contents incrementally, returning its type and size with the
+/// reader. Loose objects are decompressed as they are read; packed ones are read into memory
+/// first, since their deltas must be resolved as a whole.
+pub fn object_read_stream(
+    repo: &Repo,
+    sha: &Oid,
+) -> Result<(ObjectType, usize, Box<dyn Read>), String> {
+    if object_path_loose(repo, sha).is_some() {
+        let (kind, size, decoder) = object_open_loose(repo, sha)?;
+        return Ok((kind, size, Box::new(decoder)));
+    }
+    let (kind, data) =
+        pack::pack_read(repo, sha)?.ok_or_else(|| format!("Object {} not found", sha))?;
+    Ok((kind, data.len(), Box::new(std::io::Cursor::new(data))))
+}
+
+/// Writes the contents of the blob `sha` to `out` without holding all of it in memory, where it
+/// is stored loose, returning the number of bytes written.
+pub fn blob_copy_to(repo: &Repo, sha: &Oid, out: &mut dyn Write) -> Result<u64, String> {
+    let (kind, size, mut reader) = object_read_stream(repo, sha)?;
+    if kind != ObjectType::Blob {
+        return Err(format!("Object {} is not a blob", sha));
+    }
+    let written = std::io::copy(&mut reader, out)
+        .map_err(|e| format!("Could not copy blob {}: {:?}", sha, e))?;
+    if written != size as u64 {
+        return Err(format!("Malformed object {}: bad length", sha));
+    }
+    Ok(written)
+}
+
+/// The most bytes of object contents an [`ObjectCache`] holds; past that it starts over
+const OBJECT_CACHE_BYTES: usize = 64 << 20;
+/// Objects larger than this aren't cached, so one big blob can't push everything else out
+const OBJECT_CACHE_MAX_OBJECT: usize = OBJECT_CACHE_BYTES / 8;
+
+/// The objects a [`Repo`] has read or written during this process, so reading one again needn't
+/// go back to the object store. Objects never change once written, so entries can't go stale.
+#[derive(Debug, Default)]
+pub struct ObjectCache {
+    entries: RefCell<HashMap<Oid, (ObjectType, Vec<u8>)>>,
+    bytes: Cell<usize>,
+    store_reads: Cell<usize>,
+}
+
+impl ObjectCache {
+    fn get(&self, sha: &Oid) -> Option<(ObjectType, Vec<u8>)> {
+        self.entries.borrow().get(sha).cloned()
+    }
+
+    fn insert(&self, sha: &Oid, kind: ObjectType, data: &[u8]) {
+        if data.len() > OBJECT_CACHE_MAX_OBJECT || self.entries.borrow().contains_key(sha) {
+            return;
+        }
+        let mut entries = self.entries.borrow_mut();
+        if self.bytes.get() + data.len() > OBJECT_CACHE_BYTES {
+            entries.clear();
+            self.bytes.set(0);
+        }
+        entries.insert(*sha, (kind, data.to_vec()));
+        self.bytes.set(self.bytes.get() + data.len());
+    }
+
+    /// How many objects have been read from the object store rather than from the cache.
+    pub fn store_reads(&self) -> usize {
+        self.store_reads.get()
+    }
+}
+
+/// Where objects are kept: a repository's object directories and packs, or memory. Objects
+/// are stored as their type and contents; the object model on top of this is the same for
+/// every store.
+pub trait ObjectStore {
+    /// The type and contents of `sha`, or `None` if the store doesn't have it.
+    fn read(&self, sha: &Oid) -> Result<Option<(ObjectType, Vec<u8>)>, String>;
+    /// Stores an object, whose sha the caller has already computed as `sha`. Writing an
+    /// object the store already has does nothing.
+    fn write(&self, sha: &Oid, kind: ObjectType, data: &[u8]) -> Result<(), String>;
+    /// Whether the store has `sha`.
+    fn exists(&self, sha: &Oid) -> bool;
+    /// The sha of every object in the store, sorted.
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Oid> + '_>, String>;
+}
+
+/// A repository's objects are read from its object cache, or else from a loose object or a
+/// pack, and written as loose objects.
+impl ObjectStore for Repo {
+    fn read(&self, sha: &Oid) -> Result<Option<(ObjectType, Vec<u8>)>, String> {
+        let cache = self.object_cache();
+        if let Some(found) = cache.get(sha) {
+            return Ok(Some(found));
+        }
+        cache.store_reads.set(cache.store_reads.get() + 1);
+        let found = if object_path_loose(self, sha).is_some() {
+            Some(object_read_loose(self, sha)?)
+        } else {
+            pack::pack_read(self, sha)?
+        };
+        if let Some((kind, data)) = &found {
+            cache.insert(sha, *kind, data);
         }
+        Ok(found)
+    }
+
+    fn write(&self, sha: &Oid, kind: ObjectType, data: &[u8]) -> Result<(), String> {
+        let path = object_path(self, sha, true)?;
+        // an existing copy has the same contents, and may be compressed differently, so
+        // writing over it could leave the end of a longer stream behind
+        if !path.is_file() {
+            let mut oo = fs::OpenOptions::new();
+            let f =
+                oo.create(true).write(true).open(path).map_err(|e| {
+                    format!("Could not create/open file for object {}: {:?}", sha, e)
+                })?;
+            let mut enc = ZlibEncoder::new(f, Default::default());
+            enc.write_all(&object_bytes(kind, data))
+                .and_then(|_| enc.finish().map(|_| ()))
+                .map_err(|e| format!("Could not write object {}: {:?}", sha, e))?;
+        }
+        self.object_cache().insert(sha, kind, data);
+        Ok(())
+    }
+
+    fn exists(&self, sha: &Oid) -> bool {
+        object_exists(self, sha)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Oid> + '_>, String> {
+        Ok(Box::new(object_list(self)?.into_iter()))
+    }
+}
+
+/// An object store held entirely in memory, for tests and for embedding wyag where there is
+/// no repository on disk. Nothing is compressed and nothing outlives the store.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    objects: RefCell<BTreeMap<Oid, (ObjectType, Vec<u8>)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn read(&self, sha: &Oid) -> Result<Option<(ObjectType, Vec<u8>)>, String> {
+        Ok(self.objects.borrow().get(sha).cloned())
+    }
+
+    fn write(&self, sha: &Oid, kind: ObjectType, data: &[u8]) -> Result<(), String> {
+        self.objects
+            .borrow_mut()
+            .entry(*sha)
+            .or_insert_with(|| (kind, data.to_vec()));
+        Ok(())
+    }
+
+    fn exists(&self, sha: &Oid) -> bool {
+        self.objects.borrow().contains_key(sha)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = Oid> + '_>, String> {
+        let shas: Vec<Oid> = self.objects.borrow().keys().copied().collect();
+        Ok(Box::new(shas.into_iter()))
+    }
+}
+
+/// Reads `sha` from `store` as its type and contents.
+pub fn object_read_raw(
+    store: &dyn ObjectStore,
+    sha: &Oid,
+) -> Result<(ObjectType, Vec<u8>), String> {
+    store
+        .read(sha)?
+        .ok_or_else(|| format!("Object {} not found", sha))
+}
+
+pub fn object_read(store: &dyn ObjectStore, sha_str: &Oid) -> Result<Box<dyn Object>, String> {
+    let (kind, data) = object_read_raw(store, sha_str)?;
+    Ok(match kind {
+        ObjectType::Commit => Box::new(Commit::deserialize(&data)?),
+        ObjectType::Tree => Box::new(Tree::deserialize(&data)),
+        ObjectType::Tag => Box::new(Tag::deserialize(&data)?),
+        ObjectType::Blob => Box::new(Blob::deserialize(&data)),
     })
 }
 
-pub fn object_find<'a>(
+/// Resolves a name to the shas it could refer to: `HEAD` and other all caps refs, a full or
+/// abbreviated sha, a full ref name, or a tag, branch or remote branch name.
+pub fn object_resolve(repo: &Repo, name: &str) -> Result<Vec<Oid>, String> {
+    let name = name.trim();
+    let mut candidates = vec![];
+    if name.is_empty() {
+        return Ok(candidates);
+    }
+
+    if (4..=40).contains(&name.len()) && is_hex(name) {
+        let name = name.to_lowercase();
+        for dir in repo.object_dirs().map(|dir| dir.join(&name[..2])) {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)
+                .map_err(|e| format!("Could not read object directory {:?}: {:?}", dir, e))?
+            {
+                let entry = entry
+                    .map_err(|e| format!("Could not read object directory {:?}: {:?}", dir, e))?;
+                let sha = format!("{}{}", &name[..2], entry.file_name().to_string_lossy());
+                if let Ok(sha) = Oid::from_hex(&sha) {
+                    if sha.to_hex().starts_with(&name) && !candidates.contains(&sha) {
+                        candidates.push(sha);
+                    }
+                }
+            }
+        }
+        for pack in pack::pack_list(repo)?.iter() {
+            let shas = &pack.index.shas;
+            let start = shas.partition_point(|sha| sha.to_hex() < name);
+            for sha in shas[start..]
+                .iter()
+                .take_while(|sha| sha.to_hex().starts_with(&name))
+            {
+                if !candidates.contains(sha) {
+                    candidates.push(*sha);
+                }
+            }
+        }
+    }
+
+    if let Some(ref_name) = refs::ref_dwim(repo, name)? {
+        if let Some(sha) = refs::ref_resolve(repo, &ref_name)? {
+            if !candidates.contains(&sha) {
+                candidates.push(sha);
+            }
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Walks `path` from the tree `tree_sha`, returning the sha of the tree or blob it names,
+/// or `None` if there is nothing at that path. An empty path names the tree itself.
+pub fn tree_lookup_path(
+    repo: &dyn ObjectStore,
+    tree_sha: &Oid,
+    path: &str,
+) -> Result<Option<Oid>, String> {
+    let mut sha = *tree_sha;
+    let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+    while let Some(component) = components.next() {
+        let tree = tree_read(repo, &sha)?;
+        let leaf = match tree.iterate_leaves().find(|leaf| leaf.name() == component) {
+            Some(leaf) => leaf,
+            None => return Ok(None),
+        };
+        if components.peek().is_some() && leaf.mode != MODE_TREE {
+            return Ok(None);
+        }
+        sha = leaf.sha;
+    }
+    Ok(Some(sha))
+}
+
+/// Finds the sha of the object `name` refers to. `name` may also be `<rev>:<path>`, naming
+/// the tree or blob at `path` in the tree of `rev`, or `<rev>^{<type>}`, naming the object of
+/// that type `rev` peels to. `<rev>^{}` peels tags until something other than a tag is found,
+/// and `<rev>^{object}` is `rev` itself.
+///
+/// If `fmt` is given the object must be of that type. With `follow`, tags are followed and
+/// commits are resolved to their tree until an object of that type is found.
+pub fn object_find(
     repo: &Repo,
-    name: &'a str,
+    name: &str,
     fmt: Option<ObjectType>,
     follow: bool,
-) -> &'a str {
-    return name;
+) -> Result<Oid, String> {
+    let peel = name
+        .strip_suffix('}')
+        .and_then(|name| name.rsplit_once("^{"));
+    let sha = if let Some((rev, path)) = name.split_once(':') {
+        let tree = object_find(repo, rev, Some(ObjectType::Tree), true)?;
+        tree_lookup_path(repo, &tree, path)?
+            .ok_or_else(|| format!("Path \"{}\" does not exist in \"{}\"", path, rev))?
+    } else if let Some((rev, kind)) = peel {
+        let mut sha = object_find(repo, rev, None, false)?;
+        match kind {
+            "" => {
+                while let ObjectSelect::Tag(tag) = object_read(repo, &sha)?.get_specific() {
+                    sha = tag
+                        .object()
+                        .ok_or_else(|| format!("Tag {} has no object", sha))?;
+                }
+                sha
+            }
+            "object" => sha,
+            kind => {
+                let kind = kind
+                    .parse()
+                    .map_err(|_| format!("Invalid peel \"^{{{}}}\" in \"{}\"", kind, name))?;
+                object_peel(repo, name, sha, kind, true)?
+            }
+        }
+    } else {
+        let mut candidates = object_resolve(repo, name)?;
+        match candidates.len() {
+            0 => return Err(format!("No such reference: \"{}\"", name)),
+            1 => candidates.remove(0),
+            _ => {
+                return Err(format!(
+                    "Ambiguous reference \"{}\", candidates are:\n - {}",
+                    name,
+                    candidates
+                        .iter()
+                        .map(Oid::to_hex)
+                        .collect::<Vec<_>>()
+                        .join("\n - ")
+                ))
+            }
+        }
+    };
+
+    match fmt {
+        Some(fmt) => object_peel(repo, name, sha, fmt, follow),
+        None => Ok(sha),
+    }
+}
+
+/// Checks that `sha`, found from `name`, is an object of type `fmt`. With `follow`, tags are
+/// followed and commits are resolved to their tree until an object of that type is found.
+fn object_peel(
+    repo: &Repo,
+    name: &str,
+    mut sha: Oid,
+    fmt: ObjectType,
+    follow: bool,
+) -> Result<Oid, String> {
+    loop {
+        let obj = object_read(repo, &sha)?.get_specific();
+        let next = match &obj {
+            o if o.object_type() == fmt => return Ok(sha),
+            ObjectSelect::Tag(tag) if follow => tag.object(),
+            ObjectSelect::Commit(commit) if follow && fmt == ObjectType::Tree => commit.tree(),
+            _ => None,
+        };
+        sha = next.ok_or_else(|| format!("Object \"{}\" is not a {}", name, fmt))?;
+    }
 }
 
-/// Passing a repo means it will write
-pub fn object_write(repo: Option<&Repo>, object: &dyn Object) -> Result<String, String> {
-    let mut obj_bytes: Vec<u8> = vec![];
-    let data = object.serialize();
+/// Passing a store means it will write
+pub fn object_write(store: Option<&dyn ObjectStore>, object: &dyn Object) -> Result<Oid, String> {
+    let kind = object.fmt_header().parse()?;
+    object_write_raw(store, kind, &object.serialize())
+}
 
-    obj_bytes.extend(object.fmt_header().as_bytes());
+/// The bytes an object is hashed and stored as: a `type size\0` header, then its contents.
+fn object_bytes(kind: ObjectType, data: &[u8]) -> Vec<u8> {
+    let mut obj_bytes: Vec<u8> = vec![];
+    obj_bytes.extend(kind.as_str().as_bytes());
     obj_bytes.push(b' ');
     obj_bytes.extend(format!("{}", data.len()).as_bytes());
     obj_bytes.push(0);
-    obj_bytes.extend(&data[..]);
+    obj_bytes.extend(data);
+    obj_bytes
+}
 
-    let mut sha = Sha1::new();
-    sha.input(&obj_bytes);
-    let hex_out = sha.result_str();
+/// The sha of an object with the given type and contents.
+pub fn object_sha(kind: ObjectType, data: &[u8]) -> Oid {
+    sha1_oid(&object_bytes(kind, data))
+}
 
-    if let Some(repo) = repo {
-        let path = repo.repo_file(format!("objects/{}/{}", &hex_out[..2], &hex_out[2..]), true)?;
+/// The SHA-1 of `data`, as an object id.
+fn sha1_oid(data: &[u8]) -> Oid {
+    let mut sha = Sha1::new();
+    sha.input(data);
+    let mut bytes = [0u8; 20];
+    sha.result(&mut bytes);
+    Oid(bytes)
+}
 
-        let mut oo = fs::OpenOptions::new();
-        let f =
-            oo.create(true).write(true).open(path).map_err(|e| {
-                format!("Could not create/open file for object {}: {:?}", hex_out, e)
-            })?;
-        let mut enc = ZlibEncoder::new(f, Default::default());
-        enc.write_all(&obj_bytes[..]).unwrap();
-        enc.finish().unwrap();
+/// Like [`object_write`], for an object given as its type and serialized contents.
+pub fn object_write_raw(
+    store: Option<&dyn ObjectStore>,
+    kind: ObjectType,
+    data: &[u8],
+) -> Result<Oid, String> {
+    let hex_out = object_sha(kind, data);
+    if let Some(store) = store {
+        store.write(&hex_out, kind, data)?;
     }
-
     Ok(hex_out)
 }
 
 pub fn object_hash<R>(
     reader: &mut R,
     _type: ObjectType,
-    repo: Option<&Repo>,
-) -> Result<String, String>
+    store: Option<&dyn ObjectStore>,
+) -> Result<Oid, String>
 where
     R: Read,
 {
@@ -373,64 +1258,329 @@ where
     // TODO: refactor to avoid Box
     let obj: Box<dyn Object> = match _type {
         ObjectType::Blob => Box::new(Blob::deserialize(&data)),
-        ObjectType::Commit => Box::new(Commit::deserialize(&data)),
-        ObjectType::Tag => Box::new(Tag::deserialize(&data)),
+        ObjectType::Commit => Box::new(Commit::deserialize(&data)?),
+        ObjectType::Tag => Box::new(Tag::deserialize(&data)?),
         ObjectType::Tree => Box::new(Tree::deserialize(&data)),
     };
 
-    object_write(repo, &*obj)
+    object_write(store, &*obj)
+}
+
+pub fn commit_read(repo: &dyn ObjectStore, sha: &Oid) -> Result<Commit, String> {
+    match object_read(repo, sha)?.get_specific() {
+        ObjectSelect::Commit(c) => Ok(c),
+        _ => Err(format!("Object {} is not a commit", sha)),
+    }
+}
+
+pub fn blob_read(repo: &dyn ObjectStore, sha: &Oid) -> Result<Vec<u8>, String> {
+    match object_read(repo, sha)?.get_specific() {
+        ObjectSelect::Blob(b) => Ok(b.data),
+        _ => Err(format!("Object {} is not a blob", sha)),
+    }
+}
+
+/// Parents of the commit `sha` to follow when walking history. Commits on the `shallow`
+/// boundary are treated as having no parents, as their parents are not in the repository.
+pub fn commit_parents(commit: &Commit, sha: &Oid, shallow: &HashSet<Oid>) -> Vec<Oid> {
+    if shallow.contains(sha) {
+        vec![]
+    } else {
+        commit.parents()
+    }
+}
+
+pub fn tree_read(repo: &dyn ObjectStore, sha: &Oid) -> Result<Tree, String> {
+    match object_read(repo, sha)?.get_specific() {
+        ObjectSelect::Tree(t) => Ok(t),
+        _ => Err(format!("Object {} is not a tree", sha)),
+    }
+}
+
+/// Counts of the objects in a repository's object store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ObjectStoreStats {
+    pub loose_objects: usize,
+    pub packs: usize,
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| hex_digit_to_num(b).is_some())
+}
+
+/// Lists the sha of every loose object in the repository's own object directory, leaving out
+/// those borrowed from alternates.
+pub fn object_list_loose(repo: &Repo) -> Result<Vec<Oid>, String> {
+    object_list_loose_in(repo.object_dir())
+}
+
+fn object_list_loose_in(objects: &Path) -> Result<Vec<Oid>, String> {
+    let mut out = vec![];
+    if !objects.is_dir() {
+        return Ok(out);
+    }
+
+    let dirs =
+        fs::read_dir(objects).map_err(|e| format!("Could not read object store: {:?}", e))?;
+    for dir in dirs {
+        let dir = dir.map_err(|e| format!("Could not read object store: {:?}", e))?;
+        let name = dir.file_name().to_string_lossy().into_owned();
+        if name.len() != 2 || !is_hex(&name) {
+            continue;
+        }
+        for entry in fs::read_dir(dir.path())
+            .map_err(|e| format!("Could not read object directory {}: {:?}", name, e))?
+        {
+            let entry =
+                entry.map_err(|e| format!("Could not read object directory {}: {:?}", name, e))?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Ok(sha) = Oid::from_hex(&format!("{}{}", name, file_name)) {
+                out.push(sha);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Lists the sha of every object in the repository, loose or packed and including those in
+/// alternates, sorted.
+pub fn object_list(repo: &Repo) -> Result<Vec<Oid>, String> {
+    let mut out = vec![];
+    for dir in repo.object_dirs() {
+        out.extend(object_list_loose_in(dir)?);
+    }
+    for pack in pack::pack_list(repo)?.iter() {
+        out.extend(pack.index.shas.iter().copied());
+    }
+    out.sort();
+    out.dedup();
+    Ok(out)
+}
+
+pub fn object_store_stats(repo: &Repo) -> Result<ObjectStoreStats, String> {
+    let mut stats = ObjectStoreStats {
+        loose_objects: object_list_loose(repo)?.len(),
+        packs: 0,
+    };
+
+    let pack_dir = repo.object_dir().join("pack");
+    if pack_dir.is_dir() {
+        for entry in fs::read_dir(&pack_dir)
+            .map_err(|e| format!("Could not read pack directory: {:?}", e))?
+        {
+            let entry = entry.map_err(|e| format!("Could not read pack directory: {:?}", e))?;
+            if entry.path().extension() == Some("pack".as_ref()) {
+                stats.packs += 1;
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// The set of objects present in a repository, built once so fetch negotiation can answer
+/// "do I have X" without touching the filesystem.
+///
+/// With the `bloom` feature this is a bloom filter, bounding memory for huge repositories
+/// at the cost of `contains` sometimes returning true for objects that are missing.
+#[derive(Debug, Clone)]
+pub struct HaveSet {
+    #[cfg(not(feature = "bloom"))]
+    shas: HashSet<Oid>,
+    #[cfg(feature = "bloom")]
+    bloom: BloomFilter,
 }
 
-pub fn kvlm_parse(raw: &str) -> BTreeMap<String, Vec<String>> {
-    let mut map = BTreeMap::new();
-    kvlm_parse_inner(raw, &mut map);
-    map
+impl HaveSet {
+    pub fn load(repo: &Repo) -> Result<Self, String> {
+        let shas = object_list(repo)?;
+        #[cfg(not(feature = "bloom"))]
+        {
+            Ok(Self {
+                shas: shas.into_iter().collect(),
+            })
+        }
+        #[cfg(feature = "bloom")]
+        {
+            let mut bloom = BloomFilter::new(shas.len());
+            shas.iter().for_each(|sha| bloom.insert(sha));
+            Ok(Self { bloom })
+        }
+    }
+
+    pub fn contains(&self, sha: &Oid) -> bool {
+        #[cfg(not(feature = "bloom"))]
+        {
+            self.shas.contains(sha)
+        }
+        #[cfg(feature = "bloom")]
+        {
+            self.bloom.contains(sha)
+        }
+    }
 }
 
-fn kvlm_parse_inner(raw: &str, map: &mut BTreeMap<String, Vec<String>>) {
+/// A bloom filter over object shas sized for a ~1% false positive rate. Shas are already
+/// uniformly distributed, so their bytes are used directly as the hash values.
+#[cfg(feature = "bloom")]
+#[derive(Debug, Clone)]
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+#[cfg(feature = "bloom")]
+impl BloomFilter {
+    const BITS_PER_ITEM: usize = 10;
+    const HASHES: u64 = 7;
+
+    fn new(items: usize) -> Self {
+        let words = (items.max(1) * Self::BITS_PER_ITEM).div_ceil(64);
+        Self {
+            bits: vec![0; words],
+        }
+    }
+
+    /// Double hashing: the `i`th index is `h1 + i * h2`, with `h1` and `h2` taken from the
+    /// first 16 bytes of the sha.
+    fn indices(&self, sha: &Oid) -> impl Iterator<Item = usize> {
+        let word = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&sha.as_bytes()[i..i + 8]);
+            u64::from_be_bytes(bytes)
+        };
+        let (h1, h2) = (word(0), word(8) | 1);
+        let m = (self.bits.len() * 64) as u64;
+        (0..Self::HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    fn insert(&mut self, sha: &Oid) {
+        for idx in self.indices(sha).collect::<Vec<_>>() {
+            self.bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+
+    fn contains(&self, sha: &Oid) -> bool {
+        self.indices(sha)
+            .all(|idx| self.bits[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+}
+
+/// `gc.auto` default: the number of loose objects that triggers a repack
+const GC_AUTO_DEFAULT: usize = 6700;
+/// `gc.autoPackLimit` default: the number of packs that triggers a consolidating repack
+const GC_AUTO_PACK_LIMIT_DEFAULT: usize = 50;
+
+/// Whether the object store has grown past the `gc.auto`/`gc.autoPackLimit` thresholds.
+/// Setting either threshold to 0 disables that check.
+pub fn should_gc(repo: &Repo) -> Result<bool, String> {
+    let threshold = |name: &str, default: usize| -> Result<usize, String> {
+        match repo.config_value(name) {
+            Some(v) => v
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid value for {}, \"{}\": {:?}", name, v, e)),
+            None => Ok(default),
+        }
+    };
+    let auto = threshold("gc.auto", GC_AUTO_DEFAULT)?;
+    let pack_limit = threshold("gc.autopacklimit", GC_AUTO_PACK_LIMIT_DEFAULT)?;
+    let stats = object_store_stats(repo)?;
+
+    Ok((auto != 0 && stats.loose_objects > auto) || (pack_limit != 0 && stats.packs > pack_limit))
+}
+
+/// A key-value list with message: the headers of a commit or tag in the order they first
+/// appear, each with its values in order, and the message last under the key `message`.
+/// Repeated headers such as `parent` are kept together at the first one's position.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Kvlm {
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl Kvlm {
+    pub fn get(&self, key: &str) -> Option<&Vec<String>> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Appends `value` to `key`'s values, adding the key at the end if it is new.
+    pub fn push(&mut self, key: &str, value: String) {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some((_, values)) => values.push(value),
+            None => self.entries.push((key.to_owned(), vec![value])),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+}
+
+/// Fails if a value of the `key` header is not a sha, so that a commit or tag with a corrupt
+/// link can't be read at all rather than seeming to have no such link.
+fn kvlm_check_shas(kvlm: &Kvlm, key: &str) -> Result<(), String> {
+    for value in kvlm.get(key).into_iter().flatten() {
+        value
+            .parse::<Oid>()
+            .map_err(|e| format!("Malformed {} header {:?}: {}", key, value, e))?;
+    }
+    Ok(())
+}
+
+pub fn kvlm_parse(raw: &str) -> Result<Kvlm, String> {
+    let mut map = Kvlm::default();
+    kvlm_parse_inner(raw, &mut map)?;
+    Ok(map)
+}
+
+fn kvlm_parse_inner(raw: &str, map: &mut Kvlm) -> Result<(), String> {
     let space_idx = raw.find(' ');
     let newline_idx = raw.find('\n');
 
-    match (space_idx, newline_idx) {
-        (Some(spc), Some(nl)) => {
-            if nl < spc {
-                if nl != 0 {
-                    todo!("return error here");
-                }
-                map.insert("message".to_string(), vec![raw[1..].to_string()]);
-                return;
-            }
-            let key = raw[..spc].to_owned();
-            let mut inner = raw;
-            let mut value = String::new();
-            loop {
-                if let Some(n) = inner.find('\n') {
-                    value += &inner[1..=n];
-                    if inner.chars().nth(n + 1) != Some(' ') {
-                        inner = &inner[n..];
-                        break;
-                    }
-                    inner = &inner[n..];
-                } else {
+    // a blank line separates the headers from the message
+    if newline_idx == Some(0) {
+        map.push("message", raw[1..].to_string());
+        return Ok(());
+    }
+    if let (Some(spc), Some(nl)) = (space_idx, newline_idx) {
+        if nl < spc {
+            return Err(format!(
+                "Malformed header line {:?}, expected a space",
+                &raw[..nl]
+            ));
+        }
+        let key = &raw[..spc];
+        // continuation lines of a value start with a space
+        let mut end = nl;
+        while raw[end + 1..].starts_with(' ') {
+            match raw[end + 1..].find('\n') {
+                Some(n) => end += 1 + n,
+                None => {
+                    end = raw.len();
                     break;
                 }
             }
-
-            map.entry(key).or_default().push(value);
-            return kvlm_parse_inner(inner, map);
         }
-        _ => return,
+        let value = raw[spc + 1..end].replace("\n ", "\n");
+
+        map.push(key, value);
+        kvlm_parse_inner(raw.get(end + 1..).unwrap_or(""), map)
+    } else if let (None, Some(nl)) = (space_idx, newline_idx) {
+        Err(format!(
+            "Malformed header line {:?}, expected a space",
+            &raw[..nl]
+        ))
+    } else {
+        Ok(())
     }
 }
 
-pub fn kvlm_serializie(map: &BTreeMap<String, Vec<String>>) -> Vec<u8> {
+pub fn kvlm_serializie(map: &Kvlm) -> Vec<u8> {
     let mut out = vec![];
 
     for (k, v) in map.iter() {
         if k == "message" {
             continue;
         }
-        out.extend(k.as_bytes());
         for line in v {
             out.extend(k.as_bytes());
             out.push(b' ');
@@ -439,7 +1589,437 @@ pub fn kvlm_serializie(map: &BTreeMap<String, Vec<String>>) -> Vec<u8> {
         }
     }
     out.push(b'\n');
-    out.extend(map["message"][0].as_bytes());
+    if let Some(message) = map.get("message") {
+        out.extend(message[0].as_bytes());
+    }
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempRepo;
+
+    #[test]
+    fn should_gc_once_loose_objects_pass_gc_auto() {
+        let t = TempRepo::new();
+        t.config("gc.auto", "3");
+        for contents in ["one", "two", "three"] {
+            t.blob(contents);
+        }
+        assert!(!should_gc(&t.repo()).unwrap());
+        t.blob("four");
+        assert!(should_gc(&t.repo()).unwrap());
+
+        t.config("gc.auto", "0");
+        assert!(!should_gc(&t.repo()).unwrap());
+
+        // set for every repository in ~/.gitconfig, and overridden by the repository's own
+        let t = TempRepo::new();
+        t.global_config("gc.auto", "2");
+        for contents in ["one", "two", "three"] {
+            t.blob(contents);
+        }
+        assert!(should_gc(&t.repo()).unwrap());
+        t.config("gc.auto", "0");
+        assert!(!should_gc(&t.repo()).unwrap());
+    }
+
+    #[test]
+    fn have_set_matches_object_exists() {
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let present: Vec<Oid> = (0..40).map(|i| t.blob(&format!("blob {}", i))).collect();
+        // half the objects only in a pack
+        pack::pack_write(&repo, &present[..20]).unwrap();
+        for sha in present[..20].iter() {
+            fs::remove_file(object_path(&repo, sha, false).unwrap()).unwrap();
+        }
+        let missing: Vec<Oid> = (0..200)
+            .map(|i| object_sha(ObjectType::Blob, format!("missing {}", i).as_bytes()))
+            .collect();
+
+        let repo = t.repo();
+        let have = repo.have_set().unwrap();
+        for sha in present.iter() {
+            assert!(object_exists(&repo, sha));
+            assert!(have.contains(sha), "{}", sha);
+        }
+        let false_positives = missing
+            .iter()
+            .filter(|sha| {
+                assert!(!object_exists(&repo, sha));
+                have.contains(sha)
+            })
+            .count();
+        // a bloom filter may claim the odd missing object, a set never does
+        if cfg!(feature = "bloom") {
+            assert!(false_positives <= 10, "{} false positives", false_positives);
+        } else {
+            assert_eq!(false_positives, 0);
+        }
+    }
+
+    #[test]
+    fn object_find_resolves_rev_paths() {
+        let t = TempRepo::new();
+        t.write("dir/file.txt", "nested\n");
+        t.write("top.txt", "top\n");
+        t.commit("first");
+        let repo = t.repo();
+
+        let blob = object_find(&repo, "HEAD:dir/file.txt", None, false).unwrap();
+        assert_eq!(blob, object_sha(ObjectType::Blob, b"nested\n"));
+        assert_eq!(blob_read(&repo, &blob).unwrap(), b"nested\n");
+        let dir = object_find(&repo, "HEAD:dir", Some(ObjectType::Tree), false).unwrap();
+        assert_eq!(
+            tree_lookup_path(&repo, &dir, "file.txt").unwrap(),
+            Some(blob)
+        );
+
+        let err = object_find(&repo, "HEAD:dir/missing.txt", None, false).unwrap_err();
+        assert!(err.contains("does not exist"), "{}", err);
+        // a blob has no entries to walk into
+        assert!(object_find(&repo, "HEAD:top.txt/file.txt", None, false).is_err());
+    }
+
+    #[test]
+    fn commit_builder_orders_headers_like_git() {
+        let identity = |name: &str, email: &str, time, tz: &str| Identity {
+            name: name.to_owned(),
+            email: email.to_owned(),
+            time,
+            tz: tz.to_owned(),
+        };
+        let (first, second) = (
+            Oid::from_hex(&"1".repeat(40)).unwrap(),
+            Oid::from_hex(&"2".repeat(40)).unwrap(),
+        );
+        // set in an order other than git's
+        let commit = CommitBuilder::new()
+            .message("Merge two lines\n".to_owned())
+            .committer(identity(
+                "C O Mitter",
+                "committer@example.com",
+                1_600_000_100,
+                "-0500",
+            ))
+            .parent(&first)
+            .author(identity(
+                "A U Thor",
+                "author@example.com",
+                1_600_000_000,
+                "+0200",
+            ))
+            .parent(&second)
+            .tree(&Oid::from_hex("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap())
+            .build();
+
+        assert_eq!(
+            String::from_utf8(commit.serialize()).unwrap(),
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+parent 1111111111111111111111111111111111111111
+parent 2222222222222222222222222222222222222222
+author A U Thor <author@example.com> 1600000000 +0200
+committer C O Mitter <committer@example.com> 1600000100 -0500
+
+Merge two lines
+"
+        );
+        // as `git hash-object -t commit` names it
+        assert_eq!(
+            object_write(None, &commit).unwrap().to_hex(),
+            "53c8700adf2987ee1bc1edbfb77a0f13bb22f42b"
+        );
+        let parsed = Commit::deserialize(&commit.serialize()).unwrap();
+        assert_eq!(parsed.parents(), vec![first, second]);
+        assert_eq!(parsed.message(), "Merge two lines\n");
+    }
+
+    #[test]
+    fn tree_builder_matches_git_whatever_the_order() {
+        let blob = object_sha(ObjectType::Blob, b"hello\n");
+        let sub = object_write(
+            None,
+            &TreeBuilder::new().entry(MODE_FILE, "inner", &blob).build(),
+        )
+        .unwrap();
+        assert_eq!(sub.to_hex(), "e7964e179aca2ff7d8424597c8aa431311d7b816");
+
+        let entries = [
+            (MODE_FILE, "foo.txt", blob),
+            (MODE_TREE, "foo", sub),
+            (MODE_EXECUTABLE, "foo-bar", blob),
+            (MODE_SYMLINK, "link", blob),
+        ];
+        for order in [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2]] {
+            let tree = order
+                .iter()
+                .map(|&i| &entries[i])
+                .fold(TreeBuilder::new(), |b, (mode, name, sha)| {
+                    b.entry(*mode, name, sha)
+                })
+                .build();
+            // as `git mktree` names it; `foo` sorts as `foo/`, after `foo-bar` and `foo.txt`
+            assert_eq!(
+                object_write(None, &tree).unwrap().to_hex(),
+                "b65b774ef364f0f1c69ffcf394e9fc047ccdf49f"
+            );
+            let names: Vec<String> = tree.iterate_leaves().map(TreeLeaf::name).collect();
+            assert_eq!(names, ["foo-bar", "foo.txt", "foo", "link"]);
+        }
+    }
+
+    #[test]
+    fn a_written_object_is_read_back_from_the_cache() {
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let sha = object_write_raw(Some(&repo), ObjectType::Blob, b"cached\n").unwrap();
+        // gone from disk, so only the cache can have it
+        fs::remove_file(object_path(&repo, &sha, false).unwrap()).unwrap();
+        assert_eq!(blob_read(&repo, &sha).unwrap(), b"cached\n");
+        assert_eq!(repo.object_cache().store_reads(), 0);
+
+        let repo = t.repo();
+        assert!(blob_read(&repo, &sha).is_err());
+        assert_eq!(repo.object_cache().store_reads(), 1);
+    }
+
+    #[test]
+    fn walk_gives_each_leaf_its_full_path() {
+        let store = MemoryStore::new();
+        let write = |builder: TreeBuilder| object_write(Some(&store), &builder.build()).unwrap();
+        let blob = object_write_raw(Some(&store), ObjectType::Blob, b"leaf\n").unwrap();
+        let deepest = write(TreeBuilder::new().entry(MODE_FILE, "c.txt", &blob));
+        let dir = write(
+            TreeBuilder::new()
+                .entry(MODE_FILE, "b.txt", &blob)
+                .entry(MODE_TREE, "sub", &deepest)
+                .entry(MODE_GITLINK, "module", &blob),
+        );
+        let root = write(
+            TreeBuilder::new()
+                .entry(MODE_FILE, "a.txt", &blob)
+                .entry(MODE_TREE, "dir", &dir)
+                .entry(MODE_SYMLINK, "z", &blob),
+        );
+
+        for (prefix, expected) in [
+            (
+                "",
+                ["a.txt", "dir/b.txt", "dir/module", "dir/sub/c.txt", "z"],
+            ),
+            (
+                "top",
+                [
+                    "top/a.txt",
+                    "top/dir/b.txt",
+                    "top/dir/module",
+                    "top/dir/sub/c.txt",
+                    "top/z",
+                ],
+            ),
+        ] {
+            let mut paths = vec![];
+            tree_read(&store, &root)
+                .unwrap()
+                .walk(&store, prefix, &mut |path, _| {
+                    paths.push(path.to_owned());
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(paths, expected);
+        }
+    }
+
+    #[test]
+    fn read_object_stream_parses_a_raw_blob() {
+        let mut input: &[u8] = b"blob 5\0hellotree 0\0";
+        let (kind, data) = read_object_stream(&mut input).unwrap();
+        assert_eq!(kind, ObjectType::Blob);
+        match parse_object(kind, &data).unwrap() {
+            ObjectSelect::Blob(blob) => assert_eq!(blob.data, b"hello"),
+            other => panic!("parsed as a {:?}", other.object_type()),
+        }
+        // only the first object was consumed
+        assert_eq!(input, b"tree 0\0");
+
+        let err = read_object_stream(&b"blob 5\0hel"[..]).unwrap_err();
+        assert!(err.contains("truncated"), "{}", err);
+    }
+
+    #[test]
+    fn git_paths_are_slash_separated() {
+        // joined with the platform's separator, so `\` on Windows
+        let native = Path::new("dir").join("sub").join("f.txt");
+        assert_eq!(to_git_path(&native).unwrap(), "dir/sub/f.txt");
+        assert_eq!(from_git_path("dir/sub/f.txt"), native);
+        assert_eq!(
+            to_git_path(Path::new("./dir/./f.txt")).unwrap(),
+            "dir/f.txt"
+        );
+        if cfg!(windows) {
+            assert_eq!(to_git_path(Path::new("dir\\f.txt")).unwrap(), "dir/f.txt");
+        }
+        for bad in ["../f.txt", "dir/../../f.txt", "/etc/passwd"] {
+            assert!(to_git_path(Path::new(bad)).is_err(), "{}", bad);
+        }
+
+        let t = TempRepo::new();
+        t.write("dir/sub/f.txt", "nested\n");
+        t.commit("nested");
+        let repo = t.repo();
+        let index = crate::index::index_read(&repo).unwrap().unwrap();
+        let staged: Vec<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(staged, ["dir/sub/f.txt"]);
+        let tree = commit_read(&repo, &t.head()).unwrap().tree().unwrap();
+        let mut walked = vec![];
+        tree_read(&repo, &tree)
+            .unwrap()
+            .walk(&repo, "", &mut |path, _| {
+                walked.push(path.to_owned());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(walked, ["dir/sub/f.txt"]);
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        for (raw, err) in [
+            (&b"blob5\0hello"[..], "no space before the nul byte"),
+            (b"blob\0 5hello", "no space before the nul byte"),
+            (b"blob 05\0hello", "bad size field"),
+            (b"blob 5 hello", "no nul byte found"),
+        ] {
+            let found = read_object_stream(raw).unwrap_err();
+            assert!(found.contains(err), "{:?}: {}", raw, found);
+        }
+
+        // the same through a loose object
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let sha = object_sha(ObjectType::Blob, b"hello");
+        let mut enc = ZlibEncoder::new(vec![], Default::default());
+        enc.write_all(b"blob\0 5hello").unwrap();
+        fs::write(
+            object_path(&repo, &sha, true).unwrap(),
+            enc.finish().unwrap(),
+        )
+        .unwrap();
+        let found = object_read_raw(&repo, &sha).unwrap_err();
+        assert!(found.contains("no space before the nul byte"), "{}", found);
+    }
+
+    #[test]
+    fn oid_takes_only_a_whole_sha() {
+        let hex = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let oid = Oid::from_hex(hex).unwrap();
+        assert_eq!(oid.to_hex(), hex);
+        assert_eq!(oid.to_string(), hex);
+        assert_eq!(oid.short(), "4b825dc");
+        assert_eq!(Oid::from_hex(&hex.to_uppercase()).unwrap(), oid);
+        assert_eq!(hex.parse::<Oid>().unwrap(), oid);
+        assert_eq!(Oid::from_bytes(oid.as_bytes()).unwrap(), oid);
+
+        for bad in [
+            "",
+            "4b825dc",
+            &hex[..39],
+            &format!("{}0", hex),
+            &hex.replace('4', "g"),
+            // 40 characters, but not a name to build an object path from
+            "../../../../../../../../../../etc/passwd",
+        ] {
+            let err = Oid::from_hex(bad).unwrap_err();
+            assert!(err.contains("expected 40 hex digits"), "{:?}: {}", bad, err);
+        }
+        assert!(Oid::from_bytes(&[0; 19]).is_err());
+        assert!(Oid::from_bytes(&[0; 21]).is_err());
+    }
+
+    #[test]
+    fn links_that_are_not_shas_fail_to_read() {
+        let sha = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let commit = format!("tree {}\nparent {}\n\nmessage\n", sha, sha);
+        assert_eq!(
+            Commit::deserialize(commit.as_bytes()).unwrap().parents(),
+            vec![Oid::from_hex(sha).unwrap()]
+        );
+        for (key, corrupt) in [("tree", "tree nope\n"), ("parent", "parent 4b825dc\n")] {
+            let raw = commit.replacen(&format!("{} {}\n", key, sha), corrupt, 1);
+            let err = Commit::deserialize(raw.as_bytes()).unwrap_err();
+            assert!(
+                err.contains(&format!("Malformed {} header", key)),
+                "{}",
+                err
+            );
+        }
+
+        let tag = format!("object {}\ntype commit\ntag v1\n\nmessage\n", sha);
+        assert_eq!(
+            Tag::deserialize(tag.as_bytes()).unwrap().object(),
+            Oid::from_hex(sha).ok()
+        );
+        let raw = tag.replace(sha, &sha[..20]);
+        let err = Tag::deserialize(raw.as_bytes()).unwrap_err();
+        assert!(err.contains("Malformed object header"), "{}", err);
+    }
+
+    /// Writes a blob, a tree, a commit and a tag to `store` and reads each back, returning
+    /// their shas.
+    fn round_trip(store: &dyn ObjectStore) -> Vec<Oid> {
+        let blob = object_write_raw(Some(store), ObjectType::Blob, b"round trip\n").unwrap();
+        let tree = TreeBuilder::new().entry(MODE_FILE, "f.txt", &blob).build();
+        let tree_sha = object_write(Some(store), &tree).unwrap();
+        let author = Identity {
+            name: "A U Thor".to_owned(),
+            email: "author@example.com".to_owned(),
+            time: 1_600_000_000,
+            tz: "+0000".to_owned(),
+        };
+        let commit = CommitBuilder::new()
+            .tree(&tree_sha)
+            .author(author.clone())
+            .committer(author)
+            .message("round trip\n".to_owned())
+            .build();
+        let commit_sha = object_write(Some(store), &commit).unwrap();
+        let tag = format!(
+            "object {}\ntype commit\ntag v1\ntagger A U Thor <author@example.com> 1600000000 +0000\n\nv1\n",
+            commit_sha
+        );
+        let tag_sha = object_write_raw(Some(store), ObjectType::Tag, tag.as_bytes()).unwrap();
+
+        let written = [
+            (blob, ObjectType::Blob, b"round trip\n".to_vec()),
+            (tree_sha, ObjectType::Tree, tree.serialize()),
+            (commit_sha, ObjectType::Commit, commit.serialize()),
+            (tag_sha, ObjectType::Tag, tag.into_bytes()),
+        ];
+        for (sha, kind, data) in written.iter() {
+            assert!(store.exists(sha));
+            // written again, the object is unchanged
+            assert_eq!(object_write_raw(Some(store), *kind, data).unwrap(), *sha);
+            assert_eq!(object_read_raw(store, sha).unwrap(), (*kind, data.clone()));
+            let object = object_read(store, sha).unwrap();
+            assert_eq!(object.fmt_header(), kind.to_string());
+            assert_eq!(object.serialize(), *data);
+        }
+        let missing = object_sha(ObjectType::Blob, b"never written\n");
+        assert!(!store.exists(&missing));
+        assert_eq!(store.read(&missing).unwrap(), None);
+
+        let mut shas: Vec<Oid> = written.iter().map(|(sha, _, _)| *sha).collect();
+        shas.sort();
+        assert_eq!(store.iter().unwrap().collect::<Vec<_>>(), shas);
+        shas
+    }
+
+    #[test]
+    fn memory_store_and_repo_round_trip_alike() {
+        let t = TempRepo::new();
+        assert_eq!(round_trip(&MemoryStore::new()), round_trip(&t.repo()));
+    }
+}
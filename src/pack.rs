@@ -0,0 +1,820 @@
+use crate::object::{self, ObjectType, Oid};
+use crate::repository::Repo;
+use crypto::{digest::Digest, sha1::Sha1};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Crc};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// object type numbers in pack entry headers
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+/// `\377tOc`, the magic number starting version 2 and later pack indexes
+const IDX_MAGIC: &[u8] = b"\xfftOc";
+
+/// The deepest delta chain followed before giving up, in case of a cycle
+const MAX_DELTA_DEPTH: usize = 10_000;
+
+/// The contents of a pack's `.idx` file: the shas of the objects in the pack, sorted, and
+/// their offsets in the `.pack` file.
+#[derive(Debug, Clone)]
+pub struct PackIndex {
+    pub version: u32,
+    pub shas: Vec<Oid>,
+    pub offsets: Vec<u64>,
+    /// The CRC32 of each packed entry; only version 2 indexes record these
+    pub crcs: Vec<u32>,
+    /// The checksum of the pack this index is for
+    pub pack_checksum: String,
+    /// The checksum the index records for itself, and the one its contents actually have
+    pub checksum: String,
+    pub actual_checksum: String,
+}
+
+fn be_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "Pack index is truncated".to_owned())
+}
+
+fn be_u64(data: &[u8], pos: usize) -> Result<u64, String> {
+    Ok((be_u32(data, pos)? as u64) << 32 | be_u32(data, pos + 4)? as u64)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    let mut sha = Sha1::new();
+    sha.input(data);
+    sha.result_str()
+}
+
+impl PackIndex {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 40 {
+            return Err("Pack index is truncated".to_owned());
+        }
+        let (version, fanout) = if data.starts_with(IDX_MAGIC) {
+            (be_u32(data, 4)?, 8)
+        } else {
+            (1, 0)
+        };
+        let count = be_u32(data, fanout + 255 * 4)? as usize;
+        let body = fanout + 256 * 4;
+
+        let (mut shas, mut offsets, mut crcs) = (vec![], vec![], vec![]);
+        let trailer = match version {
+            1 => {
+                // a 4 byte offset then the 20 byte sha for each object
+                for i in 0..count {
+                    let entry = body + i * 24;
+                    offsets.push(be_u32(data, entry)? as u64);
+                    let sha = data
+                        .get(entry + 4..entry + 24)
+                        .ok_or("Pack index is truncated")?;
+                    shas.push(Oid::from_bytes(sha)?);
+                }
+                body + count * 24
+            }
+            2 => {
+                // tables of shas, CRCs and offsets, then 8 byte offsets for large packs
+                let (crc_table, offset_table) = (body + count * 20, body + count * 24);
+                let large_table = body + count * 28;
+                let mut large = 0;
+                for i in 0..count {
+                    let sha = data
+                        .get(body + i * 20..body + i * 20 + 20)
+                        .ok_or("Pack index is truncated")?;
+                    shas.push(Oid::from_bytes(sha)?);
+                    crcs.push(be_u32(data, crc_table + i * 4)?);
+                    let offset = be_u32(data, offset_table + i * 4)?;
+                    if offset & 0x8000_0000 != 0 {
+                        let idx = (offset & 0x7fff_ffff) as usize;
+                        offsets.push(be_u64(data, large_table + idx * 8)?);
+                        large = large.max(idx + 1);
+                    } else {
+                        offsets.push(offset as u64);
+                    }
+                }
+                large_table + large * 8
+            }
+            v => return Err(format!("Unsupported pack index version {}", v)),
+        };
+
+        let checksums = data
+            .get(trailer..trailer + 40)
+            .ok_or("Pack index is truncated")?;
+        Ok(Self {
+            version,
+            shas,
+            offsets,
+            crcs,
+            pack_checksum: hex(&checksums[..20]),
+            checksum: hex(&checksums[20..]),
+            actual_checksum: sha1_hex(&data[..trailer + 20]),
+        })
+    }
+
+    /// The offset of `sha` in the pack, if the pack has it.
+    pub fn find(&self, sha: &Oid) -> Option<u64> {
+        self.shas.binary_search(sha).ok().map(|i| self.offsets[i])
+    }
+}
+
+/// A `.pack` file in `objects/pack` together with its index
+#[derive(Debug, Clone)]
+pub struct Pack {
+    pub path: PathBuf,
+    pub index: PackIndex,
+}
+
+/// The packs a [`Repo`] has found, opened on the first lookup so that later ones needn't list
+/// the pack directories and parse every index again. Writing or removing a pack through the
+/// repository clears it.
+#[derive(Debug, Default)]
+pub struct PackCache {
+    packs: RefCell<Option<Rc<Vec<Pack>>>>,
+}
+
+impl PackCache {
+    /// Forgets the packs found so far, so the next lookup lists the pack directories again.
+    pub fn clear(&self) {
+        self.packs.replace(None);
+    }
+}
+
+/// Lists the packs in each of the repository's object directories, sorted by name within
+/// each. A pack without an index is skipped, as git skips it. The list is read once per
+/// [`Repo`] and kept in its [`PackCache`].
+pub fn pack_list(repo: &Repo) -> Result<Rc<Vec<Pack>>, String> {
+    let cache = repo.pack_cache();
+    if let Some(packs) = cache.packs.borrow().as_ref() {
+        return Ok(Rc::clone(packs));
+    }
+    let packs = Rc::new(pack_list_read(repo)?);
+    cache.packs.replace(Some(Rc::clone(&packs)));
+    Ok(packs)
+}
+
+fn pack_list_read(repo: &Repo) -> Result<Vec<Pack>, String> {
+    let mut packs = vec![];
+    for dir in repo.object_dirs().map(|dir| dir.join("pack")) {
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut idx_paths = vec![];
+        for entry in
+            fs::read_dir(&dir).map_err(|e| format!("Could not read pack directory: {:?}", e))?
+        {
+            let path = entry
+                .map_err(|e| format!("Could not read pack directory: {:?}", e))?
+                .path();
+            if path.extension() == Some("idx".as_ref()) && path.with_extension("pack").is_file() {
+                idx_paths.push(path);
+            }
+        }
+        idx_paths.sort();
+        for path in idx_paths {
+            packs.push(Pack::open(&path)?);
+        }
+    }
+    Ok(packs)
+}
+
+/// Reads `sha` from whichever pack has it, as its type and contents.
+pub fn pack_read(repo: &Repo, sha: &Oid) -> Result<Option<(ObjectType, Vec<u8>)>, String> {
+    for pack in pack_list(repo)?.iter() {
+        if let Some(offset) = pack.index.find(sha) {
+            return pack.read_at(repo, offset).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// The size of `sha` if a pack has it, without reading the whole object.
+pub fn pack_size(repo: &Repo, sha: &Oid) -> Result<Option<usize>, String> {
+    for pack in pack_list(repo)?.iter() {
+        if let Some(offset) = pack.index.find(sha) {
+            return pack.size_at(offset).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Reads a variable length number from a delta: 7 bits a byte, least significant first.
+fn delta_varint(delta: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let (mut value, mut shift) = (0usize, 0);
+    loop {
+        let byte = *delta.get(*pos).ok_or("Delta is truncated")?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Rebuilds an object from its delta `base` and a delta: the base and result sizes, then
+/// instructions which either copy a range of the base or insert literal bytes.
+pub fn delta_apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    let mut pos = 0;
+    let base_size = delta_varint(delta, &mut pos)?;
+    if base_size != base.len() {
+        return Err(format!(
+            "Delta expects a base of {} bytes, but it has {}",
+            base_size,
+            base.len()
+        ));
+    }
+    let size = delta_varint(delta, &mut pos)?;
+    let mut out = Vec::with_capacity(size);
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            // the low 4 bits say which offset bytes follow, the next 3 which size bytes
+            let mut field = |bits: u8, first: u32| -> Result<usize, String> {
+                let mut value = 0usize;
+                for i in 0..bits {
+                    if op & (1 << (first + i as u32)) != 0 {
+                        let byte = *delta.get(pos).ok_or("Delta is truncated")?;
+                        pos += 1;
+                        value |= (byte as usize) << (8 * i);
+                    }
+                }
+                Ok(value)
+            };
+            let offset = field(4, 0)?;
+            let len = match field(3, 4)? {
+                0 => 0x10000,
+                len => len,
+            };
+            let copied = base
+                .get(offset..offset + len)
+                .ok_or("Delta copies from outside its base")?;
+            out.extend_from_slice(copied);
+        } else if op != 0 {
+            let inserted = delta
+                .get(pos..pos + op as usize)
+                .ok_or("Delta is truncated")?;
+            out.extend_from_slice(inserted);
+            pos += op as usize;
+        } else {
+            return Err("Delta uses reserved instruction 0".to_owned());
+        }
+    }
+
+    if out.len() != size {
+        return Err(format!(
+            "Delta produced {} bytes, expected {}",
+            out.len(),
+            size
+        ));
+    }
+    Ok(out)
+}
+
+/// A pack entry's header, decoded
+enum EntryKind {
+    Whole(ObjectType),
+    OfsDelta(u64),
+    RefDelta(Oid),
+}
+
+impl Pack {
+    /// Opens the pack whose index is at `idx_path`.
+    pub fn open(idx_path: &Path) -> Result<Self, String> {
+        let data = fs::read(idx_path)
+            .map_err(|e| format!("Could not read pack index {:?}: {:?}", idx_path, e))?;
+        let index = PackIndex::parse(&data)
+            .map_err(|e| format!("Could not parse pack index {:?}: {}", idx_path, e))?;
+        Ok(Self {
+            path: idx_path.with_extension("pack"),
+            index,
+        })
+    }
+
+    /// Reads the entry at `offset`, resolving deltas, as its type and contents. The base of
+    /// a ref delta outside this pack is looked up in the rest of `repo`.
+    pub fn read_at(&self, repo: &Repo, offset: u64) -> Result<(ObjectType, Vec<u8>), String> {
+        let mut deltas = vec![];
+        let mut offset = offset;
+        let (kind, mut data) = loop {
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(format!("Delta chain too deep in {:?}", self.path));
+            }
+            let (kind, data) = self.read_entry(offset)?;
+            match kind {
+                EntryKind::Whole(kind) => break (kind, data),
+                EntryKind::OfsDelta(base) => {
+                    deltas.push(data);
+                    offset = base;
+                }
+                EntryKind::RefDelta(base) => {
+                    deltas.push(data);
+                    match self.index.find(&base) {
+                        Some(base) => offset = base,
+                        None => break object::object_read_raw(repo, &base)?,
+                    }
+                }
+            }
+        };
+        while let Some(delta) = deltas.pop() {
+            data = delta_apply(&data, &delta)?;
+        }
+        Ok((kind, data))
+    }
+
+    /// The size of the object at `offset` once any deltas are resolved. For a delta that is
+    /// the result size at the start of its instructions, so only those bytes are inflated.
+    pub fn size_at(&self, offset: u64) -> Result<usize, String> {
+        let (kind, size, reader) = self.read_entry_header(offset)?;
+        if let EntryKind::Whole(_) = kind {
+            return Ok(size);
+        }
+        // the base and result sizes are at most 10 bytes each
+        let mut header = vec![];
+        ZlibDecoder::new(reader)
+            .take(20)
+            .read_to_end(&mut header)
+            .map_err(|e| format!("Could not inflate object at offset {}: {:?}", offset, e))?;
+        let mut pos = 0;
+        delta_varint(&header, &mut pos)?;
+        delta_varint(&header, &mut pos)
+    }
+
+    /// Reads the header and inflated data of the entry at `offset`, without resolving deltas.
+    fn read_entry(&self, offset: u64) -> Result<(EntryKind, Vec<u8>), String> {
+        let (kind, size, reader) = self.read_entry_header(offset)?;
+        let mut data = Vec::with_capacity(size);
+        ZlibDecoder::new(reader)
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Could not inflate object at offset {}: {:?}", offset, e))?;
+        if data.len() != size {
+            return Err(format!(
+                "Object at offset {} in {:?} is {} bytes, expected {}",
+                offset,
+                self.path,
+                data.len(),
+                size
+            ));
+        }
+        Ok((kind, data))
+    }
+
+    /// Reads the header of the entry at `offset`: its kind and the size of its inflated data,
+    /// returning a reader positioned at the compressed data.
+    fn read_entry_header(
+        &self,
+        offset: u64,
+    ) -> Result<(EntryKind, usize, BufReader<fs::File>), String> {
+        let err = |e: std::io::Error| format!("Could not read pack {:?}: {:?}", self.path, e);
+        let mut file = fs::File::open(&self.path).map_err(err)?;
+        file.seek(SeekFrom::Start(offset)).map_err(err)?;
+        let mut reader = BufReader::new(file);
+        let mut next_byte = || -> Result<u8, String> {
+            let mut byte = [0];
+            reader.read_exact(&mut byte).map_err(err)?;
+            Ok(byte[0])
+        };
+
+        // type in bits 4-6 of the first byte, then the size, 4 bits and then 7 a byte
+        let mut byte = next_byte()?;
+        let type_num = (byte >> 4) & 0x7;
+        let mut size = (byte & 0x0f) as usize;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            byte = next_byte()?;
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+        }
+
+        let kind = match type_num {
+            OBJ_COMMIT => EntryKind::Whole(ObjectType::Commit),
+            OBJ_TREE => EntryKind::Whole(ObjectType::Tree),
+            OBJ_BLOB => EntryKind::Whole(ObjectType::Blob),
+            OBJ_TAG => EntryKind::Whole(ObjectType::Tag),
+            OBJ_OFS_DELTA => {
+                // the distance back to the base, big endian, with 1 added per extra byte
+                let mut byte = next_byte()?;
+                let mut distance = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    byte = next_byte()?;
+                    distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+                }
+                let base = offset
+                    .checked_sub(distance)
+                    .ok_or_else(|| format!("Bad delta base offset in {:?}", self.path))?;
+                EntryKind::OfsDelta(base)
+            }
+            OBJ_REF_DELTA => {
+                let mut base = [0; 20];
+                for b in base.iter_mut() {
+                    *b = next_byte()?;
+                }
+                EntryKind::RefDelta(Oid::from_bytes(&base)?)
+            }
+            n => {
+                return Err(format!(
+                    "Unknown object type {} at offset {} in {:?}",
+                    n, offset, self.path
+                ))
+            }
+        };
+
+        Ok((kind, size, reader))
+    }
+
+    /// Checks the pack and its index against their checksums, then reads back and re-hashes
+    /// every object in it. Returns a description of each problem found, paired with the
+    /// object it concerns, if any.
+    pub fn verify(&self, repo: &Repo) -> Result<Vec<(Option<Oid>, String)>, String> {
+        let mut problems = vec![];
+        let data =
+            fs::read(&self.path).map_err(|e| format!("Could not read {:?}: {:?}", self.path, e))?;
+
+        if self.index.checksum != self.index.actual_checksum {
+            problems.push((None, "index checksum mismatch".to_owned()));
+        }
+        if data.len() < 32 || !data.starts_with(b"PACK") {
+            problems.push((None, "not a pack file".to_owned()));
+            return Ok(problems);
+        }
+        let (body, trailer) = data.split_at(data.len() - 20);
+        if sha1_hex(body) != hex(trailer) {
+            problems.push((None, "pack checksum mismatch".to_owned()));
+        }
+        if hex(trailer) != self.index.pack_checksum {
+            problems.push((None, "pack does not match its index".to_owned()));
+        }
+        let count = be_u32(&data, 8)? as usize;
+        if count != self.index.shas.len() {
+            problems.push((
+                None,
+                format!(
+                    "pack has {} objects but its index has {}",
+                    count,
+                    self.index.shas.len()
+                ),
+            ));
+        }
+
+        // each entry runs up to the next one, or to the trailing checksum
+        let mut ends: Vec<u64> = self.index.offsets.clone();
+        ends.push(body.len() as u64);
+        ends.sort_unstable();
+        for (i, (sha, offset)) in self
+            .index
+            .shas
+            .iter()
+            .zip(self.index.offsets.iter())
+            .enumerate()
+        {
+            if let Some(expected) = self.index.crcs.get(i) {
+                let end = ends[ends.partition_point(|e| e <= offset)] as usize;
+                let mut crc = Crc::new();
+                crc.update(&body[*offset as usize..end]);
+                if crc.sum() != *expected {
+                    problems.push((Some(*sha), "CRC mismatch in pack index".to_owned()));
+                    continue;
+                }
+            }
+            match self.read_at(repo, *offset) {
+                Ok((kind, data)) => {
+                    let actual = object::object_sha(kind, &data);
+                    if actual != *sha {
+                        problems.push((
+                            Some(*sha),
+                            format!("hash mismatch, contents hash to {}", actual),
+                        ));
+                    }
+                }
+                Err(e) => problems.push((Some(*sha), e)),
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// The pack entry type number of `kind`
+fn type_number(kind: ObjectType) -> u8 {
+    match kind {
+        ObjectType::Commit => OBJ_COMMIT,
+        ObjectType::Tree => OBJ_TREE,
+        ObjectType::Blob => OBJ_BLOB,
+        ObjectType::Tag => OBJ_TAG,
+    }
+}
+
+/// Encodes a pack entry of type `type_num` holding `data`: its header, then `base` (a delta's
+/// reference to its base, empty for a whole object), then `data` compressed.
+fn entry_encode(type_num: u8, base: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    // type in bits 4-6 of the first byte, then the size, 4 bits and then 7 a byte
+    let mut entry = vec![];
+    let mut size = data.len();
+    let mut byte = type_num << 4 | (size & 0x0f) as u8;
+    size >>= 4;
+    while size != 0 {
+        entry.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    entry.push(byte);
+    entry.extend_from_slice(base);
+    let mut enc = ZlibEncoder::new(&mut entry, Default::default());
+    enc.write_all(data)
+        .and_then(|_| enc.finish().map(|_| ()))
+        .map_err(|e| format!("Could not compress pack entry: {:?}", e))?;
+    Ok(entry)
+}
+
+/// Writes a pack of the objects `shas`, each stored whole rather than as a delta, and its
+/// version 2 index to the repository's `objects/pack`, named after the pack's checksum as git
+/// names them. The index is moved into place last, so the pack is never seen without it.
+pub fn pack_write(repo: &Repo, shas: &[Oid]) -> Result<Pack, String> {
+    let mut shas = shas.to_vec();
+    shas.sort();
+    shas.dedup();
+    let mut entries = vec![];
+    for sha in shas {
+        let (kind, data) = object::object_read_raw(repo, &sha)?;
+        entries.push((sha, entry_encode(type_number(kind), &[], &data)?));
+    }
+    pack_write_entries(repo, &entries)
+}
+
+/// Like [`pack_write`], but the pack holds `entries`, each an object's sha and its encoded
+/// entry, in the order given.
+fn pack_write_entries(repo: &Repo, entries: &[(Oid, Vec<u8>)]) -> Result<Pack, String> {
+    let mut pack = b"PACK".to_vec();
+    pack.extend(&2u32.to_be_bytes());
+    pack.extend(&(entries.len() as u32).to_be_bytes());
+    let mut objects = vec![];
+    for (sha, entry) in entries {
+        let mut crc = Crc::new();
+        crc.update(entry);
+        objects.push((*sha, pack.len() as u64, crc.sum()));
+        pack.extend_from_slice(entry);
+    }
+    objects.sort();
+    let shas: Vec<Oid> = objects.iter().map(|o| o.0).collect();
+    let offsets: Vec<u64> = objects.iter().map(|o| o.1).collect();
+    let crcs: Vec<u32> = objects.iter().map(|o| o.2).collect();
+    let mut checksum = Sha1::new();
+    checksum.input(&pack);
+    let mut pack_checksum = [0; 20];
+    checksum.result(&mut pack_checksum);
+    pack.extend(&pack_checksum);
+
+    let mut idx = IDX_MAGIC.to_vec();
+    idx.extend(&2u32.to_be_bytes());
+    for first in 0..=255u8 {
+        let count = shas.partition_point(|sha| sha.as_bytes()[0] <= first);
+        idx.extend(&(count as u32).to_be_bytes());
+    }
+    shas.iter().for_each(|sha| idx.extend(sha.as_bytes()));
+    crcs.iter().for_each(|crc| idx.extend(&crc.to_be_bytes()));
+    // offsets that don't fit in 31 bits go in a table of 8 byte offsets after the rest
+    let mut large = vec![];
+    for offset in offsets.iter() {
+        if *offset < 0x8000_0000 {
+            idx.extend(&(*offset as u32).to_be_bytes());
+        } else {
+            idx.extend(&(0x8000_0000 | large.len() as u32).to_be_bytes());
+            large.push(*offset);
+        }
+    }
+    large
+        .iter()
+        .for_each(|offset| idx.extend(&offset.to_be_bytes()));
+    idx.extend(&pack_checksum);
+    let mut checksum = Sha1::new();
+    checksum.input(&idx);
+    let mut idx_checksum = [0; 20];
+    checksum.result(&mut idx_checksum);
+    idx.extend(&idx_checksum);
+
+    let dir = repo.object_dir().join("pack");
+    fs::create_dir_all(&dir).map_err(|e| format!("Could not create {:?}: {:?}", dir, e))?;
+    let name = format!("pack-{}", hex(&pack_checksum));
+    for (ext, data) in [("pack", &pack), ("idx", &idx)] {
+        let path = dir.join(format!("{}.{}", name, ext));
+        let tmp = dir.join(format!("tmp_{}.{}", name, ext));
+        fs::write(&tmp, data)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| format!("Could not write {:?}: {:?}", path, e))?;
+    }
+    repo.pack_cache().clear();
+    Pack::open(&dir.join(format!("{}.idx", name)))
+}
+
+/// What a repack did
+#[derive(Debug, Clone)]
+pub struct Repack {
+    /// The pack every object was written to
+    pub pack: Pack,
+    /// How many old packs and loose objects were removed
+    pub packs_removed: usize,
+    pub loose_removed: usize,
+}
+
+/// Packs every object in the repository's own object directory, loose or packed, into a
+/// single new pack. Objects only in alternates are left out.
+///
+/// With `delete`, the old packs and the loose objects the new pack holds are then removed,
+/// as `git repack -a -d` does. Before anything is removed the new pack is read back from
+/// disk and verified, and every object of every old pack and loose object to be removed is
+/// looked up in its index; if any is missing or the pack is damaged, nothing is removed.
+pub fn repack(repo: &Repo, delete: bool) -> Result<Repack, String> {
+    let old_packs: Vec<Pack> = pack_list(repo)?
+        .iter()
+        .filter(|pack| pack.path.starts_with(repo.object_dir()))
+        .cloned()
+        .collect();
+    let loose = object::object_list_loose(repo)?;
+    let mut shas = loose.clone();
+    for pack in old_packs.iter() {
+        shas.extend(pack.index.shas.iter().copied());
+    }
+    let new = pack_write(repo, &shas)?;
+    let mut out = Repack {
+        pack: new,
+        packs_removed: 0,
+        loose_removed: 0,
+    };
+    if !delete {
+        return Ok(out);
+    }
+
+    // check what was written, not what was meant to be
+    let written = Pack::open(&out.pack.path.with_extension("idx"))?;
+    if let Some((sha, problem)) = written.verify(repo)?.into_iter().next() {
+        return Err(match sha {
+            Some(sha) => format!("New pack {:?} is bad, {}: {}", written.path, sha, problem),
+            None => format!("New pack {:?} is bad: {}", written.path, problem),
+        });
+    }
+    // repacking an already packed repository can write a pack identical to an old one
+    let old_packs: Vec<&Pack> = old_packs
+        .iter()
+        .filter(|pack| pack.path != written.path)
+        .collect();
+    for pack in old_packs.iter() {
+        if let Some(sha) = pack
+            .index
+            .shas
+            .iter()
+            .find(|sha| written.index.find(sha).is_none())
+        {
+            return Err(format!(
+                "Not removing old packs: {} in {:?} is missing from the new pack {:?}",
+                sha, pack.path, written.path
+            ));
+        }
+    }
+    if let Some(sha) = loose.iter().find(|sha| written.index.find(sha).is_none()) {
+        return Err(format!(
+            "Not removing old packs: loose object {} is missing from the new pack {:?}",
+            sha, written.path
+        ));
+    }
+
+    repo.pack_cache().clear();
+    for pack in old_packs {
+        // the index first, so the pack is never left visible without its objects
+        for path in [pack.path.with_extension("idx"), pack.path.clone()] {
+            fs::remove_file(&path).map_err(|e| format!("Could not remove {:?}: {:?}", path, e))?;
+        }
+        out.packs_removed += 1;
+    }
+    for sha in loose {
+        let path = object::object_path(repo, &sha, false)?;
+        fs::remove_file(&path).map_err(|e| format!("Could not remove {:?}: {:?}", path, e))?;
+        out.loose_removed += 1;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempRepo;
+
+    /// A delta rebuilding `to` from `from`, which must be a prefix of it shorter than 256
+    /// bytes: the two sizes, a copy of all of `from`, then the rest of `to` inserted.
+    fn delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+        let mut delta = vec![];
+        for mut n in [from.len(), to.len()] {
+            while n >= 0x80 {
+                delta.push(n as u8 | 0x80);
+                n >>= 7;
+            }
+            delta.push(n as u8);
+        }
+        // a copy from offset 0, so only its one byte of size follows
+        delta.extend([0x90, from.len() as u8]);
+        for chunk in to[from.len()..].chunks(0x7f) {
+            delta.push(chunk.len() as u8);
+            delta.extend(chunk);
+        }
+        delta
+    }
+
+    #[test]
+    fn size_of_a_delta_matches_its_contents() {
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let base = b"hello world\n".to_vec();
+        let ofs = [&base[..], &[b'x'; 200]].concat();
+        let refd = [&ofs[..], b"and more\n"].concat();
+        let sha = |data: &[u8]| object::object_sha(ObjectType::Blob, data);
+
+        let base_entry = entry_encode(OBJ_BLOB, &[], &base).unwrap();
+        // the distance back to the base, under 128 so a single byte
+        let distance = [base_entry.len() as u8];
+        let ofs_entry = entry_encode(OBJ_OFS_DELTA, &distance, &delta(&base, &ofs)).unwrap();
+        let ref_entry =
+            entry_encode(OBJ_REF_DELTA, sha(&ofs).as_bytes(), &delta(&ofs, &refd)).unwrap();
+        let entries = [
+            (sha(&base), base_entry),
+            (sha(&ofs), ofs_entry),
+            (sha(&refd), ref_entry),
+        ];
+        pack_write_entries(&repo, &entries).unwrap();
+
+        for data in [&base, &ofs, &refd] {
+            let sha = sha(data);
+            assert_eq!(object::object_read_raw(&repo, &sha).unwrap().1, *data);
+            assert_eq!(object::object_size(&repo, &sha).unwrap(), data.len());
+        }
+    }
+
+    /// Writes a pack of new blobs, removing their loose copies, and returns their shas.
+    fn packed_blobs(t: &TempRepo, tag: &str) -> Vec<Oid> {
+        let repo = t.repo();
+        let shas: Vec<Oid> = (0..3)
+            .map(|i| t.blob(&format!("{} {}\n", tag, i)))
+            .collect();
+        pack_write(&repo, &shas).unwrap();
+        for sha in shas.iter() {
+            fs::remove_file(object::object_path(&repo, sha, false).unwrap()).unwrap();
+        }
+        shas
+    }
+
+    #[test]
+    fn repack_keeps_old_packs_unless_the_new_one_holds_everything() {
+        let t = TempRepo::new();
+        let mut shas = packed_blobs(&t, "first");
+        shas.extend(packed_blobs(&t, "second"));
+        shas.push(t.blob("loose\n"));
+        let old: Vec<PathBuf> = pack_list(&t.repo())
+            .unwrap()
+            .iter()
+            .map(|pack| pack.path.clone())
+            .collect();
+        assert_eq!(old.len(), 2);
+
+        // an object of the first pack that can't be read back, so can't be repacked
+        let damaged = Pack::open(&old[0].with_extension("idx")).unwrap();
+        let offset = damaged.index.offsets[0] as usize;
+        let intact = fs::read(&old[0]).unwrap();
+        let mut data = intact.clone();
+        // past the entry's header, into its compressed contents
+        data[offset + 4..offset + 8].copy_from_slice(b"\xde\xad\xbe\xef");
+        fs::write(&old[0], &data).unwrap();
+        assert!(repack(&t.repo(), true).is_err());
+        for path in old.iter() {
+            assert!(
+                path.exists() && path.with_extension("idx").exists(),
+                "{:?}",
+                path
+            );
+        }
+        let loose = object::object_path(&t.repo(), &shas[6], false).unwrap();
+        assert!(loose.exists());
+
+        fs::write(&old[0], intact).unwrap();
+        let repo = t.repo();
+        let done = repack(&repo, true).unwrap();
+        assert_eq!((done.packs_removed, done.loose_removed), (2, 1));
+        assert!(old.iter().all(|path| !path.exists()));
+        assert!(!loose.exists());
+        let repo = t.repo();
+        for sha in shas.iter() {
+            assert!(done.pack.index.find(sha).is_some(), "{}", sha);
+            object::object_read_raw(&repo, sha).unwrap();
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::repository::Repo;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Cleared by `--no-pager`
+static PAGER_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns paging on or off for the rest of the process.
+pub fn pager_set_enabled(enabled: bool) {
+    PAGER_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// The pager to run, as git picks it: `GIT_PAGER`, then `core.pager`, then `PAGER`, then
+/// `less`. An empty command or `cat` means not to page at all.
+fn pager_command(repo: &Repo) -> Option<String> {
+    let command = std::env::var("GIT_PAGER")
+        .ok()
+        .or_else(|| repo.config_value("core.pager"))
+        .or_else(|| std::env::var("PAGER").ok())
+        .unwrap_or_else(|| "less".to_owned());
+    match command.trim() {
+        "" | "cat" => None,
+        _ => Some(command),
+    }
+}
+
+/// The pager to send output to stdout through, if any: none when paging is disabled or stdout
+/// isn't a `terminal`.
+fn pager_wanted(repo: &Repo, terminal: bool) -> Option<String> {
+    if !PAGER_ENABLED.load(Ordering::Relaxed) || !terminal {
+        return None;
+    }
+    pager_command(repo)
+}
+
+/// Where a command with possibly long output writes it: through a pager when stdout is a
+/// terminal and paging is enabled, and straight to stdout otherwise. Once the pager quits,
+/// further output is thrown away rather than failing the command.
+pub struct Pager {
+    child: Option<Child>,
+    stdout: io::Stdout,
+    closed: bool,
+}
+
+impl Pager {
+    /// Starts the pager for output from a command in `repo`, if one should be used.
+    pub fn start(repo: &Repo) -> Self {
+        let mut pager = Pager {
+            child: None,
+            stdout: io::stdout(),
+            closed: false,
+        };
+        let command = match pager_wanted(repo, pager.stdout.is_terminal()) {
+            Some(command) => command,
+            None => return pager,
+        };
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(&command).stdin(Stdio::piped());
+        // git's defaults: quit if the output fits on screen, and pass colors through
+        for (var, value) in [("LESS", "FRX"), ("LV", "-c")] {
+            if std::env::var_os(var).is_none() {
+                cmd.env(var, value);
+            }
+        }
+        match cmd.spawn() {
+            Ok(child) => pager.child = Some(child),
+            Err(e) => warn!("Could not start pager {:?}: {:?}", command, e),
+        }
+        pager
+    }
+
+    /// Whether output is going to a pager rather than straight to stdout.
+    pub fn is_paging(&self) -> bool {
+        self.child.is_some()
+    }
+
+    fn output(&mut self) -> &mut dyn Write {
+        match self.child.as_mut().and_then(|child| child.stdin.as_mut()) {
+            Some(stdin) => stdin,
+            None => &mut self.stdout,
+        }
+    }
+
+    fn check_closed<T>(&mut self, result: io::Result<T>, closed: T) -> io::Result<T> {
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(closed)
+            }
+            result => result,
+        }
+    }
+}
+
+impl Write for Pager {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Ok(buf.len());
+        }
+        let result = self.output().write(buf);
+        self.check_closed(result, buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let result = self.output().flush();
+        self.check_closed(result, ())
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        if let Some(mut child) = self.child.take() {
+            // closing its input lets the pager see the end of the output
+            drop(child.stdin.take());
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempRepo;
+
+    #[test]
+    fn output_goes_straight_to_stdout_without_a_terminal_or_with_no_pager() {
+        let t = TempRepo::new();
+        let marker = t.sibling("paged");
+        let pager = format!("touch '{}'; cat", marker.display());
+        std::env::set_var("GIT_PAGER", &pager);
+        let repo = t.repo();
+        let on_terminal = pager_wanted(&repo, true);
+        let off_terminal = pager_wanted(&repo, false);
+        pager_set_enabled(false);
+        let disabled = pager_wanted(&repo, true);
+        let started = Pager::start(&repo);
+        let paging = started.is_paging();
+        drop(started);
+        pager_set_enabled(true);
+        std::env::remove_var("GIT_PAGER");
+
+        assert_eq!(on_terminal, Some(pager));
+        assert_eq!(off_terminal, None);
+        assert_eq!(disabled, None);
+        assert!(!paging);
+        assert!(!marker.exists(), "the pager was run");
+
+        t.config("core.pager", "cat");
+        assert_eq!(pager_wanted(&t.repo(), true), None);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::lockfile::LockFile;
+use crate::object::Oid;
+use crate::repository::Repo;
+use std::fs;
+use std::path::Path;
+
+const SYMREF_PREFIX: &str = "ref: ";
+
+/// Reads the raw contents of a ref, either a sha or `ref: <target>`. Falls back to
+/// `packed-refs` for refs without a loose file.
+fn ref_read(repo: &Repo, name: &str) -> Result<Option<String>, String> {
+    let path = repo.gitdir().join(name);
+    if path.is_file() {
+        let data = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read ref \"{}\": {:?}", name, e))?;
+        return Ok(Some(data.trim_end().to_owned()));
+    }
+
+    Ok(packed_refs(repo)?
+        .into_iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, sha)| sha))
+}
+
+/// Resolves a ref such as `HEAD` or `refs/heads/master` to the sha it points at,
+/// following symbolic refs. Returns `None` if the ref does not exist.
+pub fn ref_resolve(repo: &Repo, name: &str) -> Result<Option<Oid>, String> {
+    let mut name = name.to_owned();
+    // bound the number of symbolic refs followed, in case of a cycle
+    for _ in 0..5 {
+        match ref_read(repo, &name)? {
+            Some(data) => match data.strip_prefix(SYMREF_PREFIX) {
+                Some(target) => name = target.to_owned(),
+                None => {
+                    return Oid::from_hex(&data)
+                        .map(Some)
+                        .map_err(|e| format!("Bad ref \"{}\": {}", name, e))
+                }
+            },
+            None => return Ok(None),
+        }
+    }
+    Err(format!("Too many levels of symbolic refs at \"{}\"", name))
+}
+
+/// The full name of the ref `name` stands for, such as `refs/remotes/origin/main` for
+/// `origin/main`: the first of `name`, `refs/<name>`, `refs/tags/<name>`, `refs/heads/<name>`
+/// and `refs/remotes/<name>` that exists. Returns `None` if none does.
+pub fn ref_dwim(repo: &Repo, name: &str) -> Result<Option<String>, String> {
+    // like git, only all caps names such as `HEAD` or `ORIG_HEAD` are looked up directly
+    // in the git dir, so `config` can't be mistaken for a ref
+    let direct =
+        name.starts_with("refs/") || name.chars().all(|c| c.is_ascii_uppercase() || c == '_');
+    let search = ["refs/", "refs/tags/", "refs/heads/", "refs/remotes/"];
+    let ref_names = direct
+        .then(|| name.to_owned())
+        .into_iter()
+        .chain(search.iter().map(|prefix| format!("{}{}", prefix, name)));
+    for ref_name in ref_names {
+        if ref_resolve(repo, &ref_name)?.is_some() {
+            return Ok(Some(ref_name));
+        }
+    }
+    Ok(None)
+}
+
+/// If `name` is a symbolic ref, returns the ref it points to.
+pub fn ref_symbolic_target(repo: &Repo, name: &str) -> Result<Option<String>, String> {
+    Ok(ref_read(repo, name)?.and_then(|data| data.strip_prefix(SYMREF_PREFIX).map(str::to_owned)))
+}
+
+fn packed_refs(repo: &Repo) -> Result<Vec<(String, String)>, String> {
+    let path = repo.gitdir().join("packed-refs");
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let data =
+        fs::read_to_string(&path).map_err(|e| format!("Could not read packed-refs: {:?}", e))?;
+
+    Ok(data
+        .lines()
+        // skip the header and peeled `^<sha>` lines
+        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+        .filter_map(|line| {
+            let (sha, name) = line.split_once(' ')?;
+            Some((name.to_owned(), sha.to_owned()))
+        })
+        .collect())
+}
+
+fn ref_list_dir(repo: &Repo, dir: &Path, out: &mut Vec<(String, Oid)>) -> Result<(), String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Could not read ref directory {:?}: {:?}", dir, e))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("Could not read ref directory {:?}: {:?}", dir, e))?
+            .path();
+        if path.is_dir() {
+            ref_list_dir(repo, &path, out)?;
+        } else {
+            let name = path
+                .strip_prefix(repo.gitdir())
+                .map_err(|e| format!("Ref {:?} outside of the repository: {:?}", path, e))?
+                .to_string_lossy()
+                .into_owned();
+            if let Some(sha) = ref_resolve(repo, &name)? {
+                out.push((name, sha));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lists every ref under `refs/` with the sha it resolves to, sorted by name.
+pub fn ref_list(repo: &Repo) -> Result<Vec<(String, Oid)>, String> {
+    let mut out = vec![];
+    let refs_dir = repo.gitdir().join("refs");
+    if refs_dir.is_dir() {
+        ref_list_dir(repo, &refs_dir, &mut out)?;
+    }
+    for (name, sha) in packed_refs(repo)? {
+        if !out.iter().any(|(n, _)| *n == name) {
+            let sha = Oid::from_hex(&sha).map_err(|e| format!("Bad ref \"{}\": {}", name, e))?;
+            out.push((name, sha));
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+fn ref_write(repo: &Repo, name: &str, contents: &str) -> Result<(), String> {
+    let path = repo.repo_file(name, true)?;
+    LockFile::acquire(&path)?
+        .commit(format!("{}\n", contents).as_bytes())
+        .map_err(|e| format!("Could not write ref \"{}\": {}", name, e))
+}
+
+/// Points the ref `name` at `sha`, creating it if needed.
+pub fn ref_update(repo: &Repo, name: &str, sha: &Oid) -> Result<(), String> {
+    ref_write(repo, name, &sha.to_hex())
+}
+
+/// Makes `name` a symbolic ref pointing at the ref `target`.
+pub fn ref_set_symbolic(repo: &Repo, name: &str, target: &str) -> Result<(), String> {
+    ref_write(repo, name, &format!("{}{}", SYMREF_PREFIX, target))
+}
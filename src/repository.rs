@@ -1,4 +1,8 @@
+use crate::config;
+use crate::object::{self, HaveSet, ObjectCache, Oid};
+use crate::pack::PackCache;
 use ini::Ini;
+use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -7,20 +11,70 @@ pub struct Repo {
     worktree: PathBuf,
     gitdir: PathBuf,
     conf: Ini,
+    object_dir: PathBuf,
+    alternates: Vec<PathBuf>,
+    objects: ObjectCache,
+    packs: PackCache,
+}
+
+/// Overrides where objects are read from and written to, instead of `.git/objects`
+const OBJECT_DIRECTORY_ENV: &str = "GIT_OBJECT_DIRECTORY";
+/// Extra object directories to read objects from, separated like `PATH`
+const ALTERNATE_OBJECT_DIRECTORIES_ENV: &str = "GIT_ALTERNATE_OBJECT_DIRECTORIES";
+
+/// The object directories `object_dir` borrows objects from: those in the environment, then
+/// those listed in its `info/alternates` file, relative to `object_dir`.
+fn alternates_read(object_dir: &Path) -> Vec<PathBuf> {
+    let mut out: Vec<PathBuf> = std::env::var_os(ALTERNATE_OBJECT_DIRECTORIES_ENV)
+        .map(|dirs| std::env::split_paths(&dirs).collect())
+        .unwrap_or_default();
+    if let Ok(data) = fs::read_to_string(object_dir.join("info/alternates")) {
+        out.extend(
+            data.lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| object_dir.join(l)),
+        );
+    }
+    out.retain(|dir| !dir.as_os_str().is_empty() && dir != object_dir);
+    out
 }
 
 impl Repo {
     pub fn new(path: PathBuf, force: bool) -> Result<Self, String> {
         trace!("Repo::new, {:?}", path);
         let gitdir = path.join(".git");
+        Self::open(path, gitdir, force)
+    }
+
+    /// Opens the repository whose git directory is `path` itself, as a bare repository is laid
+    /// out. Its work tree is reported as `path` too, but commands needing one should first
+    /// check [`Repo::require_worktree`].
+    pub fn new_bare(path: PathBuf, force: bool) -> Result<Self, String> {
+        trace!("Repo::new_bare, {:?}", path);
+        Self::open(path.clone(), path, force)
+    }
+
+    fn open(path: PathBuf, gitdir: PathBuf, force: bool) -> Result<Self, String> {
+        let object_dir = match std::env::var_os(OBJECT_DIRECTORY_ENV) {
+            Some(dir) if !dir.is_empty() => std::env::current_dir()
+                .map_err(|e| format!("Could not get the current directory: {:?}", e))?
+                .join(dir),
+            _ => gitdir.join("objects"),
+        };
+        let alternates = alternates_read(&object_dir);
 
         let mut repo = Self {
             worktree: path,
             gitdir,
             conf: Ini::default(),
+            object_dir,
+            alternates,
+            objects: ObjectCache::default(),
+            packs: PackCache::default(),
         };
         let config_path = repo.repo_file("config", false);
-        match config_path.and_then(|c| Ini::load_from_file(c).map_err(|e| e.to_string())) {
+        match config_path.and_then(|c| config::config_load(&c, Some(&repo.gitdir))) {
             Ok(ini) => repo.conf = ini,
             Err(_) if force => (),
             Err(e) => return Err(format!("Failed to create repository object: {}", e)),
@@ -32,7 +86,7 @@ impl Repo {
                 .conf
                 .get_from(Some("core"), "repositoryformatversion")
                 .and_then(|v| v.parse::<usize>().ok())
-                .ok_or_else(|| format!("Could not get repo format version"))?;
+                .ok_or_else(|| "Could not get repo format version".to_string())?;
 
             if version != 0 {
                 return Err(format!("Unsupported repo version found: {}", version));
@@ -42,6 +96,123 @@ impl Repo {
         Ok(repo)
     }
 
+    /// The repository's local config, `.git/config`, with includes resolved
+    pub fn config(&self) -> &Ini {
+        &self.conf
+    }
+
+    /// Looks up `name`, e.g. `user.name`, in the local config and then the global one.
+    pub fn config_value(&self, name: &str) -> Option<String> {
+        config::config_get(&self.conf, name)
+            .map(str::to_owned)
+            .or_else(|| {
+                let global = config::config_global(Some(&self.gitdir)).ok()?;
+                config::config_get(&global, name).map(str::to_owned)
+            })
+    }
+
+    /// Whether `core.bare` says the repository has no work tree.
+    pub fn is_bare(&self) -> bool {
+        config::config_get(&self.conf, "core.bare")
+            .map(|v| {
+                matches!(
+                    v.trim().to_lowercase().as_str(),
+                    "true" | "yes" | "on" | "1"
+                )
+            })
+            .unwrap_or(false)
+    }
+
+    /// Fails for a bare repository, for commands that read or change the work tree or the
+    /// index describing it.
+    pub fn require_worktree(&self) -> Result<(), String> {
+        if self.is_bare() {
+            Err("this operation must be run in a work tree".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The objects read and written through this handle so far.
+    pub fn object_cache(&self) -> &ObjectCache {
+        &self.objects
+    }
+
+    /// The packs found through this handle so far.
+    pub fn pack_cache(&self) -> &PackCache {
+        &self.packs
+    }
+
+    /// Every object in the repository, loose or packed, including those in alternates.
+    pub fn have_set(&self) -> Result<HaveSet, String> {
+        object::HaveSet::load(self)
+    }
+
+    pub fn worktree(&self) -> &Path {
+        &self.worktree
+    }
+
+    pub fn gitdir(&self) -> &Path {
+        &self.gitdir
+    }
+
+    /// Where new objects are written: `.git/objects`, unless `GIT_OBJECT_DIRECTORY` is set
+    pub fn object_dir(&self) -> &Path {
+        &self.object_dir
+    }
+
+    /// Every directory objects are read from: the object directory, then its alternates from
+    /// `GIT_ALTERNATE_OBJECT_DIRECTORIES` and `info/alternates`
+    pub fn object_dirs(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.object_dir.as_path())
+            .chain(self.alternates.iter().map(|p| p.as_path()))
+    }
+
+    /// Runs `f` against a copy of the repository whose new objects go to a temporary
+    /// quarantine directory, while existing objects stay readable through it as an alternate.
+    /// If `f` succeeds the quarantined objects are moved into the object directory, and if it
+    /// fails they are thrown away, so a failed import leaves no partial objects behind.
+    pub fn with_quarantine<T, F>(&self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&Repo) -> Result<T, String>,
+    {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let incoming = self.object_dir.join(format!(
+            "tmp_objdir-incoming-{}-{}",
+            std::process::id(),
+            nanos
+        ));
+        fs::create_dir_all(&incoming)
+            .map_err(|e| format!("Could not create quarantine {:?}: {:?}", incoming, e))?;
+
+        let quarantine = Repo {
+            worktree: self.worktree.clone(),
+            gitdir: self.gitdir.clone(),
+            conf: self.conf.clone(),
+            object_dir: incoming.clone(),
+            alternates: self.object_dirs().map(Path::to_owned).collect(),
+            // objects written to the quarantine must not be cached for the real repository
+            // until they have been moved into it
+            objects: ObjectCache::default(),
+            packs: PackCache::default(),
+        };
+        let result = f(&quarantine).and_then(|value| {
+            quarantine_migrate(&incoming, &self.object_dir)?;
+            self.packs.clear();
+            Ok(value)
+        });
+
+        let cleanup = fs::remove_dir_all(&incoming)
+            .map_err(|e| format!("Could not remove quarantine {:?}: {:?}", incoming, e));
+        // the import's own error explains more than a failure to clean up after it
+        let value = result?;
+        cleanup?;
+        Ok(value)
+    }
+
     fn repo_path<P>(&self, path: P) -> PathBuf
     where
         P: AsRef<Path>,
@@ -83,12 +254,50 @@ impl Repo {
     }
 }
 
-fn repo_default_config() -> Ini {
+/// Moves the loose objects and packs in the quarantine `incoming` into `object_dir`. Pack
+/// indexes are moved after their packs, so a pack is never visible without its contents.
+fn quarantine_migrate(incoming: &Path, object_dir: &Path) -> Result<(), String> {
+    let read_dir = |dir: &Path| -> Result<Vec<PathBuf>, String> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir).map_err(|e| format!("Could not read {:?}: {:?}", dir, e))? {
+            paths.push(
+                entry
+                    .map_err(|e| format!("Could not read {:?}: {:?}", dir, e))?
+                    .path(),
+            );
+        }
+        paths.sort_by_key(|p| p.extension() == Some("idx".as_ref()));
+        Ok(paths)
+    };
+    let move_file = |from: &Path, to_dir: &Path| -> Result<(), String> {
+        let to = to_dir.join(from.file_name().unwrap_or_default());
+        // objects are named by their contents, so an existing one is already identical
+        if to.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(to_dir)
+            .map_err(|e| format!("Could not create {:?}: {:?}", to_dir, e))?;
+        fs::rename(from, &to).map_err(|e| format!("Could not move {:?}: {:?}", from, e))
+    };
+
+    for dir in read_dir(incoming)? {
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        let is_fanout = name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit());
+        if dir.is_dir() && (is_fanout || name == "pack") {
+            for file in read_dir(&dir)? {
+                move_file(&file, &object_dir.join(&*name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn repo_default_config(bare: bool) -> Ini {
     let mut ret = Ini::new();
     ret.with_section(Some("core".to_owned()))
         .set("repositoryformatversion", "0")
         .set("filemode", "false")
-        .set("bare", "false");
+        .set("bare", bare.to_string());
 
     ret
 }
@@ -99,8 +308,21 @@ where
 {
     let pb: PathBuf = path.as_ref().to_owned();
     trace!("repo_create: {:?}", &pb);
+    repo_create_in(Repo::new(pb, true)?, false)
+}
+
+/// Creates an empty bare repository at `path`, which holds what would otherwise be in `.git`.
+pub fn repo_create_bare<P>(path: P) -> Result<Repo, String>
+where
+    P: AsRef<Path>,
+{
+    let pb: PathBuf = path.as_ref().to_owned();
+    trace!("repo_create_bare: {:?}", &pb);
+    repo_create_in(Repo::new_bare(pb, true)?, true)
+}
 
-    let repo = Repo::new(pb, true)?;
+/// Lays out a new repository for `repo`, whose directory must be empty or not exist yet.
+fn repo_create_in(repo: Repo, bare: bool) -> Result<Repo, String> {
     trace!("REPO CREATED");
 
     if repo.worktree.exists() {
@@ -119,7 +341,8 @@ where
             .map_err(|e| format!("Could not create directories: {:?}", e))?;
     }
     repo.repo_dir("branches", true)?;
-    repo.repo_dir("objects", true)?;
+    fs::create_dir_all(repo.object_dir())
+        .map_err(|e| format!("Could not create object directory: {:?}", e))?;
     repo.repo_dir("refs/tags", true)?;
     repo.repo_dir("refs/heads", true)?;
 
@@ -149,7 +372,7 @@ where
 
     {
         let config_file_path = repo.repo_file("config", false)?;
-        let config = repo_default_config();
+        let config = repo_default_config(bare);
         config
             .write_to_file(config_file_path)
             .expect("write config to FS");
@@ -158,7 +381,7 @@ where
     Ok(repo)
 }
 
-pub fn repo_find<P>(path: Option<P>, required: bool) -> Result<Repo, String>
+pub fn repo_find<P>(path: Option<P>, _required: bool) -> Result<Repo, String>
 where
     P: AsRef<Path>,
 {
@@ -176,10 +399,117 @@ where
     if with_git.is_dir() {
         return Repo::new(pb, false);
     }
+    // a bare repository is its own git directory; the `.git` of a repository with a work
+    // tree is skipped so the search goes on up to the work tree
+    if pb.join("HEAD").is_file() && pb.join("objects").is_dir() && pb.join("refs").is_dir() {
+        let repo = Repo::new_bare(pb.clone(), false)?;
+        if repo.is_bare() {
+            return Ok(repo);
+        }
+    }
 
     if pb.pop() {
-        repo_find(Some(pb), required)
+        repo_find(Some(pb), _required)
     } else {
         Err("At root, could not find git repo".to_owned())
     }
 }
+
+/// Reads the commits listed in `.git/shallow`. These are the boundary of a shallow clone:
+/// their parents are not present in the repository.
+pub fn shallow_read(repo: &Repo) -> Result<HashSet<Oid>, String> {
+    let path = repo.repo_path("shallow");
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+    let data =
+        fs::read_to_string(&path).map_err(|e| format!("Could not read shallow file: {:?}", e))?;
+    data.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| Oid::from_hex(l).map_err(|e| format!("Bad shallow file: {}", e)))
+        .collect()
+}
+
+/// Writes the shallow boundary, `.git/shallow`, one sha per line.
+pub fn shallow_write(repo: &Repo, boundary: &[Oid]) -> Result<(), String> {
+    if boundary.is_empty() {
+        return Ok(());
+    }
+    let mut boundary = boundary.to_vec();
+    boundary.sort();
+    let data: String = boundary.iter().map(|sha| format!("{}\n", sha)).collect();
+    fs::write(repo.repo_path("shallow"), data)
+        .map_err(|e| format!("Could not write shallow file: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::ObjectType;
+    use crate::pack;
+    use crate::test_util::TempRepo;
+
+    /// Writes a loose blob and a pack holding another, returning both shas.
+    fn import(repo: &Repo, tag: &str) -> Result<Vec<Oid>, String> {
+        let write = |contents: String| {
+            object::object_write_raw(Some(repo), ObjectType::Blob, contents.as_bytes())
+        };
+        let loose = write(format!("loose {}", tag))?;
+        let packed = object::object_sha(ObjectType::Blob, format!("packed {}", tag).as_bytes());
+        write(format!("packed {}", tag))?;
+        pack::pack_write(repo, &[packed])?;
+        fs::remove_file(object::object_path(repo, &packed, false)?)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(vec![loose, packed])
+    }
+
+    fn leftover_quarantines(repo: &Repo) -> usize {
+        fs::read_dir(repo.object_dir())
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("tmp_objdir-incoming-")
+            })
+            .count()
+    }
+
+    #[test]
+    fn failed_import_leaves_the_store_untouched() {
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let mut written = vec![];
+        let err = repo
+            .with_quarantine(|quarantine| -> Result<(), String> {
+                written = import(quarantine, "failed")?;
+                assert!(written
+                    .iter()
+                    .all(|sha| object::object_exists(quarantine, sha)));
+                Err("import failed".to_owned())
+            })
+            .unwrap_err();
+        assert_eq!(err, "import failed");
+
+        let repo = t.repo();
+        assert_eq!(written.len(), 2);
+        assert!(written.iter().all(|sha| !object::object_exists(&repo, sha)));
+        assert!(object::object_list(&repo).unwrap().is_empty());
+        assert_eq!(leftover_quarantines(&repo), 0);
+    }
+
+    #[test]
+    fn successful_import_migrates_every_object() {
+        let t = TempRepo::new();
+        let repo = t.repo();
+        let written = repo.with_quarantine(|q| import(q, "kept")).unwrap();
+        assert!(written.iter().all(|sha| object::object_exists(&repo, sha)));
+
+        let repo = t.repo();
+        assert!(written.iter().all(|sha| object::object_exists(&repo, sha)));
+        let mut expected = written.clone();
+        expected.sort();
+        assert_eq!(object::object_list(&repo).unwrap(), expected);
+        assert_eq!(pack::pack_list(&repo).unwrap().len(), 1);
+        assert_eq!(leftover_quarantines(&repo), 0);
+    }
+}
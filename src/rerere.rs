@@ -0,0 +1,61 @@
+use crate::object::Oid;
+use crate::repository::Repo;
+use crypto::{digest::Digest, sha1::Sha1};
+use std::fs;
+
+/// The directory resolutions are recorded in, one subdirectory per conflict
+const RR_CACHE: &str = "rr-cache";
+
+/// Whether `rerere.enabled` turns on recording and reusing resolutions.
+pub fn rerere_enabled(repo: &Repo) -> bool {
+    repo.config_value("rerere.enabled")
+        .map(|v| {
+            matches!(
+                v.trim().to_lowercase().as_str(),
+                "true" | "yes" | "on" | "1"
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Identifies a conflict by the blobs of its base, ours and theirs sides, any of which may be
+/// absent. Ours and theirs are taken in sorted order, so the same conflict met with the sides
+/// swapped, as when a merge is redone as a rebase, has the same id.
+pub fn conflict_id(sides: [Option<&Oid>; 3]) -> String {
+    let none = "0".repeat(40);
+    let hex = |side: Option<&Oid>| side.map_or(none.clone(), |sha| sha.to_hex());
+    let mut pair = [hex(sides[1]), hex(sides[2])];
+    pair.sort();
+    let mut sha = Sha1::new();
+    sha.input_str(&format!("{}\n{}\n{}\n", hex(sides[0]), pair[0], pair[1]));
+    sha.result_str()
+}
+
+/// Records `resolution` as the contents that resolve the conflict between `sides`, in
+/// `.git/rr-cache/<id>/postimage`.
+pub fn rerere_record(
+    repo: &Repo,
+    sides: [Option<&Oid>; 3],
+    resolution: &[u8],
+) -> Result<(), String> {
+    let path = repo.repo_file(
+        format!("{}/{}/postimage", RR_CACHE, conflict_id(sides)),
+        true,
+    )?;
+    fs::write(&path, resolution).map_err(|e| format!("Could not write {:?}: {:?}", path, e))
+}
+
+/// The contents recorded as resolving the conflict between `sides`, if it was resolved before.
+pub fn rerere_lookup(repo: &Repo, sides: [Option<&Oid>; 3]) -> Result<Option<Vec<u8>>, String> {
+    let path = repo
+        .gitdir()
+        .join(RR_CACHE)
+        .join(conflict_id(sides))
+        .join("postimage");
+    if !path.is_file() {
+        return Ok(None);
+    }
+    fs::read(&path)
+        .map(Some)
+        .map_err(|e| format!("Could not read {:?}: {:?}", path, e))
+}
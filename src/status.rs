@@ -0,0 +1,195 @@
+use crate::index::{self, Index, IndexEntry};
+use crate::object::{self, ObjectType, Oid, MODE_GITLINK};
+use crate::repository::Repo;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+/// A path that differs between `HEAD`, the index and the work tree, with the letters `git
+/// status --short` shows for it: how the index differs from `HEAD`, then how the work tree
+/// differs from the index, each a space if it doesn't. Untracked paths are `??`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusEntry {
+    pub path: String,
+    pub index: char,
+    pub worktree: char,
+}
+
+/// Hashes the work tree file at the repo-relative `path` as a blob, or for a symlink its
+/// target, writing the blob to `repo` if `write` is set. `meta` should come from
+/// `symlink_metadata`.
+pub fn worktree_hash(
+    repo: &Repo,
+    path: &str,
+    meta: &fs::Metadata,
+    write: bool,
+) -> Result<Oid, String> {
+    let full = repo.worktree().join(object::from_git_path(path));
+    let store = if write {
+        Some(repo as &dyn object::ObjectStore)
+    } else {
+        None
+    };
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(&full)
+            .map_err(|e| format!("Could not read symlink {:?}: {:?}", full, e))?;
+        object::object_hash(
+            &mut target.to_string_lossy().as_bytes(),
+            ObjectType::Blob,
+            store,
+        )
+    } else {
+        let mut f =
+            fs::File::open(&full).map_err(|e| format!("Could not open {:?}: {:?}", full, e))?;
+        object::object_hash(&mut f, ObjectType::Blob, store)
+    }
+}
+
+/// How the work tree file for `entry` differs from it: `D` if it is gone, `M` if its contents
+/// or mode changed. The file is only hashed if its stat data no longer matches the entry.
+fn worktree_change(repo: &Repo, entry: &IndexEntry) -> Result<char, String> {
+    let full = repo.worktree().join(object::from_git_path(&entry.path));
+    let meta = match fs::symlink_metadata(&full) {
+        Ok(meta) if !meta.is_dir() => meta,
+        _ => return Ok('D'),
+    };
+    let current = IndexEntry::from_metadata(&entry.path, &entry.sha, &meta);
+    if current.mode != entry.mode {
+        return Ok('M');
+    }
+    if current.size == entry.size && current.mtime == entry.mtime {
+        return Ok(' ');
+    }
+    match worktree_hash(repo, &entry.path, &meta, false)? == entry.sha {
+        true => Ok(' '),
+        false => Ok('M'),
+    }
+}
+
+/// The short status letters of a conflicted path, from which of its base, ours and theirs
+/// stages are present.
+fn unmerged_code(stages: [bool; 3]) -> [char; 2] {
+    match stages {
+        [true, false, false] => ['D', 'D'],
+        [false, true, false] => ['A', 'U'],
+        [true, true, false] => ['U', 'D'],
+        [false, false, true] => ['U', 'A'],
+        [true, false, true] => ['D', 'U'],
+        [false, true, true] => ['A', 'A'],
+        _ => ['U', 'U'],
+    }
+}
+
+/// Collects the untracked paths below the repo-relative directory `dir`. A directory holding
+/// no tracked files is given as a whole, as `<dir>/`, and only if it holds any file at all.
+fn untracked(
+    repo: &Repo,
+    tracked: &BTreeSet<&str>,
+    dir: &str,
+    out: &mut Vec<String>,
+) -> Result<(), String> {
+    let full = repo.worktree().join(object::from_git_path(dir));
+    let mut names = vec![];
+    for entry in
+        fs::read_dir(&full).map_err(|e| format!("Could not read dir {:?}: {:?}", full, e))?
+    {
+        let entry = entry.map_err(|e| format!("Could not read dir {:?}: {:?}", full, e))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name != ".git" {
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            names.push((name, is_dir));
+        }
+    }
+    names.sort();
+    for (name, is_dir) in names {
+        let path = if dir.is_empty() {
+            name
+        } else {
+            format!("{}/{}", dir, name)
+        };
+        if !is_dir {
+            if !tracked.contains(path.as_str()) {
+                out.push(path);
+            }
+            continue;
+        }
+        let prefix = format!("{}/", path);
+        let has_tracked = tracked
+            .range(prefix.as_str()..)
+            .next()
+            .is_some_and(|t| t.starts_with(&prefix));
+        if has_tracked {
+            untracked(repo, tracked, &path, out)?;
+        } else {
+            let mut inside = vec![];
+            untracked(repo, tracked, &path, &mut inside)?;
+            if !inside.is_empty() {
+                out.push(prefix);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compares the index against `head`, the tree of `HEAD` (`None` for an unborn branch), and the work
+/// tree against the index, listing each path that differs, as `git status --short` does.
+/// Tracked paths come first in path order, then untracked ones. `.gitignore` files are not
+/// read, so every untracked path is listed.
+pub fn status(repo: &Repo, head: Option<&Oid>, index: &Index) -> Result<Vec<StatusEntry>, String> {
+    let mut head_leaves = BTreeMap::new();
+    if let Some(tree) = head {
+        object::tree_read(repo, tree)?.walk(repo, "", &mut |path, leaf| {
+            head_leaves.insert(path.to_owned(), (leaf.mode, leaf.sha));
+            Ok(())
+        })?;
+    }
+
+    let mut codes: BTreeMap<String, [char; 2]> = BTreeMap::new();
+    for (path, stages) in index::index_unmerged(index) {
+        codes.insert(path.to_owned(), unmerged_code(stages.map(|s| s.is_some())));
+    }
+    for entry in index.entries.iter().filter(|e| e.stage() == 0) {
+        let staged = if entry.intent_to_add() {
+            ' '
+        } else {
+            match head_leaves.get(&entry.path) {
+                None => 'A',
+                Some(leaf) if *leaf != (index::tree_mode(entry.mode), entry.sha) => 'M',
+                Some(_) => ' ',
+            }
+        };
+        let changed = if entry.intent_to_add() {
+            'A'
+        } else if index::tree_mode(entry.mode) == MODE_GITLINK {
+            // submodules aren't looked into
+            ' '
+        } else {
+            worktree_change(repo, entry)?
+        };
+        if [staged, changed] != [' ', ' '] {
+            codes.insert(entry.path.clone(), [staged, changed]);
+        }
+    }
+    let tracked: BTreeSet<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+    for path in head_leaves.keys() {
+        if !tracked.contains(path.as_str()) {
+            codes.insert(path.clone(), ['D', ' ']);
+        }
+    }
+
+    let mut out: Vec<StatusEntry> = codes
+        .into_iter()
+        .map(|(path, [index, worktree])| StatusEntry {
+            path,
+            index,
+            worktree,
+        })
+        .collect();
+    let mut new = vec![];
+    untracked(repo, &tracked, "", &mut new)?;
+    out.extend(new.into_iter().map(|path| StatusEntry {
+        path,
+        index: '?',
+        worktree: '?',
+    }));
+    Ok(out)
+}
@@ -0,0 +1,166 @@
+use crate::commands;
+use crate::config;
+use crate::object::{self, ObjectType, Oid};
+use crate::repository::{self, Repo};
+use std::cell::{Cell, RefCell};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use structopt::StructOpt;
+
+/// Held while any [`TempRepo`] is alive: commands find their repository through the current
+/// directory and read the environment, and both are shared by every test in the process.
+static LOCK: Mutex<()> = Mutex::new(());
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static HELD: RefCell<Weak<MutexGuard<'static, ()>>> = const { RefCell::new(Weak::new()) };
+}
+
+/// Takes [`LOCK`], or shares it with the other repositories of the same test.
+fn lock() -> Rc<MutexGuard<'static, ()>> {
+    HELD.with(|held| {
+        if let Some(guard) = held.borrow().upgrade() {
+            return guard;
+        }
+        let guard = Rc::new(LOCK.lock().unwrap_or_else(PoisonError::into_inner));
+        *held.borrow_mut() = Rc::downgrade(&guard);
+        guard
+    })
+}
+
+/// Parses `args`, which follow the subcommand name, as the options of a wyag subcommand.
+pub fn args<T: StructOpt>(args: &[&str]) -> T {
+    T::from_iter(std::iter::once("wyag").chain(args.iter().copied()))
+}
+
+/// A repository in a new temporary directory, removed when it is dropped. Creating one makes
+/// it the current directory, so commands run against it.
+pub struct TempRepo {
+    path: PathBuf,
+    time: Cell<i64>,
+    siblings: RefCell<Vec<PathBuf>>,
+    /// `$HOME` as it was before [`TempRepo::global_config`] replaced it.
+    home: RefCell<Option<Option<OsString>>>,
+    _lock: Rc<MutexGuard<'static, ()>>,
+}
+
+impl TempRepo {
+    pub fn new() -> Self {
+        let lock = lock();
+        let path = std::env::temp_dir().join(format!(
+            "wyag-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let repo = repository::repo_create(&path).unwrap();
+        let config = repo.gitdir().join("config");
+        let mut conf = fs::read_to_string(&config).unwrap();
+        conf += "[user]\nname = A U Thor\nemail = author@example.com\n";
+        fs::write(&config, conf).unwrap();
+
+        let out = Self {
+            path: path.canonicalize().unwrap(),
+            time: Cell::new(1_600_000_000),
+            siblings: RefCell::default(),
+            home: RefCell::default(),
+            _lock: lock,
+        };
+        out.enter();
+        out
+    }
+
+    /// Makes this the current directory.
+    pub fn enter(&self) {
+        std::env::set_current_dir(&self.path).unwrap();
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A path next to the repository for the test to create something at, removed along
+    /// with it.
+    pub fn sibling(&self, name: &str) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap().to_owned();
+        file_name.push(format!("-{}", name));
+        let path = self.path.with_file_name(file_name);
+        self.siblings.borrow_mut().push(path.clone());
+        path
+    }
+
+    /// Opens the repository afresh, so nothing cached by an earlier handle is seen.
+    pub fn repo(&self) -> Repo {
+        repository::repo_find(Some(&self.path), true).unwrap()
+    }
+
+    /// Writes `contents` to `path` in the work tree, creating its directories.
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.path.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path.join(path)).unwrap()
+    }
+
+    /// Sets `name` in the repository's config file.
+    pub fn config(&self, name: &str, value: &str) {
+        let path = self.repo().gitdir().join("config");
+        config::config_file_edit(&path, |text| config::config_edit_set(text, name, value)).unwrap();
+    }
+
+    /// Sets `name` in a global config file, in a home directory next to the repository that
+    /// `$HOME` points at until the repository is dropped.
+    pub fn global_config(&self, name: &str, value: &str) {
+        let home = self.sibling("home");
+        fs::create_dir_all(&home).unwrap();
+        self.home
+            .borrow_mut()
+            .get_or_insert_with(|| std::env::var_os("HOME"));
+        std::env::set_var("HOME", &home);
+        let path = home.join(".gitconfig");
+        config::config_file_edit(&path, |text| config::config_edit_set(text, name, value)).unwrap();
+    }
+
+    /// Stores `contents` as a blob.
+    pub fn blob(&self, contents: &str) -> Oid {
+        object::object_write_raw(Some(&self.repo()), ObjectType::Blob, contents.as_bytes()).unwrap()
+    }
+
+    /// Stages the whole work tree and commits it, each commit a second after the last.
+    pub fn commit(&self, message: &str) -> Oid {
+        self.enter();
+        let time = self.time.get() + 1;
+        self.time.set(time);
+        for role in ["AUTHOR", "COMMITTER"] {
+            std::env::set_var(format!("GIT_{}_DATE", role), format!("{} +0000", time));
+        }
+        commands::cmd_add(&args(&["."])).unwrap();
+        commands::cmd_commit(&args(&["-m", message])).unwrap();
+        self.head()
+    }
+
+    pub fn head(&self) -> Oid {
+        object::object_find(&self.repo(), "HEAD", None, false).unwrap()
+    }
+}
+
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(std::env::temp_dir());
+        match self.home.borrow_mut().take() {
+            Some(Some(home)) => std::env::set_var("HOME", home),
+            Some(None) => std::env::remove_var("HOME"),
+            None => (),
+        }
+        let _ = fs::remove_dir_all(&self.path);
+        for path in self.siblings.borrow().iter() {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use crate::config;
+use crate::object::{self, ObjectSelect, Oid, MODE_GITLINK, MODE_TREE};
+use crate::refs;
+use crate::repository::{self, Repo};
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// A fetch refspec such as `+refs/heads/*:refs/remotes/origin/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    pub force: bool,
+    pub src: String,
+    pub dst: String,
+}
+
+impl Refspec {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let idx = spec
+            .find(':')
+            .ok_or_else(|| format!("Invalid refspec \"{}\", expected <src>:<dst>", spec))?;
+        let (src, dst) = (&spec[..idx], &spec[idx + 1..]);
+        if src.matches('*').count() != dst.matches('*').count() || src.matches('*').count() > 1 {
+            return Err(format!("Invalid refspec \"{}\", mismatched globs", spec));
+        }
+        Ok(Self {
+            force,
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+        })
+    }
+
+    /// Maps a remote ref name to its local name, if this refspec covers it.
+    pub fn map(&self, name: &str) -> Option<String> {
+        match self.src.find('*') {
+            Some(idx) => {
+                let (prefix, suffix) = (&self.src[..idx], &self.src[idx + 1..]);
+                if name.len() < prefix.len() + suffix.len() {
+                    return None;
+                }
+                let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some(self.dst.replacen('*', matched, 1))
+            }
+            None if name == self.src => Some(self.dst.clone()),
+            None => None,
+        }
+    }
+
+    /// Maps a local ref name back to the remote ref it is fetched from, if this refspec
+    /// covers it.
+    pub fn unmap(&self, name: &str) -> Option<String> {
+        let reversed = Self {
+            force: self.force,
+            src: self.dst.clone(),
+            dst: self.src.clone(),
+        };
+        reversed.map(name)
+    }
+}
+
+/// The refspec clone configures for the `origin` remote
+const DEFAULT_FETCH_REFSPEC: &str = "+refs/heads/*:refs/remotes/origin/*";
+const TAGS_REFSPEC: &str = "refs/tags/*:refs/tags/*";
+/// The refspec of a mirror, which copies every ref as it is
+const MIRROR_REFSPEC: &str = "+refs/*:refs/*";
+
+/// The ref the branch `branch` tracks, from its `branch.<name>.remote` and
+/// `branch.<name>.merge` config: the remote-tracking ref the remote's fetch refspec maps the
+/// merged ref to, or for a remote of `.` the local branch itself. Returns `None` if no
+/// upstream is configured, or the remote doesn't fetch that ref.
+pub fn branch_upstream(repo: &Repo, branch: &str) -> Result<Option<String>, String> {
+    let remote = repo.config_value(&format!("branch.{}.remote", branch));
+    let merge = repo.config_value(&format!("branch.{}.merge", branch));
+    let (remote, merge) = match (remote, merge) {
+        (Some(remote), Some(merge)) => (remote, merge),
+        _ => return Ok(None),
+    };
+    if remote == "." {
+        return Ok(Some(merge));
+    }
+    match repo.config_value(&format!("remote.{}.fetch", remote)) {
+        Some(fetch) => Ok(Refspec::parse(&fetch)?.map(&merge)),
+        None => Ok(None),
+    }
+}
+
+/// Makes the branch `branch` (a short name) track `upstream`, a full ref name: a local branch,
+/// or a remote-tracking ref some remote's fetch refspec fetches into. Writes the
+/// `branch.<name>.remote` and `branch.<name>.merge` config [`branch_upstream`] reads.
+pub fn branch_set_upstream(repo: &Repo, branch: &str, upstream: &str) -> Result<(), String> {
+    let (remote, merge) = if upstream.starts_with("refs/heads/") {
+        (".".to_owned(), upstream.to_owned())
+    } else {
+        let fetched = config::config_flatten(repo.config())
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let remote = name.strip_prefix("remote.")?.strip_suffix(".fetch")?;
+                let merge = Refspec::parse(&value).ok()?.unmap(upstream)?;
+                Some((remote.to_owned(), merge))
+            })
+            .next();
+        fetched.ok_or_else(|| {
+            format!(
+                "Cannot set up tracking information; starting point '{}' is not a branch",
+                upstream
+            )
+        })?
+    };
+
+    let config_path = repo.repo_file("config", false)?;
+    config::config_file_edit(&config_path, |text| {
+        config::config_edit_set(text, &format!("branch.{}.remote", branch), &remote)?;
+        config::config_edit_set(text, &format!("branch.{}.merge", branch), &merge)
+    })
+}
+
+/// Opens the repository a `file://` url or plain path points at, either a work tree or a
+/// bare repository.
+fn open_local(url: &str) -> Result<Repo, String> {
+    let path = PathBuf::from(url.strip_prefix("file://").unwrap_or(url));
+    let path = path
+        .canonicalize()
+        .map_err(|e| format!("Could not find repository {:?}: {:?}", path, e))?;
+    if path.join(".git").is_dir() {
+        Repo::new(path, false)
+    } else {
+        Repo::new_bare(path, false)
+    }
+}
+
+/// Walks history from `tips`, stopping `depth` commits deep if given. Returns every commit
+/// reached and the boundary commits whose parents were cut off, either by the depth limit
+/// or because they are on the `shallow` boundary already.
+fn walk_commits(
+    repo: &Repo,
+    tips: &[Oid],
+    depth: Option<usize>,
+    shallow: &HashSet<Oid>,
+) -> Result<(Vec<Oid>, Vec<Oid>), String> {
+    let mut seen = HashSet::new();
+    let mut commits = vec![];
+    let mut boundary = vec![];
+    // breadth first, so each commit is first reached at its shallowest depth
+    let mut queue: VecDeque<(Oid, usize)> = tips.iter().map(|t| (*t, 1)).collect();
+
+    while let Some((sha, d)) = queue.pop_front() {
+        if !seen.insert(sha) {
+            continue;
+        }
+        let commit = object::commit_read(repo, &sha)?;
+        let parents = object::commit_parents(&commit, &sha, shallow);
+        commits.push(sha);
+
+        if depth.is_some_and(|depth| d >= depth) || shallow.contains(&sha) {
+            if !commit.parents().is_empty() {
+                boundary.push(sha);
+            }
+            continue;
+        }
+        for parent in parents {
+            queue.push_back((parent, d + 1));
+        }
+    }
+
+    Ok((commits, boundary))
+}
+
+fn copy_tree(src: &Repo, dest: &Repo, sha: &Oid, copied: &mut HashSet<Oid>) -> Result<(), String> {
+    if !copied.insert(*sha) {
+        return Ok(());
+    }
+    object::object_copy(src, dest, sha)?;
+    for leaf in object::tree_read(src, sha)?.iterate_leaves() {
+        match leaf.mode {
+            MODE_TREE => copy_tree(src, dest, &leaf.sha, copied)?,
+            MODE_GITLINK => (),
+            _ => {
+                if copied.insert(leaf.sha) {
+                    object::object_copy(src, dest, &leaf.sha)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies `sha` and, for tags, the objects they point to. Returns the commit it peels to,
+/// if any.
+fn copy_peeled(
+    src: &Repo,
+    dest: &Repo,
+    sha: &Oid,
+    copied: &mut HashSet<Oid>,
+) -> Result<Option<Oid>, String> {
+    let mut sha = *sha;
+    loop {
+        match object::object_read(src, &sha)?.get_specific() {
+            ObjectSelect::Commit(_) => return Ok(Some(sha)),
+            ObjectSelect::Tag(tag) => {
+                if copied.insert(sha) {
+                    object::object_copy(src, dest, &sha)?;
+                }
+                sha = tag
+                    .object()
+                    .ok_or_else(|| format!("Tag {} has no object", sha))?;
+            }
+            ObjectSelect::Tree(_) => {
+                copy_tree(src, dest, &sha, copied)?;
+                return Ok(None);
+            }
+            ObjectSelect::Blob(_) => {
+                if copied.insert(sha) {
+                    object::object_copy(src, dest, &sha)?;
+                }
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Clones the repository at `url`, a `file://` url or local path, into a new repository at
+/// `dest`. With a `depth`, only that many commits of history are fetched from each ref and
+/// the cut off commits are recorded in `.git/shallow`.
+///
+/// With `mirror`, the new repository is bare and every ref is copied under its own name,
+/// rather than branches going under `refs/remotes/origin/`.
+///
+/// The work tree is left empty; returns the new repository and the commit `HEAD` points to
+/// so the caller can check it out.
+pub fn clone_local(
+    url: &str,
+    dest: &Path,
+    depth: Option<usize>,
+    mirror: bool,
+) -> Result<(Repo, Option<Oid>), String> {
+    if depth == Some(0) {
+        return Err("Depth must be a positive number".to_owned());
+    }
+    let src = open_local(url)?;
+    let (repo, fetch_refspec) = if mirror {
+        (repository::repo_create_bare(dest)?, MIRROR_REFSPEC)
+    } else {
+        (repository::repo_create(dest)?, DEFAULT_FETCH_REFSPEC)
+    };
+    let mut refspecs = vec![Refspec::parse(fetch_refspec)?];
+    if !mirror {
+        refspecs.push(Refspec::parse(TAGS_REFSPEC)?);
+    }
+
+    let mut copied = HashSet::new();
+    let mut tips = vec![];
+    let mut local_refs = vec![];
+    for (name, sha) in refs::ref_list(&src)? {
+        let local = match refspecs.iter().find_map(|spec| spec.map(&name)) {
+            Some(local) => local,
+            None => continue,
+        };
+        if let Some(commit) = copy_peeled(&src, &repo, &sha, &mut copied)? {
+            tips.push(commit);
+        }
+        local_refs.push((local, sha));
+    }
+    let head = refs::ref_resolve(&src, "HEAD")?;
+    if let Some(head) = &head {
+        tips.push(*head);
+    }
+
+    let shallow = repository::shallow_read(&src)?;
+    let (commits, boundary) = walk_commits(&src, &tips, depth, &shallow)?;
+    for sha in commits.iter() {
+        object::object_copy(&src, &repo, sha)?;
+        let commit = object::commit_read(&src, sha)?;
+        let tree = commit
+            .tree()
+            .ok_or_else(|| format!("Commit {} does not have a tree", sha))?;
+        copy_tree(&src, &repo, &tree, &mut copied)?;
+    }
+    repository::shallow_write(&repo, &boundary)?;
+
+    for (name, sha) in local_refs.iter() {
+        refs::ref_update(&repo, name, sha)?;
+    }
+
+    let config_path = repo.repo_file("config", false)?;
+    let mut ini = config::config_read_file(&config_path)?;
+    let url = src.worktree().to_string_lossy().into_owned();
+    config::config_set(&mut ini, "remote.origin.url", &url)?;
+    config::config_set(&mut ini, "remote.origin.fetch", fetch_refspec)?;
+    if mirror {
+        config::config_set(&mut ini, "remote.origin.mirror", "true")?;
+    }
+
+    match (refs::ref_symbolic_target(&src, "HEAD")?, &head) {
+        (Some(branch_ref), Some(sha)) => {
+            refs::ref_update(&repo, &branch_ref, sha)?;
+            refs::ref_set_symbolic(&repo, "HEAD", &branch_ref)?;
+            // a mirror has the branch itself, not a remote branch to track
+            if let Some(branch) = branch_ref.strip_prefix("refs/heads/").filter(|_| !mirror) {
+                refs::ref_set_symbolic(
+                    &repo,
+                    "refs/remotes/origin/HEAD",
+                    &format!("refs/remotes/origin/{}", branch),
+                )?;
+                config::config_set(&mut ini, &format!("branch.{}.remote", branch), "origin")?;
+                config::config_set(&mut ini, &format!("branch.{}.merge", branch), &branch_ref)?;
+            }
+        }
+        (None, Some(sha)) => refs::ref_update(&repo, "HEAD", sha)?,
+        // an empty source repository, leave `HEAD` as unborn
+        (_, None) => (),
+    }
+    ini.write_to_file(&config_path)
+        .map_err(|e| format!("Could not write config file {:?}: {:?}", config_path, e))?;
+
+    // reload so the repository sees the config written above
+    let repo = if mirror {
+        Repo::new_bare(repo.gitdir().to_owned(), false)?
+    } else {
+        Repo::new(repo.worktree().to_owned(), false)?
+    };
+    Ok((repo, head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempRepo;
+
+    #[test]
+    fn clone_depth_one_copies_only_the_tip() {
+        let t = TempRepo::new();
+        for (i, contents) in ["one", "two", "three"].iter().enumerate() {
+            t.write("file.txt", contents);
+            t.write(&format!("dir/{}.txt", i), contents);
+            t.commit(contents);
+        }
+        let tip = t.head();
+        let src = t.repo();
+        let mut expected = vec![tip];
+        let tree = object::commit_read(&src, &tip).unwrap().tree().unwrap();
+        expected.push(tree);
+        let mut subtrees = vec![tree];
+        while let Some(sha) = subtrees.pop() {
+            for leaf in object::tree_read(&src, &sha).unwrap().iterate_leaves() {
+                expected.push(leaf.sha);
+                if leaf.mode == object::MODE_TREE {
+                    subtrees.push(leaf.sha);
+                }
+            }
+        }
+        expected.sort();
+        expected.dedup();
+
+        let dest = t.sibling("clone");
+        let (repo, head) = clone_local(&t.path().to_string_lossy(), &dest, Some(1), false).unwrap();
+        assert_eq!(head, Some(tip));
+        let parent = object::commit_read(&src, &tip).unwrap().parents()[0];
+        assert!(!object::object_exists(&repo, &parent));
+        assert_eq!(object::object_list(&repo).unwrap(), expected);
+        assert_eq!(
+            repository::shallow_read(&repo).unwrap(),
+            HashSet::from([tip])
+        );
+        assert_eq!(refs::ref_resolve(&repo, "HEAD").unwrap(), Some(tip));
+    }
+
+    #[test]
+    fn mirror_clone_copies_every_ref_under_its_own_name() {
+        let t = TempRepo::new();
+        t.write("f.txt", "one\n");
+        let first = t.commit("one");
+        t.write("f.txt", "two\n");
+        t.commit("two");
+        let src = t.repo();
+        let side = object::CommitBuilder::new()
+            .tree(&object::commit_read(&src, &first).unwrap().tree().unwrap())
+            .parent(&first)
+            .author(object::Identity::from_env(&src, "AUTHOR").unwrap())
+            .committer(object::Identity::from_env(&src, "COMMITTER").unwrap())
+            .message("side\n".to_owned())
+            .build();
+        let side = object::object_write(Some(&src), &side).unwrap();
+        refs::ref_update(&src, "refs/heads/side", &side).unwrap();
+        refs::ref_update(&src, "refs/tags/light", &first).unwrap();
+        let tag = format!(
+            "object {}\ntype commit\ntag annotated\ntagger A U Thor <author@example.com> 1600000000 +0000\n\nannotated\n",
+            side
+        );
+        let tag =
+            object::object_write_raw(Some(&src), object::ObjectType::Tag, tag.as_bytes()).unwrap();
+        refs::ref_update(&src, "refs/tags/annotated", &tag).unwrap();
+
+        let dest = t.sibling("mirror.git");
+        let (repo, head) = clone_local(&t.path().to_string_lossy(), &dest, None, true).unwrap();
+        assert!(repo.is_bare());
+        let names: Vec<String> = refs::ref_list(&repo)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.0)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "refs/heads/master",
+                "refs/heads/side",
+                "refs/tags/annotated",
+                "refs/tags/light"
+            ]
+        );
+        assert_eq!(
+            refs::ref_list(&repo).unwrap(),
+            refs::ref_list(&src).unwrap()
+        );
+        assert_eq!(head, refs::ref_resolve(&src, "HEAD").unwrap());
+        assert_eq!(
+            refs::ref_symbolic_target(&repo, "HEAD").unwrap().as_deref(),
+            Some("refs/heads/master")
+        );
+        for sha in [tag, side, first] {
+            assert!(object::object_exists(&repo, &sha), "{}", sha);
+        }
+        assert_eq!(
+            repo.config_value("remote.origin.mirror").as_deref(),
+            Some("true")
+        );
+        assert_eq!(
+            repo.config_value("remote.origin.fetch").as_deref(),
+            Some("+refs/*:refs/*")
+        );
+    }
+}